@@ -0,0 +1,166 @@
+// `recruitment generate`, which makes up an input file to throw at the real thing.
+// Everything in it is consistent: disputes only ever point at real deposits of the same client,
+// and resolves and chargebacks only at disputes that are still open.
+// Withdrawals can still overdraw, that's something the engine has to deal with anyway.
+
+use std::{fs::File, io};
+
+use recruitment::{ClientId, TransactionId};
+use rust_decimal::Decimal;
+
+use super::flag_value;
+
+struct GenerateArgs {
+    clients: ClientId,
+    deposits: u64,
+    withdrawals: u64,
+    // Fraction of deposits that get disputed at some point
+    dispute_ratio: f64,
+    // Fraction of those disputes that end in a chargeback, the rest get resolved
+    chargeback_ratio: f64,
+    seed: u64,
+    output: Option<String>,
+}
+
+impl GenerateArgs {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, anyhow::Error> {
+        let mut parsed = Self {
+            clients: 10,
+            deposits: 1000,
+            withdrawals: 500,
+            dispute_ratio: 0.05,
+            chargeback_ratio: 0.2,
+            seed: 1,
+            output: None,
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--clients" => parsed.clients = flag_value(&mut args, &arg)?.parse()?,
+                "--deposits" => parsed.deposits = flag_value(&mut args, &arg)?.parse()?,
+                "--withdrawals" => parsed.withdrawals = flag_value(&mut args, &arg)?.parse()?,
+                "--dispute-ratio" => parsed.dispute_ratio = ratio(flag_value(&mut args, &arg)?)?,
+                "--chargeback-ratio" => {
+                    parsed.chargeback_ratio = ratio(flag_value(&mut args, &arg)?)?;
+                }
+                "--seed" => parsed.seed = flag_value(&mut args, &arg)?.parse()?,
+                "--output" => parsed.output = Some(flag_value(&mut args, &arg)?),
+                other => anyhow::bail!("Unknown generate argument {other}"),
+            }
+        }
+        if parsed.clients == 0 {
+            anyhow::bail!("Need at least one client to generate anything");
+        }
+        Ok(parsed)
+    }
+}
+
+fn ratio(value: String) -> Result<f64, anyhow::Error> {
+    let ratio: f64 = value.parse()?;
+    if !(0.0..=1.0).contains(&ratio) {
+        anyhow::bail!("Ratio {value} has to be between 0 and 1");
+    }
+    Ok(ratio)
+}
+
+// xorshift64*, there's no need for a whole rand dependency just to make up some numbers,
+// and this way a seed always gives the same file
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // Zero is the one state xorshift never leaves
+        Self(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+
+    // Up to 1000 with the full 4 decimal places
+    fn amount(&mut self) -> Decimal {
+        Decimal::new(self.below(10_000_000) as i64 + 1, 4)
+    }
+}
+
+pub fn run(args: impl Iterator<Item = String>) -> Result<(), anyhow::Error> {
+    let args = GenerateArgs::parse(args)?;
+    let writer: Box<dyn io::Write> = match &args.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout().lock()),
+    };
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record(["type", "client", "tx", "amount"])?;
+
+    let mut rng = Rng::new(args.seed);
+    let mut next_tx: TransactionId = 1;
+    let (mut deposits_left, mut withdrawals_left) = (args.deposits, args.withdrawals);
+    // Deposits that were picked to get disputed, but haven't been yet, and disputes waiting to get settled
+    let mut to_dispute: Vec<(ClientId, TransactionId)> = Vec::new();
+    let mut disputed: Vec<(ClientId, TransactionId)> = Vec::new();
+
+    let mut write = |kind: &str, client: ClientId, tx: TransactionId, amount: Option<Decimal>| {
+        let amount = amount.map(|amount| amount.to_string()).unwrap_or_default();
+        writer.write_record([kind, &client.to_string(), &tx.to_string(), &amount])
+    };
+
+    while deposits_left + withdrawals_left > 0 {
+        let client = rng.below(args.clients.into()) as ClientId + 1;
+        let tx = next_tx;
+        next_tx = next_tx
+            .checked_add(1)
+            .ok_or_else(|| anyhow::anyhow!("Ran out of tx ids"))?;
+        // Picking proportionally to what's left spreads both kinds evenly over the whole file
+        if rng.below(deposits_left + withdrawals_left) < deposits_left {
+            deposits_left -= 1;
+            write("deposit", client, tx, Some(rng.amount()))?;
+            if rng.chance(args.dispute_ratio) {
+                to_dispute.push((client, tx));
+            }
+        } else {
+            withdrawals_left -= 1;
+            write("withdrawal", client, tx, Some(rng.amount()))?;
+        }
+
+        // Disputes and their settlements trickle in a while after what they refer to
+        if !to_dispute.is_empty() && rng.chance(0.1) {
+            let (client, tx) = to_dispute.swap_remove(rng.below(to_dispute.len() as u64) as usize);
+            write("dispute", client, tx, None)?;
+            disputed.push((client, tx));
+        }
+        if !disputed.is_empty() && rng.chance(0.1) {
+            let (client, tx) = disputed.swap_remove(rng.below(disputed.len() as u64) as usize);
+            let kind = settlement(&mut rng, args.chargeback_ratio);
+            write(kind, client, tx, None)?;
+        }
+    }
+    // Whatever's left over still gets to happen, so the ratios hold
+    for (client, tx) in to_dispute {
+        write("dispute", client, tx, None)?;
+        disputed.push((client, tx));
+    }
+    for (client, tx) in disputed {
+        let kind = settlement(&mut rng, args.chargeback_ratio);
+        write(kind, client, tx, None)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn settlement(rng: &mut Rng, chargeback_ratio: f64) -> &'static str {
+    if rng.chance(chargeback_ratio) {
+        "chargeback"
+    } else {
+        "resolve"
+    }
+}
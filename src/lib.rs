@@ -0,0 +1,6368 @@
+// The engine itself. It does no IO of its own beyond the readers and writers it's handed,
+// so it builds for wasm32-unknown-unknown too
+use std::{
+    cell::RefCell,
+    cmp,
+    collections::{
+        hash_map::{DefaultHasher, Entry},
+        BTreeMap, BTreeSet, HashMap, HashSet, VecDeque,
+    },
+    fmt,
+    hash::{Hash, Hasher},
+    io,
+    rc::Rc,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use csv::{ByteRecord, QuoteStyle, ReaderBuilder, StringRecord, Writer, WriterBuilder};
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+
+// String in, string out, with the default config. Meant for the browser, where there's no files or stdout to speak of
+pub fn process_str(csv: &str) -> Result<String, String> {
+    let process = || -> Result<String, anyhow::Error> {
+        let csv_reader = reader_builder(&InputConfig::default()).from_reader(csv.as_bytes());
+        let mut state = EngineState::new(Arc::new(AtomicBool::new(false)));
+        handle_transactions(
+            deserialize_transactions(csv_reader, &InputConfig::default()),
+            &mut state,
+            &ProcessingConfig::default(),
+        )?;
+        let mut output = Vec::new();
+        let output_config = OutputConfig::default();
+        write_output(
+            &mut CsvSink::new(&mut output, &output_config),
+            [(None, &state)],
+            &output_config,
+        )?;
+        Ok(String::from_utf8(output)?)
+    };
+    process().map_err(|err| err.to_string())
+}
+
+// Where the clients are kept
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ClientStorage {
+    // Only client ids below 65536, which is all of them in the spec
+    #[default]
+    Array,
+    // Any id, at the cost of hashing every lookup
+    HashMap,
+    // Any id, slower still, but the output comes out sorted by client
+    BTree,
+}
+
+enum Clients {
+    // A HashMap would be marginally more readable, but let's go fast and preallocate a big array, because we can with u16 keys and 500kb is nothing
+    // Massive overkill for small examples though
+    Array(Box<[Option<Box<ClientState>>; 1 << 16]>),
+    HashMap(HashMap<ClientId, Box<ClientState>>),
+    BTree(BTreeMap<ClientId, Box<ClientState>>),
+}
+
+impl Clients {
+    fn new(storage: ClientStorage) -> Self {
+        const NONE: std::option::Option<Box<ClientState>> = None;
+        match storage {
+            ClientStorage::Array => Clients::Array(Box::new([NONE; 1 << 16])),
+            ClientStorage::HashMap => Clients::HashMap(HashMap::new()),
+            ClientStorage::BTree => Clients::BTree(BTreeMap::new()),
+        }
+    }
+
+    fn get(&self, client_id: ClientId) -> Option<&ClientState> {
+        match self {
+            Clients::Array(clients) => clients.get(client_id as usize)?.as_deref(),
+            Clients::HashMap(clients) => clients.get(&client_id).map(Box::as_ref),
+            Clients::BTree(clients) => clients.get(&client_id).map(Box::as_ref),
+        }
+    }
+
+    fn get_mut(&mut self, client_id: ClientId) -> Option<&mut ClientState> {
+        match self {
+            Clients::Array(clients) => clients.get_mut(client_id as usize)?.as_deref_mut(),
+            Clients::HashMap(clients) => clients.get_mut(&client_id).map(Box::as_mut),
+            Clients::BTree(clients) => clients.get_mut(&client_id).map(Box::as_mut),
+        }
+    }
+
+    fn get_or_insert(&mut self, client_id: ClientId) -> Result<&mut ClientState, anyhow::Error> {
+        let new = || Box::new(ClientState::new());
+        Ok(match self {
+            Clients::Array(clients) => {
+                let Some(client) = clients.get_mut(client_id as usize) else {
+                    anyhow::bail!("Client {client_id} doesn't fit in the array, that needs --storage hashmap or btree");
+                };
+                client.get_or_insert_with(new)
+            }
+            Clients::HashMap(clients) => clients.entry(client_id).or_insert_with(new),
+            Clients::BTree(clients) => clients.entry(client_id).or_insert_with(new),
+        })
+    }
+
+    fn take(&mut self, client_id: ClientId) -> Option<Box<ClientState>> {
+        match self {
+            Clients::Array(clients) => clients.get_mut(client_id as usize)?.take(),
+            Clients::HashMap(clients) => clients.remove(&client_id),
+            Clients::BTree(clients) => clients.remove(&client_id),
+        }
+    }
+
+    // Roughly how much this takes up: the slots, plus a ClientState for every client. Whatever the clients
+    // have on the heap themselves (open disputes and so on) isn't counted, that's the same for every storage anyway
+    fn approximate_bytes(&self) -> usize {
+        use std::mem::size_of;
+        let slot = size_of::<ClientId>() + size_of::<Box<ClientState>>();
+        let (slots, clients) = match self {
+            Clients::Array(clients) => (
+                size_of_val(clients.as_ref()),
+                clients.iter().flatten().count(),
+            ),
+            // A BTree doesn't say how much it has allocated, but its nodes are mostly full
+            Clients::HashMap(clients) => (clients.capacity() * slot, clients.len()),
+            Clients::BTree(clients) => (clients.len() * slot, clients.len()),
+        };
+        slots + clients * size_of::<ClientState>()
+    }
+
+    // The order they're output in. Same as they first showed up in, except for the BTree, which has them sorted already
+    fn ids<'a>(&'a self, seen_clients: &'a [ClientId]) -> Box<dyn Iterator<Item = ClientId> + 'a> {
+        match self {
+            Clients::BTree(clients) => Box::new(clients.keys().copied()),
+            _ => Box::new(seen_clients.iter().copied()),
+        }
+    }
+}
+
+// Everything that gets built up while going through the transactions
+pub type ExplainTracer = Box<dyn FnMut(&str)>;
+
+pub struct EngineState<S: TxStore = HashMapStore> {
+    client_states: Clients,
+    seen_clients: Vec<ClientId>,
+    // Clients are ok for in-memory, but this would probably need disk storage and memory cache for real life applications
+    // As this is just a wrapper over a hashmap, so I hope you're not throwing gigabytes of csv at this, because it could OOM easily
+    // I'm only saving the transactions that have IDs of their own, so deposit and withdraw, as the others do not (which is insane for real life ofc)
+    tx_database: S,
+    stats: ProcessingStats,
+    // Hashes of every record so far, for --dedup
+    seen_records: HashSet<u64>,
+    // Flipped from the SIGUSR1 handler, checked (and reset) once per record
+    snapshot_requested: Arc<AtomicBool>,
+    // When each dispute gets auto resolved, in records processed, with --auto-resolve-after.
+    // The timeout is the same for all of them, so this is always sorted
+    dispute_deadlines: VecDeque<(u64, ClientId, TransactionId)>,
+    // Clients that went quiet for --finalize-after records, got their row written out early, and were dropped
+    finalized_clients: HashSet<ClientId>,
+    // Including ones that came back later
+    finalized_count: usize,
+    finalized_sink: Option<(Box<dyn OutputSink>, OutputConfig)>,
+    // Where `commit` rows write out the clients that changed since the last one, and which those are
+    commit_sink: Option<(Box<dyn OutputSink>, OutputConfig)>,
+    changed_since_commit: BTreeSet<ClientId>,
+    // Rows the commits wrote out, a client that was in several of them counting every time
+    committed_count: usize,
+    observers: Vec<Box<dyn TransactionObserver>>,
+    // Where the --explain lines go, stderr if there's nothing here
+    explain_tracer: Option<ExplainTracer>,
+}
+
+impl EngineState {
+    pub fn new(snapshot_requested: Arc<AtomicBool>) -> Self {
+        Self::with_store(snapshot_requested, HashMapStore::new())
+    }
+}
+
+impl<S: TxStore> EngineState<S> {
+    // For keeping the transactions somewhere other than the default in-memory HashMap
+    pub fn with_store(snapshot_requested: Arc<AtomicBool>, tx_database: S) -> Self {
+        Self {
+            client_states: Clients::new(ClientStorage::default()),
+            seen_clients: Vec::with_capacity(1 << 13),
+            tx_database,
+            stats: ProcessingStats::default(),
+            seen_records: HashSet::new(),
+            snapshot_requested,
+            dispute_deadlines: VecDeque::new(),
+            finalized_clients: HashSet::new(),
+            finalized_count: 0,
+            finalized_sink: None,
+            commit_sink: None,
+            changed_since_commit: BTreeSet::new(),
+            committed_count: 0,
+            observers: Vec::new(),
+            explain_tracer: None,
+        }
+    }
+
+    // Clients that are already there get moved over, but it's meant to be picked before anything's processed
+    pub fn store_clients_in(&mut self, storage: ClientStorage) -> Result<(), anyhow::Error> {
+        let mut client_states = Clients::new(storage);
+        for client_id in &self.seen_clients {
+            if let Some(client) = self.client_states.take(*client_id) {
+                *client_states.get_or_insert(*client_id)? = *client;
+            }
+        }
+        self.client_states = client_states;
+        Ok(())
+    }
+
+    // Where the rows of clients finalized with --finalize-after go. Without one, they're dropped without a trace
+    pub fn finalize_into(&mut self, sink: Box<dyn OutputSink>, output_config: OutputConfig) {
+        self.finalized_sink = Some((sink, output_config));
+    }
+
+    // Where the checkpoints from `commit` rows go. Without one, a `commit` row is an error, rather than a checkpoint that quietly never happened
+    pub fn commit_into(&mut self, sink: Box<dyn OutputSink>, output_config: OutputConfig) {
+        self.commit_sink = Some((sink, output_config));
+    }
+
+    // Writes out every client that changed since the last commit, sorted like the output, and flushes
+    fn commit(&mut self) -> Result<(), anyhow::Error> {
+        let Some((sink, output_config)) = &mut self.commit_sink else {
+            anyhow::bail!(
+                "Got a commit row, but there's nowhere for checkpoints to go, they don't work with --follow or --namespace-by-file"
+            );
+        };
+        for client_id in std::mem::take(&mut self.changed_since_commit) {
+            // Might have been finalized since
+            let Some(client) = self.client_states.get(client_id) else {
+                continue;
+            };
+            sink.emit(&ClientRecord::from_id_and_state(
+                &client_id,
+                client,
+                output_config,
+            )?)?;
+            self.committed_count += 1;
+        }
+        sink.finish()
+    }
+
+    // One client's row as it would be in the output right now. None if it's never been seen
+    pub fn client_record(
+        &self,
+        client_id: ClientId,
+        output_config: &OutputConfig,
+    ) -> Option<Result<ClientRecord, anyhow::Error>> {
+        self.client_states
+            .get(client_id)
+            .map(|client| ClientRecord::from_id_and_state(&client_id, client, output_config))
+    }
+
+    // Gets told about every transaction from the input as it's applied, see `TransactionObserver`
+    pub fn observe_with(&mut self, observer: Box<dyn TransactionObserver>) {
+        self.observers.push(observer);
+    }
+
+    // Takes the --explain trace instead of it going to stderr, one line per state change
+    pub fn explain_with(&mut self, tracer: ExplainTracer) {
+        self.explain_tracer = Some(tracer);
+    }
+
+    pub fn finalized_count(&self) -> usize {
+        self.finalized_count
+    }
+
+    pub fn committed_count(&self) -> usize {
+        self.committed_count
+    }
+
+    // All clients over the whole array get looked at, so this only runs once every `finalize_after` records
+    fn finalize_idle_clients(&mut self, finalize_after: u64) -> Result<(), anyhow::Error> {
+        let records = self.stats.records;
+        let mut finalized = Vec::new();
+        self.seen_clients.retain(|client_id| {
+            let idle = self
+                .client_states
+                .get(*client_id)
+                .is_some_and(|client| client.last_seen.saturating_add(finalize_after) <= records);
+            if idle {
+                finalized.push(*client_id);
+            }
+            !idle
+        });
+        for client_id in finalized {
+            let Some(client) = self.client_states.take(client_id) else {
+                continue;
+            };
+            if let Some((sink, output_config)) = &mut self.finalized_sink {
+                sink.emit(&ClientRecord::from_id_and_state(
+                    &client_id,
+                    &client,
+                    output_config,
+                )?)?;
+            }
+            self.finalized_clients.insert(client_id);
+            self.finalized_count += 1;
+        }
+        Ok(())
+    }
+
+    // Estimates, from counts and capacities, of what the bigger parts of the state take up, in bytes
+    pub fn report_memory(
+        &self,
+        namespace: Option<&str>,
+        mut writer: impl io::Write,
+    ) -> Result<(), io::Error> {
+        let namespace = namespace
+            .map(|namespace| format!(" {namespace}"))
+            .unwrap_or_default();
+        let tx_database = self
+            .tx_database
+            .approximate_bytes()
+            .map(|bytes| bytes.to_string())
+            .unwrap_or_else(|| "unknown".to_owned());
+        writeln!(
+            writer,
+            "memory{namespace}: client states {}, tx database {tx_database}, seen clients {}",
+            self.client_states.approximate_bytes(),
+            self.seen_clients.capacity() * std::mem::size_of::<ClientId>()
+        )
+    }
+
+    // Only clients that have any open disputes show up
+    pub fn report_open_disputes(
+        &self,
+        namespace: Option<&str>,
+        mut writer: impl io::Write,
+    ) -> Result<(), io::Error> {
+        for client_id in &self.seen_clients {
+            let Some(client) = self.client_states.get(*client_id) else {
+                continue;
+            };
+            if client.txns_under_dispute.is_empty() {
+                continue;
+            }
+            let held: Decimal = client
+                .txns_under_dispute
+                .values()
+                .map(|dispute| dispute.held)
+                .sum();
+            let namespace = namespace
+                .map(|namespace| format!("{namespace}/"))
+                .unwrap_or_default();
+            writeln!(
+                writer,
+                "client {namespace}{client_id}: {} open disputes, {held} held",
+                client.txns_under_dispute.len()
+            )?;
+        }
+        Ok(())
+    }
+
+    // Every open dispute of every client that has any, split up by tx, sorted by tx within a client.
+    // The held in the output is the sum of these plus manual holds
+    pub fn held_breakdown(&self) -> Vec<HeldBreakdown> {
+        let mut breakdown = Vec::new();
+        for client_id in &self.seen_clients {
+            let Some(client) = self.client_states.get(*client_id) else {
+                continue;
+            };
+            if client.txns_under_dispute.is_empty() {
+                continue;
+            }
+            let mut disputes: Vec<DisputedHeld> = client
+                .txns_under_dispute
+                .iter()
+                .map(|(tx, dispute)| DisputedHeld {
+                    tx: *tx,
+                    held: dispute.held,
+                })
+                .collect();
+            disputes.sort_unstable_by_key(|dispute| dispute.tx);
+            breakdown.push(HeldBreakdown {
+                source: None,
+                client: *client_id,
+                held: client.held,
+                disputes,
+            });
+        }
+        breakdown
+    }
+
+    // Picks up where an earlier run left off, from what `dump_tx_database` wrote, so that a file with only disputes
+    // can refer to deposits from before. Only the transactions come back, the client balances don't
+    pub fn load_tx_database(&mut self, reader: impl io::Read) -> Result<(), anyhow::Error> {
+        load_tx_store(&mut self.tx_database, reader)
+    }
+
+    // Every dispute that's still open, so that a later run's file can resolve or charge it back.
+    // Sorted, so that the same state always gives the same file. Finalized clients are already gone
+    pub fn dump_dispute_ledger(&self, writer: impl io::Write) -> Result<(), anyhow::Error> {
+        let mut entries: Vec<LedgerEntry> = self
+            .seen_clients
+            .iter()
+            .filter_map(|client_id| Some((*client_id, self.client_states.get(*client_id)?)))
+            .flat_map(|(client_id, client)| {
+                client
+                    .txns_under_dispute
+                    .iter()
+                    .map(move |(tx, dispute)| LedgerEntry {
+                        client_id,
+                        transaction_id: *tx,
+                        amount: dispute.amount,
+                        held: dispute.held,
+                        transaction_type: dispute.transaction_type,
+                    })
+            })
+            .collect();
+        entries.sort_by_key(|entry| (entry.client_id, entry.transaction_id));
+        let mut csv_writer = Writer::from_writer(writer);
+        for entry in entries {
+            csv_writer.serialize(entry)?;
+        }
+        csv_writer.flush()?;
+        Ok(())
+    }
+
+    // The other half of `dump_dispute_ledger`. Balances don't carry over between runs, but what's under dispute
+    // is held, so that gets put back, and a resolve or chargeback later on nets it out again
+    pub fn load_dispute_ledger(&mut self, reader: impl io::Read) -> Result<(), anyhow::Error> {
+        for entry in csv::Reader::from_reader(reader).into_deserialize() {
+            let LedgerEntry {
+                client_id,
+                transaction_id,
+                amount,
+                held,
+                transaction_type,
+            } = entry?;
+            if self.client_states.get(client_id).is_none() {
+                self.seen_clients.push(client_id);
+            }
+            let client = self.client_states.get_or_insert(client_id)?;
+            client.held += held;
+            client.txns_under_dispute.insert(
+                transaction_id,
+                OpenDispute {
+                    amount,
+                    held,
+                    transaction_type,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    // Clients carried over from an earlier run, in the same shape as the output (any bool style, extra columns ignored).
+    // Nothing but the balances and the locked flag survive, so held is all manual holds, see `ClientState::with`
+    pub fn load_seed(&mut self, reader: impl io::Read) -> Result<(), anyhow::Error> {
+        for row in csv::Reader::from_reader(reader).into_deserialize() {
+            let OutputRow {
+                client,
+                available,
+                held,
+                locked,
+                ..
+            } = row?;
+            let Ok(client) = client.parse::<ClientId>() else {
+                anyhow::bail!("Client {client} in the seed isn't in decimal");
+            };
+            let locked = match locked.as_str() {
+                "true" | "1" | "yes" => true,
+                "false" | "0" | "no" => false,
+                other => anyhow::bail!(
+                    "Client {client} in the seed has a locked of {other}, that's not a bool"
+                ),
+            };
+            if self.client_states.get(client).is_some() {
+                anyhow::bail!("Client {client} is in the seed twice");
+            }
+            self.seen_clients.push(client);
+            let seeded = self.client_states.get_or_insert(client)?;
+            *seeded = ClientState::with(available, held, locked);
+            seeded.seed_locked = locked;
+        }
+        Ok(())
+    }
+
+    // Including ones that got skipped, errors aside
+    pub fn records_processed(&self) -> u64 {
+        self.stats.records
+    }
+
+    // How many records of each type there were, ones that got skipped included
+    pub fn type_counts(&self) -> impl Iterator<Item = (TransactionType, u64)> + '_ {
+        TransactionType::ALL
+            .iter()
+            .copied()
+            .zip(self.stats.by_type.iter().copied())
+    }
+
+    // Resolves of txs that weren't under dispute. More of these than a handful is a bug upstream
+    pub fn orphan_resolves(&self) -> u64 {
+        self.stats.orphan_resolves
+    }
+
+    // Everything that's held across all the clients, open disputes and admin holds alike. None if it doesn't fit in a Decimal
+    pub fn held_total(&self) -> Option<Decimal> {
+        self.seen_clients
+            .iter()
+            .filter_map(|client_id| self.client_states.get(*client_id))
+            .try_fold(Decimal::ZERO, |total, client| {
+                total.checked_add(client.held)
+            })
+    }
+
+    // Everything stored for disputes to refer to, and whether they did, as csv
+    pub fn dump_tx_database(&self, writer: impl io::Write) -> Result<(), anyhow::Error> {
+        dump_tx_store(&self.tx_database, writer)
+    }
+
+    // Clients that ended up with a negative available or held, one line each. Nothing legit gets there
+    // with the default config, so any of these are an accounting bug or a weird edge case worth a look.
+    // Clients that were finalized early aren't around anymore to be checked
+    pub fn unbalanced_clients(&self, namespace: Option<&str>) -> Vec<String> {
+        let namespace = namespace
+            .map(|namespace| format!("{namespace}/"))
+            .unwrap_or_default();
+        self.seen_clients
+            .iter()
+            .filter_map(|client_id| {
+                let client = self.client_states.get(*client_id)?;
+                (client.available < Decimal::ZERO || client.held < Decimal::ZERO).then(|| {
+                    format!(
+                        "client {namespace}{client_id}: available {}, held {}",
+                        client.available, client.held
+                    )
+                })
+            })
+            .collect()
+    }
+
+    // Basic fraud signals, one line per client that trips any of them
+    pub fn report_suspicious(
+        &self,
+        namespace: Option<&str>,
+        mut writer: impl io::Write,
+    ) -> Result<(), io::Error> {
+        for client_id in &self.seen_clients {
+            let Some(client) = self.client_states.get(*client_id) else {
+                continue;
+            };
+            let mut reasons = Vec::new();
+            if client.chargebacks > 0
+                && client.chargebacks as f64 / client.deposits.max(1) as f64
+                    > SUSPICIOUS_CHARGEBACK_RATIO
+            {
+                reasons.push(format!(
+                    "{} of {} deposits charged back",
+                    client.chargebacks, client.deposits
+                ));
+            }
+            if client.disputes >= SUSPICIOUS_DISPUTE_COUNT {
+                reasons.push(format!("{} disputes", client.disputes));
+            }
+            if reasons.is_empty() {
+                continue;
+            }
+            let namespace = namespace
+                .map(|namespace| format!("{namespace}/"))
+                .unwrap_or_default();
+            writeln!(
+                writer,
+                "client {namespace}{client_id}: {}",
+                reasons.join(", ")
+            )?;
+        }
+        Ok(())
+    }
+
+    // Clients with more than `ratio` of their total held, one line each. A lot of money stuck in disputes
+    // is a risk sign. Anyone with something held and a total of zero or less is over any ratio
+    pub fn report_high_held(
+        &self,
+        namespace: Option<&str>,
+        ratio: Decimal,
+        mut writer: impl io::Write,
+    ) -> Result<(), io::Error> {
+        let namespace = namespace
+            .map(|namespace| format!("{namespace}/"))
+            .unwrap_or_default();
+        for client_id in &self.seen_clients {
+            let Some(client) = self.client_states.get(*client_id) else {
+                continue;
+            };
+            if client.held <= Decimal::ZERO {
+                continue;
+            }
+            let Some(total) = client.available.checked_add(client.held) else {
+                continue;
+            };
+            let over = match client.held.checked_div(total) {
+                Some(held_ratio) if total > Decimal::ZERO => held_ratio > ratio,
+                _ => true,
+            };
+            if over {
+                writeln!(
+                    writer,
+                    "client {namespace}{client_id}: {} of a total of {total} held",
+                    client.held
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // Meant for keeping an eye on long runs, so it goes to stderr and doesn't touch the actual output
+    fn dump_snapshot(&self, config: &ProcessingConfig) {
+        diagnostic(config, format_args!("{}", self.snapshot()));
+    }
+
+    // One line of it all, split out of the dump so that it can be looked at without going through stderr
+    fn snapshot(&self) -> String {
+        let (available, held) = self
+            .seen_clients
+            .iter()
+            .filter_map(|client_id| self.client_states.get(*client_id))
+            .fold(
+                (Decimal::ZERO, Decimal::ZERO),
+                |(available, held), client| {
+                    (
+                        available.saturating_add(client.available),
+                        held.saturating_add(client.held),
+                    )
+                },
+            );
+        format!(
+            "records: {}, duplicates skipped: {}, orphan resolves: {}, clients: {}, deposited: {}, withdrawn: {}, available: {}, held: {}",
+            self.stats.records,
+            self.stats.duplicates_skipped,
+            self.stats.orphan_resolves,
+            self.seen_clients.len(),
+            self.stats.total_deposited,
+            self.stats.total_withdrawn,
+            available,
+            held,
+        )
+    }
+}
+
+// For a server that processes uploads on several threads at once. Clients are split over shards by id, each with
+// its own lock and its own tx store, so transactions of clients in different shards never wait on each other.
+// A dispute only ever sees the txs from its own shard, which are the ones of its own client and that's what it
+// should be referring to anyway. Only `apply_one` happens (held back settlements included), so there's none of dedup, --explain, stats or the observers
+pub struct SharedEngine<S: TxStore = HashMapStore> {
+    shards: Vec<Mutex<Shard<S>>>,
+}
+
+struct Shard<S> {
+    clients: HashMap<ClientId, ClientState>,
+    tx_database: S,
+}
+
+impl SharedEngine {
+    // Zero is taken as one
+    pub fn new(shards: usize) -> Self {
+        Self::from_stores((0..shards.max(1)).map(|_| HashMapStore::new()).collect())
+    }
+}
+
+impl<S: TxStore> SharedEngine<S> {
+    // One shard per store
+    pub fn with_stores(stores: Vec<S>) -> Result<Self, anyhow::Error> {
+        if stores.is_empty() {
+            anyhow::bail!("A SharedEngine needs at least one store to shard over");
+        }
+        Ok(Self::from_stores(stores))
+    }
+
+    fn from_stores(stores: Vec<S>) -> Self {
+        let shards = stores
+            .into_iter()
+            .map(|tx_database| {
+                Mutex::new(Shard {
+                    clients: HashMap::new(),
+                    tx_database,
+                })
+            })
+            .collect();
+        Self { shards }
+    }
+
+    fn shard(
+        &self,
+        client_id: ClientId,
+    ) -> Result<std::sync::MutexGuard<'_, Shard<S>>, anyhow::Error> {
+        self.shards[client_id as usize % self.shards.len()]
+            .lock()
+            // Only a panic in another thread's apply gets it here
+            .map_err(|_| anyhow::anyhow!("Shard of client {client_id} is poisoned"))
+    }
+
+    pub fn apply(
+        &self,
+        transaction: &Transaction,
+        config: &ProcessingConfig,
+    ) -> Result<Applied, anyhow::Error> {
+        // There's no output here to checkpoint
+        if let Transaction::Commit = transaction {
+            return Ok(Applied::Skipped(SkipReason::NotAClient));
+        }
+        let client_id = transaction.client_id();
+        let mut shard = self.shard(client_id)?;
+        let Shard {
+            clients,
+            tx_database,
+        } = &mut *shard;
+        let client = clients.entry(client_id).or_insert_with(ClientState::new);
+        let applied = apply_one(transaction, client, tx_database, config)?;
+        // Same as in `handle_transactions`, an error out of the settlement is the settlement's. The dispute
+        // has gone through by then either way
+        if let Some(settlement) = early_settlement(transaction, &applied, client) {
+            apply_one(&settlement, client, tx_database, config)?;
+        }
+        Ok(applied)
+    }
+
+    pub fn client_record(
+        &self,
+        client_id: ClientId,
+        output_config: &OutputConfig,
+    ) -> Result<Option<ClientRecord>, anyhow::Error> {
+        let shard = self.shard(client_id)?;
+        shard
+            .clients
+            .get(&client_id)
+            .map(|client| ClientRecord::from_id_and_state(&client_id, client, output_config))
+            .transpose()
+    }
+
+    // Everyone, sorted by client, since there's no one order they showed up in across threads.
+    // Shards get locked one at a time, so it's only a consistent snapshot if nothing's being applied meanwhile
+    pub fn client_records(
+        &self,
+        output_config: &OutputConfig,
+    ) -> Result<Vec<ClientRecord>, anyhow::Error> {
+        let mut client_records = Vec::new();
+        for shard in &self.shards {
+            let shard = shard
+                .lock()
+                .map_err(|_| anyhow::anyhow!("A shard is poisoned"))?;
+            for (client_id, client) in &shard.clients {
+                client_records.push(ClientRecord::from_id_and_state(
+                    client_id,
+                    client,
+                    output_config,
+                )?);
+            }
+        }
+        client_records.sort_by_key(|client_record| client_record.client_id);
+        Ok(client_records)
+    }
+}
+
+// Pulled out of thin air, but a legit account basically never gets a quarter of its deposits charged back
+const SUSPICIOUS_CHARGEBACK_RATIO: f64 = 0.25;
+const SUSPICIOUS_DISPUTE_COUNT: u32 = 5;
+
+#[derive(Debug, Default)]
+struct ProcessingStats {
+    records: u64,
+    total_deposited: Decimal,
+    // Only the withdrawals that actually went through
+    total_withdrawn: Decimal,
+    duplicates_skipped: u64,
+    orphan_resolves: u64,
+    // How many of each type, in the order of `TransactionType::ALL`. An array because it's bumped for every record
+    by_type: [u64; TransactionType::ALL.len()],
+}
+
+// How the csv gets read, as opposed to what's done with it
+#[derive(Debug, Clone)]
+pub struct InputConfig {
+    // None is the csv crate's default of 8KiB. On a local 2M row (~50MB) file anything from 1KiB to 1MiB
+    // came out at ~2.2s, within noise, as parsing and the per-record work dominate.
+    // Could still matter for slow or network filesystems, where fewer bigger reads help
+    pub buffer_size: Option<usize>,
+    // Currency symbols and codes (`$`, `USD`) that can come before or after an amount, and get dropped before parsing it.
+    // Empty means amounts have to be plain numbers
+    pub strip_currency: Vec<String>,
+    // Map input files into memory instead of reading them. Only the CLI opens files, so it's the one that looks at this
+    pub mmap: bool,
+    // Error out on amounts where there shouldn't be any, and on missing ones, instead of making do
+    pub strict_schema: bool,
+    // Treat rows with an empty type (they're trimmed, so whitespace counts) as comments, instead of erroring on them
+    pub skip_blank_type: bool,
+    // Replace bytes that aren't valid UTF-8 with U+FFFD instead of erroring out, for messy exports
+    pub lossy_utf8: bool,
+    // Lines longer than this are an error, see `RowLimitReader`. None is unbounded
+    pub max_row_bytes: Option<usize>,
+    // How many times in a row a failed read gets retried before giving up, for flaky network filesystems.
+    // Same as mmap, only the CLI does any reading of its own
+    pub io_retries: u32,
+    // Which of the header row and the fields get whitespace trimmed off. Everything by default,
+    // but some inputs have leading spaces that mean something
+    pub trim: csv::Trim,
+    // Read line by line, skipping the ones that are broken, see `deserialize_lines`
+    pub recover_lines: bool,
+    // For files without a header row, which columns are where. None is a file that has one
+    pub column_order: Option<StringRecord>,
+    // Turn lone `\r`s into newlines, see `NewlineNormalizer`
+    pub normalize_newlines: bool,
+    pub delimiter: u8,
+    pub quote: u8,
+    // Guess the two above from the start of every file, see `detect_dialect`. Only the CLI opens files, so it's the one doing that
+    pub detect_dialect: bool,
+}
+
+// A real row is a few dozen bytes, so anything near this is a corrupted file
+pub const DEFAULT_MAX_ROW_BYTES: usize = 4 << 20;
+
+impl Default for InputConfig {
+    fn default() -> Self {
+        Self {
+            buffer_size: None,
+            strip_currency: Vec::new(),
+            mmap: false,
+            strict_schema: false,
+            skip_blank_type: false,
+            lossy_utf8: false,
+            max_row_bytes: Some(DEFAULT_MAX_ROW_BYTES),
+            io_retries: 0,
+            trim: csv::Trim::All,
+            recover_lines: false,
+            column_order: None,
+            normalize_newlines: false,
+            delimiter: b',',
+            quote: b'"',
+            detect_dialect: false,
+        }
+    }
+}
+
+// The csv reader copes with any mix of `\n`, `\r\n` and lone `\r` (old Macs, some broken exports), but everything
+// here that goes by lines (--max-row-bytes, --follow, --recover-lines) only splits on `\n`, so to those a file
+// of lone `\r`s is one giant line. This turns every `\r\n` and lone `\r` into a `\n` before any of them see it.
+// A `\r` inside a quoted field becomes a newline too, which it never should be in here
+pub struct NewlineNormalizer<R> {
+    inner: R,
+    // A `\n` right after this is the second half of a `\r\n` that's already been written out
+    after_cr: bool,
+}
+
+impl<R> NewlineNormalizer<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            after_cr: false,
+        }
+    }
+}
+
+impl<R: io::Read> io::Read for NewlineNormalizer<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.inner.read(buf)?;
+            if read == 0 {
+                return Ok(0);
+            }
+            // Only ever gets shorter, so it can be done in place
+            let mut written = 0;
+            for index in 0..read {
+                let byte = buf[index];
+                if byte == b'\n' && self.after_cr {
+                    self.after_cr = false;
+                    continue;
+                }
+                self.after_cr = byte == b'\r';
+                buf[written] = if byte == b'\r' { b'\n' } else { byte };
+                written += 1;
+            }
+            // A chunk that was nothing but the `\n` of a `\r\n` would otherwise look like the end of the file
+            if written > 0 {
+                return Ok(written);
+            }
+        }
+    }
+}
+
+// The csv reader keeps growing its record buffer for as long as a row goes on, so one giant "line"
+// in a broken file would all end up in memory. This sits in front of it and errors out as soon as
+// a line goes over the limit, before that happens.
+// It goes by newlines, so a quoted field with newlines in it counts as several shorter lines
+pub struct RowLimitReader<R> {
+    inner: R,
+    max_row_bytes: usize,
+    // Bytes since the last newline, and which line that is
+    row_bytes: usize,
+    line: u64,
+}
+
+impl<R> RowLimitReader<R> {
+    pub fn new(inner: R, max_row_bytes: usize) -> Self {
+        Self {
+            inner,
+            max_row_bytes,
+            row_bytes: 0,
+            line: 1,
+        }
+    }
+}
+
+impl<R> RowLimitReader<R> {
+    fn check_row(&self, row_bytes: usize) -> io::Result<()> {
+        if row_bytes > self.max_row_bytes {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Line {} is longer than the max row size of {} bytes",
+                    self.line, self.max_row_bytes
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<R: io::Read> io::Read for RowLimitReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        let chunk = &buf[..read];
+        // Only the line that was already going and the last one can be too long, unless the chunk
+        // is longer than the limit itself, which is rare enough to just go line by line.
+        // Going line by line everywhere came out ~15% slower on the 2M row file
+        if chunk.len() <= self.max_row_bytes {
+            let first_newline = chunk.iter().position(|byte| *byte == b'\n');
+            let last_newline = chunk.iter().rposition(|byte| *byte == b'\n');
+            if let (Some(first_newline), Some(last_newline)) = (first_newline, last_newline) {
+                self.check_row(self.row_bytes + first_newline)?;
+                self.line += chunk.iter().filter(|byte| **byte == b'\n').count() as u64;
+                self.row_bytes = chunk.len() - last_newline - 1;
+            } else {
+                self.row_bytes += chunk.len();
+                self.check_row(self.row_bytes)?;
+            }
+            return Ok(read);
+        }
+        for (i, segment) in chunk.split(|byte| *byte == b'\n').enumerate() {
+            if i > 0 {
+                self.row_bytes = 0;
+                self.line += 1;
+            }
+            self.row_bytes += segment.len();
+            self.check_row(self.row_bytes)?;
+        }
+        Ok(read)
+    }
+}
+
+// Retries failed reads with a backoff, so that a network filesystem hiccuping doesn't end the whole run.
+// It's meant to sit right on top of the file, under decompression and csv parsing, so it only ever sees
+// errors from the actual reading, and a broken file still fails right away.
+// Sleeps in between, so it's no use on wasm
+pub struct RetryReader<R> {
+    inner: R,
+    retries: u32,
+    // --quiet, for the warning about each retry
+    quiet: bool,
+}
+
+impl<R> RetryReader<R> {
+    pub fn new(inner: R, retries: u32, quiet: bool) -> Self {
+        Self {
+            inner,
+            retries,
+            quiet,
+        }
+    }
+}
+
+impl<R: io::Read> io::Read for RetryReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut backoff = Duration::from_millis(10);
+        let mut attempt = 0;
+        loop {
+            match self.inner.read(buf) {
+                // Garbage is garbage however many times it's read
+                Err(err) if err.kind() != io::ErrorKind::InvalidData && attempt < self.retries => {
+                    attempt += 1;
+                    if !self.quiet {
+                        eprintln!(
+                            "Read failed ({err}), retrying in {backoff:?}, attempt {attempt} of {}",
+                            self.retries
+                        );
+                    }
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(Duration::from_secs(5));
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+pub fn reader_builder(input_config: &InputConfig) -> ReaderBuilder {
+    let mut builder = ReaderBuilder::new();
+    builder
+        .flexible(true)
+        .trim(input_config.trim)
+        .delimiter(input_config.delimiter)
+        .quote(input_config.quote)
+        .has_headers(input_config.column_order.is_none());
+    // Zero would read nothing at all, so it's the default like None is
+    if let Some(buffer_size) = input_config.buffer_size.filter(|size| *size > 0) {
+        builder.buffer_capacity(buffer_size);
+    }
+    builder
+}
+
+// The delimiter and quote a file most likely has, from its first few KB. A delimiter has to be on every line,
+// and the one there's the most of on the first line wins. Anything less clear cut than that is a comma.
+// The quote is whichever of `"` and `'` starts more fields, `"` if neither does
+pub fn detect_dialect(sample: &[u8], complete: bool) -> (u8, u8) {
+    let mut lines: Vec<&[u8]> = sample.split(|byte| *byte == b'\n').collect();
+    // The sample most likely cut the last line off halfway
+    if !complete && lines.len() > 1 {
+        lines.pop();
+    }
+    let lines: Vec<&[u8]> = lines
+        .into_iter()
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .filter(|line| !line.is_empty())
+        .collect();
+    // Not counting anything inside double quotes, which is where a delimiter-looking thing is most likely to be
+    let count = |line: &[u8], delimiter: u8| {
+        let mut quoted = false;
+        line.iter()
+            .filter(|byte| {
+                if **byte == b'"' {
+                    quoted = !quoted;
+                }
+                !quoted && **byte == delimiter
+            })
+            .count()
+    };
+    let mut candidates: Vec<(usize, u8)> = [b',', b';', b'\t']
+        .into_iter()
+        .filter(|delimiter| lines.iter().all(|line| count(line, *delimiter) > 0))
+        .map(|delimiter| {
+            (
+                lines.first().map_or(0, |line| count(line, delimiter)),
+                delimiter,
+            )
+        })
+        .collect();
+    candidates.sort_unstable_by_key(|(count, _)| cmp::Reverse(*count));
+    let delimiter = match candidates.as_slice() {
+        [(best, delimiter), (second, _), ..] if best > second => *delimiter,
+        [(_, delimiter)] => *delimiter,
+        _ => b',',
+    };
+    let starts_fields = |quote: u8| {
+        lines
+            .iter()
+            .map(|line| {
+                let after_delimiter = line
+                    .windows(2)
+                    .filter(|pair| pair[0] == delimiter && pair[1] == quote)
+                    .count();
+                after_delimiter + usize::from(line.first() == Some(&quote))
+            })
+            .sum::<usize>()
+    };
+    let quote = if starts_fields(b'\'') > starts_fields(b'"') {
+        b'\''
+    } else {
+        b'"'
+    };
+    (delimiter, quote)
+}
+
+pub fn deserialize_transactions<R: io::Read>(
+    mut csv_reader: csv::Reader<R>,
+    input_config: &InputConfig,
+) -> impl Iterator<Item = Result<Transaction, anyhow::Error>> {
+    // A broken header row is the first error out, rather than every row failing to find its columns
+    let headers = match &input_config.column_order {
+        Some(column_order) => Ok(column_order),
+        None => csv_reader.headers(),
+    };
+    let (headers, header_error) = match headers {
+        Ok(headers) => (headers.clone(), validate_headers(headers).err()),
+        Err(err) => (StringRecord::new(), Some(err.into())),
+    };
+    // The csv reader doesn't trim the very first record when there's no header row
+    let mut trim_first = input_config.column_order.is_some()
+        && matches!(input_config.trim, csv::Trim::All | csv::Trim::Fields);
+    let input_config = input_config.clone();
+    header_error
+        .map(Err)
+        .into_iter()
+        .chain(csv_reader.into_byte_records().filter_map(move |result| {
+            result
+                .map_err(anyhow::Error::from)
+                .and_then(|mut record| {
+                    if trim_first {
+                        record.trim();
+                        trim_first = false;
+                    }
+                    decode_record(record, &input_config)
+                })
+                .and_then(|record| parse_record(&record, &headers, &input_config))
+                .transpose()
+        }))
+}
+
+// Every line on its own, instead of one reader over all of them. A stray quote in a corrupted line
+// would otherwise make the csv reader take everything up to the next quote as one field, and the rows
+// in it with it. Here a broken line is a `BadLine`, and the next one starts fresh.
+// Quoted fields can't have newlines in them like this, which a transaction's shouldn't anyway
+pub fn deserialize_lines<R: io::BufRead>(
+    reader: R,
+    input_config: &InputConfig,
+) -> impl Iterator<Item = Result<Transaction, anyhow::Error>> {
+    let input_config = input_config.clone();
+    let mut headers = input_config.column_order.clone();
+    // Each line gets a reader of its own, which would start counting from zero, so errors would all say line 1
+    let (mut line_number, mut byte) = (0, 0);
+    reader.split(b'\n').filter_map(move |line| {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => return Some(Err(err.into())),
+        };
+        line_number += 1;
+        let mut position = csv::Position::new();
+        position.set_line(line_number).set_byte(byte);
+        byte += line.len() as u64 + 1;
+        let record = parse_line(&line, position, headers.is_none(), &input_config);
+        let Some(headers) = &headers else {
+            // Nothing to go by without the header row, so that one still has to be right
+            return record
+                .and_then(|record| {
+                    let Some(record) = record else {
+                        return Ok(());
+                    };
+                    validate_headers(&record)?;
+                    headers = Some(record);
+                    Ok(())
+                })
+                .err()
+                .map(Err);
+        };
+        let transaction = record.and_then(|record| match record {
+            Some(record) => parse_record(&record, headers, &input_config),
+            None => Ok(None),
+        });
+        match transaction {
+            // These have their own flags for what to do with them
+            Err(err)
+                if err.is::<BadAmount>()
+                    || err.is::<MissingAmount>()
+                    || err.is::<NonPositiveAmount>()
+                    || err.is::<UnknownType>() =>
+            {
+                Some(Err(err))
+            }
+            Err(err) => Some(Err(BadLine {
+                line: line_number,
+                err,
+            }
+            .into())),
+            Ok(transaction) => transaction.map(Ok),
+        }
+    })
+}
+
+// None is a blank line
+fn parse_line(
+    line: &[u8],
+    position: csv::Position,
+    is_header: bool,
+    input_config: &InputConfig,
+) -> Result<Option<StringRecord>, anyhow::Error> {
+    let mut csv_reader = reader_builder(input_config)
+        .has_headers(false)
+        .from_reader(line);
+    let Some(record) = csv_reader.byte_records().next() else {
+        return Ok(None);
+    };
+    let mut record = record?;
+    // Only one row per line, or the rest of them would quietly go missing
+    if csv_reader.byte_records().next().is_some() {
+        anyhow::bail!("there's more than one row on it, it might need --normalize-newlines");
+    }
+    record.set_position(Some(position));
+    // A reader without headers doesn't trim its very first record, which is every record here, so it's done by hand.
+    // Same as the repl, but that one only has data lines, and this has the header line too for --trim to tell apart
+    let trim = match input_config.trim {
+        csv::Trim::All => true,
+        csv::Trim::Headers => is_header,
+        csv::Trim::Fields => !is_header,
+        _ => false,
+    };
+    if trim {
+        record.trim();
+    }
+    decode_record(record, input_config).map(Some)
+}
+
+// A line that didn't parse with --recover-lines, which gets skipped (or rejected with --strict) rather than ending the run
+#[derive(Debug)]
+pub struct BadLine {
+    line: u64,
+    err: anyhow::Error,
+}
+
+impl fmt::Display for BadLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {} is broken, {}", self.line, self.err)
+    }
+}
+
+impl std::error::Error for BadLine {}
+
+// Records get read as bytes and decoded here, because the csv crate's own error for bad UTF-8
+// only says which field it's in and how far into it, which is hard to find in a big file
+pub fn decode_record(
+    record: ByteRecord,
+    input_config: &InputConfig,
+) -> Result<StringRecord, anyhow::Error> {
+    let err = match StringRecord::from_byte_record(record) {
+        Ok(record) => return Ok(record),
+        Err(err) => err,
+    };
+    let (field, valid_up_to) = (err.utf8_error().field(), err.utf8_error().valid_up_to());
+    let record = err.into_byte_record();
+    if input_config.lossy_utf8 {
+        let mut lossy: StringRecord = record.iter().map(String::from_utf8_lossy).collect();
+        lossy.set_position(record.position().cloned());
+        return Ok(lossy);
+    }
+    let Some(position) = record.position() else {
+        anyhow::bail!("invalid UTF-8 in field {}", field + 1);
+    };
+    // The fields before it plus a delimiter each, which is exact as long as nothing in the row is quoted
+    let in_record: usize = record
+        .iter()
+        .take(field)
+        .map(|field| field.len() + 1)
+        .sum::<usize>()
+        + valid_up_to;
+    anyhow::bail!(
+        "invalid UTF-8 at byte {} (line {}, field {})",
+        position.byte() as usize + in_record,
+        position.line(),
+        field + 1
+    )
+}
+
+const EXPECTED_HEADERS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+// Columns can come in any order, but a misspelled one would otherwise only show up as
+// a confusing "missing field" on the first row that needs it, or not at all for `amount`
+pub fn validate_headers(headers: &StringRecord) -> Result<(), anyhow::Error> {
+    // An empty file has no header row, and no transactions either, which is fine
+    if headers.is_empty() {
+        return Ok(());
+    }
+    let missing: Vec<&str> = EXPECTED_HEADERS
+        .into_iter()
+        .filter(|expected| !headers.iter().any(|header| header == *expected))
+        .collect();
+    let unexpected: Vec<&str> = headers
+        .iter()
+        .filter(|header| !EXPECTED_HEADERS.contains(header))
+        .collect();
+    if !missing.is_empty() || !unexpected.is_empty() {
+        anyhow::bail!(
+            "Bad header row, missing columns: [{}], unexpected columns: [{}]. Expected exactly {}",
+            missing.join(", "),
+            unexpected.join(", "),
+            EXPECTED_HEADERS.join(", ")
+        );
+    }
+    Ok(())
+}
+
+// One csv row to a `Transaction`, for readers that go record by record. None is a row that's meant to be skipped
+pub fn parse_record(
+    record: &StringRecord,
+    headers: &StringRecord,
+    input_config: &InputConfig,
+) -> Result<Option<Transaction>, anyhow::Error> {
+    if input_config.skip_blank_type {
+        let type_index = headers.iter().position(|header| header == "type");
+        if type_index.and_then(|type_index| record.get(type_index)) == Some("") {
+            return Ok(None);
+        }
+    }
+    // A commit doesn't need a client or a tx, so it can't go through the usual deserializing
+    let type_index = headers.iter().position(|header| header == "type");
+    let type_name = type_index.and_then(|type_index| record.get(type_index));
+    if type_name == Some("commit") {
+        return Ok(Some(Transaction::Commit));
+    }
+    // Most likely a type that got added upstream after this was written, which --skip-unknown-types can skip.
+    // A blank one is a broken row rather than a new type, and only gets skipped with --skip-blank-type
+    if let Some(type_name) = type_name.filter(|type_name| {
+        !type_name.is_empty()
+            && !TransactionType::ALL
+                .iter()
+                .any(|known| known.as_str() == *type_name)
+    }) {
+        let tx_index = headers.iter().position(|header| header == "tx");
+        return Err(UnknownType {
+            type_name: type_name.to_owned(),
+            tx: tx_index
+                .and_then(|tx_index| record.get(tx_index))
+                .unwrap_or_default()
+                .to_owned(),
+        }
+        .into());
+    }
+    let stripped = strip_currency(record, headers, input_config);
+    let record = stripped.as_ref().unwrap_or(record);
+    let mut transaction_record: TransactionRecord = record
+        .deserialize(Some(headers))
+        .map_err(|err| bad_amount(record, headers).unwrap_or_else(|| err.into()))?;
+    check_schema(&mut transaction_record, input_config)?;
+    Transaction::try_from(transaction_record).map(Some)
+}
+
+// Only deposits, withdrawals, holds and releases have amounts, and those have to be above zero. With --strict-schema anything else is an error,
+// except for reversals, which upstreams send with the (negative) amount they take back, so that's part of the format.
+// Otherwise a stray amount on a dispute gets ignored, and a row that's missing its amount gets skipped.
+// Resolves and chargebacks are allowed to claim an amount, unless the schema is strict
+fn check_schema(
+    record: &mut TransactionRecord,
+    input_config: &InputConfig,
+) -> Result<(), anyhow::Error> {
+    let transaction_type = record.transaction_type;
+    let tx = record.transaction_id;
+    let needs_amount = matches!(
+        transaction_type,
+        TransactionType::Deposit
+            | TransactionType::Withdrawal
+            | TransactionType::Hold
+            | TransactionType::Release
+    );
+    match (needs_amount, record.value) {
+        (true, None) if !input_config.strict_schema => {
+            return Err(MissingAmount {
+                transaction_type,
+                tx,
+            }
+            .into());
+        }
+        (false, Some(_))
+            if input_config.strict_schema && transaction_type != TransactionType::Reversal =>
+        {
+            anyhow::bail!("Invalid record: {transaction_type:?} {tx} should not have an amount")
+        }
+        (false, Some(_)) if transaction_type == TransactionType::Dispute => record.value = None,
+        // A negative deposit is a withdrawal that skips the funds check, and a negative withdrawal is free money.
+        // Zero doesn't move anything, it only leaves a tx behind to be disputed
+        (true, Some(amount)) if amount <= Decimal::ZERO => {
+            return Err(NonPositiveAmount {
+                transaction_type,
+                tx,
+                amount,
+            }
+            .into());
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// A row of a type that isn't one of `TransactionType`. An error, unless it's skipped with --skip-unknown-types.
+// The tx is whatever was in its column, there's no knowing what it means for a type like that
+#[derive(Debug)]
+pub struct UnknownType {
+    type_name: String,
+    tx: String,
+}
+
+impl fmt::Display for UnknownType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} is of an unknown type", self.type_name, self.tx)
+    }
+}
+
+impl std::error::Error for UnknownType {}
+
+// A row that can't be applied without an amount, and that gets skipped (or rejected with --strict) rather than ending the run
+#[derive(Debug)]
+pub struct MissingAmount {
+    transaction_type: TransactionType,
+    tx: TransactionId,
+}
+
+impl fmt::Display for MissingAmount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} {} has no amount", self.transaction_type, self.tx)
+    }
+}
+
+impl std::error::Error for MissingAmount {}
+
+// Same as a missing amount, skipped or rejected with --strict
+#[derive(Debug)]
+pub struct NonPositiveAmount {
+    transaction_type: TransactionType,
+    tx: TransactionId,
+    amount: Decimal,
+}
+
+impl fmt::Display for NonPositiveAmount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} {} has an amount of {}, which isn't above zero",
+            self.transaction_type, self.tx, self.amount
+        )
+    }
+}
+
+impl std::error::Error for NonPositiveAmount {}
+
+// An amount that isn't a number gets its own error instead of csv's "Failed to parse", so that --lenient-amount can tell it apart.
+// Only checked once a row failed, the rows that parse fine don't pay for it
+fn bad_amount(record: &StringRecord, headers: &StringRecord) -> Option<anyhow::Error> {
+    let amount = record.get(headers.iter().position(|header| header == "amount")?)?;
+    if amount.is_empty() || Decimal::from_str(amount).is_ok() {
+        return None;
+    }
+    Some(
+        BadAmount {
+            amount: amount.to_owned(),
+            line: record.position().map(|position| position.line()),
+        }
+        .into(),
+    )
+}
+
+#[derive(Debug)]
+pub struct BadAmount {
+    amount: String,
+    line: Option<u64>,
+}
+
+impl fmt::Display for BadAmount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "amount {:?} isn't a number", self.amount)?;
+        if let Some(line) = self.line {
+            write!(f, ", on line {line}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for BadAmount {}
+
+// A copy of the record with the currency taken off the amount, or None if there was nothing to take off,
+// so that the usual plain amounts don't cost an extra allocation
+fn strip_currency(
+    record: &StringRecord,
+    headers: &StringRecord,
+    input_config: &InputConfig,
+) -> Option<StringRecord> {
+    if input_config.strip_currency.is_empty() {
+        return None;
+    }
+    let amount_index = headers.iter().position(|header| header == "amount")?;
+    let amount = record.get(amount_index)?;
+    let mut stripped = amount;
+    for symbol in &input_config.strip_currency {
+        if let Some(rest) = stripped.strip_prefix(symbol.as_str()) {
+            stripped = rest.trim_start();
+        }
+        if let Some(rest) = stripped.strip_suffix(symbol.as_str()) {
+            stripped = rest.trim_end();
+        }
+    }
+    if stripped.len() == amount.len() {
+        return None;
+    }
+    Some(
+        record
+            .iter()
+            .enumerate()
+            .map(|(index, field)| {
+                if index == amount_index {
+                    stripped
+                } else {
+                    field
+                }
+            })
+            .collect(),
+    )
+}
+
+pub fn handle_transactions<S: TxStore>(
+    transaction_iter: impl Iterator<Item = Result<Transaction, anyhow::Error>>,
+    state: &mut EngineState<S>,
+    config: &ProcessingConfig,
+) -> Result<(), anyhow::Error> {
+    for transaction in transaction_iter {
+        let mut transaction = match transaction {
+            Ok(transaction) => transaction,
+            // The row didn't parse, so there's no telling whether it was a deposit or a dispute that didn't need the amount anyway.
+            // Disputes don't come with amounts though, so this is in practice always a deposit or a withdrawal
+            Err(err) if config.lenient_amount && err.is::<BadAmount>() => {
+                diagnostic(config, format_args!("Skipping, {err}"));
+                continue;
+            }
+            Err(err) if config.skip_unknown_types && err.is::<UnknownType>() => {
+                reject(config, err.to_string())?;
+                continue;
+            }
+            Err(err)
+                if err.is::<MissingAmount>()
+                    || err.is::<NonPositiveAmount>()
+                    || err.is::<BadLine>() =>
+            {
+                reject(config, err.to_string())?;
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        // A checkpoint rather than a transaction, there's no client to apply it to
+        if let Transaction::Commit = transaction {
+            state.stats.records += 1;
+            state.stats.by_type[TransactionType::Commit as usize] += 1;
+            state.commit()?;
+            continue;
+        }
+        let client_id = transaction.client_id();
+
+        if state.snapshot_requested.swap(false, Ordering::Relaxed) {
+            state.dump_snapshot(config);
+        }
+        state.stats.records += 1;
+        // ALL is in declaration order, so the discriminant is the index
+        state.stats.by_type[transaction.transaction_type() as usize] += 1;
+        if let Some(finalize_after) = config.finalize_after {
+            if state.stats.records.is_multiple_of(finalize_after) {
+                state.finalize_idle_clients(finalize_after)?;
+            }
+        }
+
+        // Same record twice in a row (or anywhere, really) is most likely the upstream export stuttering
+        // Only the hash is kept, 8 bytes a record instead of a whole copy of it
+        if config.dedup {
+            let mut hasher = DefaultHasher::new();
+            transaction.hash(&mut hasher);
+            if !state.seen_records.insert(hasher.finish()) {
+                state.stats.duplicates_skipped += 1;
+                continue;
+            }
+        }
+        if let Some(scale) = config.normalize_scale {
+            normalize_amount(&mut transaction, scale, config)?;
+        }
+
+        let EngineState {
+            client_states,
+            seen_clients,
+            tx_database,
+            stats,
+            dispute_deadlines,
+            finalized_clients,
+            commit_sink,
+            changed_since_commit,
+            observers,
+            explain_tracer,
+            ..
+        } = state;
+
+        // For disputes etc we're modifying the client id in the dispute transaction,
+        // And not in the original transaction
+        // Quite unsafe if we do not trust the data source (but we do)
+        // Create a default client if none exists
+        if client_states.get(client_id).is_none() {
+            // Its row has already been written out, so it'll get a second one, starting from zero
+            if finalized_clients.remove(&client_id) {
+                if config.strict {
+                    anyhow::bail!("Client {client_id} came back after being finalized");
+                }
+                diagnostic(
+                    config,
+                    format_args!(
+                        "Client {client_id} came back after being finalized, starting it over"
+                    ),
+                );
+            }
+            seen_clients.push(client_id);
+        }
+        let client = client_states.get_or_insert(client_id)?;
+        client.last_seen = stats.records;
+        if commit_sink.is_some() {
+            changed_since_commit.insert(client_id);
+        }
+
+        let applied = apply_and_report(
+            &transaction,
+            client,
+            tx_database,
+            stats,
+            observers,
+            explain_tracer,
+            config,
+        )?;
+        if let Some(settlement) = early_settlement(&transaction, &applied, client) {
+            apply_and_report(
+                &settlement,
+                client,
+                tx_database,
+                stats,
+                observers,
+                explain_tracer,
+                config,
+            )?;
+        }
+
+        let Some(auto_resolve_after) = config.auto_resolve_after else {
+            continue;
+        };
+        if let Applied::Dispute { .. } = applied {
+            dispute_deadlines.push_back((
+                stats.records.saturating_add(auto_resolve_after),
+                client_id,
+                transaction.transaction_id(),
+            ));
+        }
+        while let Some(&(deadline, client_id, tx)) = dispute_deadlines.front() {
+            if deadline > stats.records {
+                break;
+            }
+            dispute_deadlines.pop_front();
+            // Might have been resolved or charged back in the meantime. A tx that got resolved and then disputed again
+            // goes by the first dispute's deadline
+            let Some(client) = client_states.get_mut(client_id) else {
+                continue;
+            };
+            if !client.txns_under_dispute.contains_key(&tx) {
+                continue;
+            }
+            diagnostic(
+                config,
+                format_args!("Auto resolving dispute of tx {tx} for client {client_id}, open for {auto_resolve_after} records"),
+            );
+            let resolve = Transaction::Resolve {
+                client: client_id,
+                tx,
+                amount: None,
+            };
+            apply_and_report(
+                &resolve,
+                client,
+                tx_database,
+                stats,
+                observers,
+                explain_tracer,
+                config,
+            )?;
+        }
+    }
+    if let Some((sink, _)) = &mut state.finalized_sink {
+        sink.finish()?;
+    }
+    for observer in &mut state.observers {
+        observer.finish()?;
+    }
+    Ok(())
+}
+
+// A resolve or chargeback --buffer-ooo held back for a dispute that just went through, to be applied next.
+// The dispute stays applied whatever happens to it
+fn early_settlement(
+    transaction: &Transaction,
+    applied: &Applied,
+    client: &mut ClientState,
+) -> Option<Transaction> {
+    match (transaction, applied) {
+        (Transaction::Dispute { tx, .. }, Applied::Dispute { .. }) => {
+            client.early_settlements.remove(tx)
+        }
+        _ => None,
+    }
+}
+
+// `apply_one`, and everything that goes along with it: --explain, the stats, --detect-lock-conflicts, --track-tx-ids
+// and the observers. For the transactions from the input, and just as much for the settlements --buffer-ooo held back
+// and the resolves --auto-resolve-after makes up, which change the client all the same
+fn apply_and_report<S: TxStore>(
+    transaction: &Transaction,
+    client: &mut ClientState,
+    tx_database: &mut S,
+    stats: &mut ProcessingStats,
+    observers: &mut [Box<dyn TransactionObserver>],
+    explain_tracer: &mut Option<ExplainTracer>,
+    config: &ProcessingConfig,
+) -> Result<Applied, anyhow::Error> {
+    let client_id = transaction.client_id();
+    let before = (client.available, client.held, client.locked);
+    let applied = apply_one(transaction, client, tx_database, config)?;
+    if config.explain == Some(transaction.transaction_id()) {
+        let line = format!(
+            "explain: {transaction:?} -> {applied:?}, client {client_id} available {} -> {}, held {} -> {}, locked {} -> {}",
+            before.0, client.available, before.1, client.held, before.2, client.locked
+        );
+        match explain_tracer {
+            Some(tracer) => tracer(&line),
+            None => eprintln!("{line}"),
+        }
+    }
+    match applied {
+        // These are only ever shown, so running past what a Decimal holds isn't worth ending the run over
+        Applied::Deposit { amount } => {
+            stats.total_deposited = stats.total_deposited.saturating_add(amount);
+        }
+        Applied::Withdrawal { amount } => {
+            stats.total_withdrawn = stats.total_withdrawn.saturating_add(amount);
+        }
+        Applied::Skipped(SkipReason::NotUnderDispute)
+            if matches!(transaction, Transaction::Resolve { .. }) =>
+        {
+            stats.orphan_resolves += 1;
+            if config.warn_orphan_resolve {
+                reject(
+                    config,
+                    format!(
+                        "resolve of tx {} for client {client_id}, which isn't under dispute",
+                        transaction.transaction_id()
+                    ),
+                )?;
+            }
+        }
+        _ => {}
+    }
+    if config.detect_lock_conflicts && client.seed_locked {
+        lock_conflict(transaction, &applied, client_id, config)?;
+    }
+    if config.track_tx_ids
+        && matches!(
+            applied,
+            Applied::Deposit { .. } | Applied::Withdrawal { .. } | Applied::Hold { .. }
+        )
+    {
+        client.tx_ids.push(transaction.transaction_id());
+    }
+    for observer in observers.iter_mut() {
+        observer.observe(transaction, &applied, client)?;
+    }
+    Ok(applied)
+}
+
+// A seeded client is locked because of a chargeback in some earlier run. A resolve means the input thinks
+// a dispute of it ended without one, and another chargeback means it got locked twice
+fn lock_conflict(
+    transaction: &Transaction,
+    applied: &Applied,
+    client_id: ClientId,
+    config: &ProcessingConfig,
+) -> Result<(), anyhow::Error> {
+    let tx = transaction.transaction_id();
+    let conflict = match applied {
+        Applied::Resolve { .. } => "got a resolve",
+        Applied::Chargeback { .. } => "got locked again by a chargeback",
+        _ => return Ok(()),
+    };
+    if config.strict {
+        anyhow::bail!("Client {client_id} is locked in the seed, but {conflict} of tx {tx}");
+    }
+    diagnostic(
+        config,
+        format_args!(
+            "Lock conflict, client {client_id} is locked in the seed, but {conflict} of tx {tx}"
+        ),
+    );
+    Ok(())
+}
+
+// What applying a single transaction did to the client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Applied {
+    Deposit { amount: Decimal },
+    Withdrawal { amount: Decimal },
+    // A withdrawal with not enough available, nothing moved
+    Overdraft,
+    // `held` is what actually moved, which isn't always the disputed amount
+    Dispute { held: Decimal },
+    Resolve { released: Decimal },
+    Chargeback { removed: Decimal },
+    Hold { amount: Decimal },
+    Release { amount: Decimal },
+    Reversal { amount: Decimal },
+    // A resolve or chargeback waiting for its dispute, with --buffer-ooo
+    Buffered,
+    // Nothing happened. Anything worth a warning already got one
+    Skipped(SkipReason),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    // Dispute of a tx we've never seen
+    UnknownTransaction,
+    // Refers to a tx that isn't a deposit or a withdrawal
+    NotDisputable,
+    // Dispute of a tx that's still under dispute from before
+    AlreadyDisputed,
+    // Resolve or chargeback of a tx that isn't disputed (anymore), or doesn't exist at all
+    NotUnderDispute,
+    // Resolve or chargeback claiming another amount than what was disputed
+    AmountMismatch,
+    // Hold or release without --allow-admin
+    AdminNotAllowed,
+    // Hold of more than is available, or release of more than is on hold
+    InsufficientFunds,
+    // Reversal of something that isn't a deposit, or of one that's disputed, charged back or already reversed
+    NotReversible,
+    // Deposit or withdrawal over --max-amount
+    OverMaxAmount,
+    // Its type is turned off with --disable
+    Disabled,
+    // The new balance wouldn't fit in a Decimal
+    Overflow,
+    // Dispute of a tx for zero, with --skip-zero-disputes
+    ZeroAmount,
+    // A commit, which isn't about any client. `handle_transactions` deals with those before they get here
+    NotAClient,
+}
+
+// The whole per-transaction logic, for what's already known to be the right client.
+// `handle_transactions` is a loop over this, but it's also usable on its own to replay things one by one.
+// Either the whole transaction goes through or nothing about the client or the tx database changes:
+// every arm works out the new balances and does whatever can fail first, and only then writes anything
+pub fn apply_one(
+    transaction: &Transaction,
+    client: &mut ClientState,
+    tx_database: &mut impl TxStore,
+    config: &ProcessingConfig,
+) -> Result<Applied, anyhow::Error> {
+    let client_id = transaction.client_id();
+    let transaction_type = transaction.transaction_type();
+    if config.disabled.contains(&transaction_type) {
+        diagnostic(
+            config,
+            format_args!(
+                "Skipping, {transaction_type:?} {} as those are disabled",
+                transaction.transaction_id()
+            ),
+        );
+        return Ok(Applied::Skipped(SkipReason::Disabled));
+    }
+    // An amount this big is a fat finger or a corrupted row rather than real money, whichever way it goes
+    if let Transaction::Deposit { tx, amount, .. } | Transaction::Withdrawal { tx, amount, .. } =
+        *transaction
+    {
+        if let Some(max_amount) = config
+            .max_amount
+            .filter(|max_amount| amount.abs() > *max_amount)
+        {
+            reject(
+                config,
+                format!("tx {tx} of {amount} for client {client_id}, over the max amount of {max_amount}"),
+            )?;
+            return Ok(Applied::Skipped(SkipReason::OverMaxAmount));
+        }
+    }
+    let applied = match *transaction {
+        Transaction::Deposit { tx, amount, .. } => {
+            // Money coming into an account that got charged back is more likely broken upstream data than a real deposit
+            if client.locked && config.error_on_locked_deposit {
+                anyhow::bail!("Deposit {tx} for client {client_id}, which is locked");
+            }
+            let Some(available) = client.available.checked_add(amount) else {
+                return overflow(transaction, config);
+            };
+            // Store transaction for posterity
+            tx_database.save(
+                StoredTransaction {
+                    transaction_type: TransactionType::Deposit,
+                    transaction_id: tx,
+                    amount,
+                    dispute_status: DisputeStatus::Undisputed,
+                },
+                config.tx_conflict,
+            )?;
+            client.available = available;
+            client.deposits += 1;
+            client.volume = client.volume.saturating_add(amount);
+            Applied::Deposit { amount }
+        }
+        Transaction::Withdrawal { tx, amount, .. } => {
+            // The spec does not mention if a failed withdrawal is disputable
+            // There's no harm in treating it as such, but it needs to be specified
+            let Some(available) = client.available.checked_sub(amount) else {
+                return overflow(transaction, config);
+            };
+            let balance = match config.withdraw_against {
+                WithdrawAgainst::Available => Some(client.available),
+                WithdrawAgainst::Total => client.available.checked_add(client.held),
+            };
+            let Some(balance) = balance else {
+                return overflow(transaction, config);
+            };
+            tx_database.save(
+                StoredTransaction {
+                    transaction_type: TransactionType::Withdrawal,
+                    transaction_id: tx,
+                    amount,
+                    dispute_status: DisputeStatus::Undisputed,
+                },
+                config.tx_conflict,
+            )?;
+            if balance < amount {
+                client.overdraft_attempts += 1;
+                return Ok(Applied::Overdraft);
+            }
+
+            client.available = available;
+            client.withdrawals += 1;
+            client.volume = client.volume.saturating_add(amount);
+            Applied::Withdrawal { amount }
+        }
+        Transaction::Dispute { tx, .. } => {
+            // Its amount is already held, holding it again would leave the extra behind once it's settled
+            if client.txns_under_dispute.contains_key(&tx) {
+                reject(
+                    config,
+                    format!(
+                        "dispute of tx {tx} for client {client_id}, which is already under dispute"
+                    ),
+                )?;
+                return Ok(Applied::Skipped(SkipReason::AlreadyDisputed));
+            }
+            // Note that there's no checking that the dispute belongs to the same client as the transaction, as that was not specified
+            let Some(referenced_tx) = tx_database.query(tx) else {
+                return Ok(Applied::Skipped(SkipReason::UnknownTransaction));
+            };
+            // Admin holds and releases aren't something a client can dispute
+            if !referenced_tx.is_disputable() {
+                return Ok(Applied::Skipped(SkipReason::NotDisputable));
+            }
+            // Nothing would move, but the tx would still end up disputed, and then resolved or charged back
+            if config.skip_zero_disputes && referenced_tx.amount.is_zero() {
+                diagnostic(
+                    config,
+                    format_args!(
+                        "Skipping, dispute of tx {tx} for client {client_id}, which is for zero"
+                    ),
+                );
+                return Ok(Applied::Skipped(SkipReason::ZeroAmount));
+            }
+
+            // Which way the money goes is up to the policy, see `DisputePolicy`
+            let semantics = config
+                .dispute_policy
+                .for_type(referenced_tx.transaction_type);
+            let mut held = referenced_tx.amount;
+            if referenced_tx.is_withdrawal() {
+                if config.withdrawal_dispute_noop {
+                    // Still counts as disputed, the money just doesn't move
+                    held = Decimal::ZERO;
+                } else if config.safe_withdrawal_dispute
+                    && semantics.dispute.available == Direction::Down
+                {
+                    // Only hold what's actually there, so that available never goes below zero
+                    held = held.min(client.available.max(Decimal::ZERO));
+                    if held < referenced_tx.amount {
+                        diagnostic(config, format_args!(
+                            "Dispute of withdrawal {tx} for client {client_id}: only {held} of {} could be held, {} is unaccounted for",
+                            referenced_tx.amount,
+                            referenced_tx.amount - held
+                        ));
+                    }
+                }
+            }
+
+            let Some((available, client_held)) = semantics.dispute.apply(client, held) else {
+                return overflow(transaction, config);
+            };
+
+            client.txns_under_dispute.insert(
+                referenced_tx.transaction_id,
+                OpenDispute {
+                    amount: referenced_tx.amount,
+                    held,
+                    transaction_type: referenced_tx.transaction_type,
+                },
+            );
+
+            client.available = available;
+            client.held = client_held;
+            client.disputes += 1;
+            tx_database.set_dispute_status(tx, DisputeStatus::Disputed);
+            // A resolve or chargeback that showed up before this dispute can go through now, but that's
+            // a transaction of its own that can fail on its own, so it's up to the caller, see `early_settlement`
+            Applied::Dispute { held }
+        }
+        // Everything these need got stored with the dispute, so the tx database isn't asked again
+        Transaction::Resolve { tx, amount, .. } => {
+            let Some(dispute) = client.txns_under_dispute.get(&tx) else {
+                return Ok(buffer_early_settlement(transaction, client, config));
+            };
+            if !claimed_amount_matches("resolve", tx, amount, dispute.amount, config)? {
+                return Ok(Applied::Skipped(SkipReason::AmountMismatch));
+            }
+            // Whatever was held on dispute goes back, which isn't always the full amount
+            let held = dispute.held;
+            let semantics = config.dispute_policy.for_type(dispute.transaction_type);
+            let Some((available, client_held)) = semantics.resolve.apply(client, held) else {
+                return overflow(transaction, config);
+            };
+            client.txns_under_dispute.remove(&tx);
+
+            client.held = client_held;
+            client.available = available;
+            tx_database.set_dispute_status(tx, DisputeStatus::Resolved);
+            Applied::Resolve { released: held }
+        }
+        Transaction::Chargeback { tx, amount, .. } => {
+            let Some(dispute) = client.txns_under_dispute.get(&tx) else {
+                return Ok(buffer_early_settlement(transaction, client, config));
+            };
+            if !claimed_amount_matches("chargeback", tx, amount, dispute.amount, config)? {
+                return Ok(Applied::Skipped(SkipReason::AmountMismatch));
+            }
+            let held = dispute.held;
+            let (available, client_held, manually_held, removed) = match config.chargeback_source {
+                ChargebackSource::Held => {
+                    let semantics = config.dispute_policy.for_type(dispute.transaction_type);
+                    let Some((available, client_held)) = semantics.chargeback.apply(client, held)
+                    else {
+                        return overflow(transaction, config);
+                    };
+                    (
+                        Some(available),
+                        Some(client_held),
+                        Some(client.manually_held),
+                        held,
+                    )
+                }
+                ChargebackSource::Available => (
+                    client.available.checked_sub(dispute.amount),
+                    Some(client.held),
+                    client.manually_held.checked_add(held),
+                    dispute.amount,
+                ),
+                ChargebackSource::Total => (
+                    dispute
+                        .amount
+                        .checked_sub(held)
+                        .and_then(|rest| client.available.checked_sub(rest)),
+                    client.held.checked_sub(held),
+                    Some(client.manually_held),
+                    dispute.amount,
+                ),
+            };
+            let (Some(available), Some(client_held), Some(manually_held)) =
+                (available, client_held, manually_held)
+            else {
+                return overflow(transaction, config);
+            };
+            client.txns_under_dispute.remove(&tx);
+
+            client.available = available;
+            client.held = client_held;
+            client.manually_held = manually_held;
+            // Spec does not mention if an account being frozen blocks future transactions, so I'm not doing that
+            client.locked = true;
+            client.chargebacks += 1;
+            tx_database.set_dispute_status(tx, DisputeStatus::ChargedBack);
+            Applied::Chargeback { removed }
+        }
+        // Manual holds placed by operators, separate from the dispute machinery
+        Transaction::Hold { tx, amount, .. } => {
+            if !config.allow_admin {
+                reject(config, format!("hold {tx} without --allow-admin"))?;
+                return Ok(Applied::Skipped(SkipReason::AdminNotAllowed));
+            }
+            if client.available < amount {
+                reject(
+                    config,
+                    format!(
+                        "hold {tx} of {amount} for client {client_id}, only {} is available",
+                        client.available
+                    ),
+                )?;
+                return Ok(Applied::Skipped(SkipReason::InsufficientFunds));
+            }
+            let (Some(available), Some(client_held), Some(manually_held)) = (
+                client.available.checked_sub(amount),
+                client.held.checked_add(amount),
+                client.manually_held.checked_add(amount),
+            ) else {
+                return overflow(transaction, config);
+            };
+            tx_database.save(
+                StoredTransaction {
+                    transaction_type: TransactionType::Hold,
+                    transaction_id: tx,
+                    amount,
+                    dispute_status: DisputeStatus::Undisputed,
+                },
+                config.tx_conflict,
+            )?;
+            client.available = available;
+            client.held = client_held;
+            client.manually_held = manually_held;
+            Applied::Hold { amount }
+        }
+        Transaction::Release { tx, amount, .. } => {
+            if !config.allow_admin {
+                reject(config, format!("release {tx} without --allow-admin"))?;
+                return Ok(Applied::Skipped(SkipReason::AdminNotAllowed));
+            }
+            // Only what was put on hold manually can be released, the disputed part of held is off limits
+            if client.manually_held < amount {
+                reject(
+                    config,
+                    format!("release {tx} of {amount} for client {client_id}, only {} is on manual hold", client.manually_held),
+                )?;
+                return Ok(Applied::Skipped(SkipReason::InsufficientFunds));
+            }
+            let (Some(client_held), Some(available), Some(manually_held)) = (
+                client.held.checked_sub(amount),
+                client.available.checked_add(amount),
+                client.manually_held.checked_sub(amount),
+            ) else {
+                return overflow(transaction, config);
+            };
+            tx_database.save(
+                StoredTransaction {
+                    transaction_type: TransactionType::Release,
+                    transaction_id: tx,
+                    amount,
+                    dispute_status: DisputeStatus::Undisputed,
+                },
+                config.tx_conflict,
+            )?;
+            client.held = client_held;
+            client.available = available;
+            client.manually_held = manually_held;
+            Applied::Release { amount }
+        }
+        Transaction::Commit => return Ok(Applied::Skipped(SkipReason::NotAClient)),
+        Transaction::Reversal { tx, amount, .. } => {
+            let Some(referenced_tx) = tx_database.query(tx) else {
+                return Ok(Applied::Skipped(SkipReason::UnknownTransaction));
+            };
+            // A resolved dispute is fine, the money is back in available by then
+            if referenced_tx.transaction_type != TransactionType::Deposit
+                || !matches!(
+                    referenced_tx.dispute_status,
+                    DisputeStatus::Undisputed | DisputeStatus::Resolved
+                )
+            {
+                reject(
+                    config,
+                    format!(
+                        "reversal of tx {tx}, which is a {:?} that's {:?}",
+                        referenced_tx.transaction_type, referenced_tx.dispute_status
+                    ),
+                )?;
+                return Ok(Applied::Skipped(SkipReason::NotReversible));
+            }
+            let reversed = referenced_tx.amount;
+            if let Some(claimed) = amount.filter(|claimed| *claimed != -reversed) {
+                reject(
+                    config,
+                    format!("reversal of tx {tx} claims an amount of {claimed}, but it would be {} for a deposit of {reversed}",
+                        -reversed),
+                )?;
+                return Ok(Applied::Skipped(SkipReason::AmountMismatch));
+            }
+            if client.available < reversed {
+                reject(
+                    config,
+                    format!(
+                        "reversal of tx {tx} of {reversed} for client {client_id}, only {} is available",
+                        client.available
+                    ),
+                )?;
+                return Ok(Applied::Skipped(SkipReason::InsufficientFunds));
+            }
+            let Some(available) = client.available.checked_sub(reversed) else {
+                return overflow(transaction, config);
+            };
+            client.available = available;
+            tx_database.set_dispute_status(tx, DisputeStatus::Reversed);
+            Applied::Reversal { amount: reversed }
+        }
+    };
+    Ok(applied)
+}
+
+// Pads the amount out to `scale` decimal places, so that every stored amount and every balance has the same scale.
+// One with more places than that gets rounded, half to even, or is an error with --strict
+fn normalize_amount(
+    transaction: &mut Transaction,
+    scale: u32,
+    config: &ProcessingConfig,
+) -> Result<(), anyhow::Error> {
+    let tx = transaction.transaction_id();
+    let Some(amount) = transaction.amount_mut() else {
+        return Ok(());
+    };
+    if amount.scale() > scale {
+        let rounded = amount.round_dp_with_strategy(scale, RoundingStrategy::MidpointNearestEven);
+        if config.strict {
+            anyhow::bail!(
+                "tx {tx} has an amount of {amount}, which has more than {scale} decimal places"
+            );
+        }
+        diagnostic(
+            config,
+            format_args!("Rounding the amount of tx {tx} from {amount} to {rounded}"),
+        );
+        *amount = rounded;
+    }
+    amount.rescale(scale);
+    Ok(())
+}
+
+// Decimal panics when a sum doesn't fit, which is no way to find out about a corrupted row
+fn overflow(
+    transaction: &Transaction,
+    config: &ProcessingConfig,
+) -> Result<Applied, anyhow::Error> {
+    reject(
+        config,
+        format!(
+            "{:?} {} for client {} would overflow its balance",
+            transaction.transaction_type(),
+            transaction.transaction_id(),
+            transaction.client_id()
+        ),
+    )?;
+    Ok(Applied::Skipped(SkipReason::Overflow))
+}
+
+// With out of order streams, a resolve or chargeback can come before the dispute it settles.
+// Only the latest one per tx is kept, a second one for the same tx would be settling it twice anyway
+fn buffer_early_settlement(
+    transaction: &Transaction,
+    client: &mut ClientState,
+    config: &ProcessingConfig,
+) -> Applied {
+    if !config.buffer_ooo {
+        return Applied::Skipped(SkipReason::NotUnderDispute);
+    }
+    client
+        .early_settlements
+        .insert(transaction.transaction_id(), transaction.clone());
+    Applied::Buffered
+}
+
+// A resolve or chargeback that comes with an amount different from the disputed one is inconsistent,
+// and we can't tell which side is wrong, so it doesn't get applied
+fn claimed_amount_matches(
+    kind: &str,
+    tx: TransactionId,
+    claimed: Option<Decimal>,
+    disputed: Decimal,
+    config: &ProcessingConfig,
+) -> Result<bool, anyhow::Error> {
+    match claimed {
+        Some(claimed) if claimed != disputed => {
+            reject(
+                config,
+                format!(
+                    "{kind} of tx {tx} claims an amount of {claimed}, but {disputed} was disputed"
+                ),
+            )?;
+            Ok(false)
+        }
+        _ => Ok(true),
+    }
+}
+
+// For records that can't be applied: an error with --strict, a warning and a skip otherwise
+fn reject(config: &ProcessingConfig, message: String) -> Result<(), anyhow::Error> {
+    if config.strict {
+        anyhow::bail!(message);
+    }
+    diagnostic(config, format_args!("Skipping, {message}"));
+    Ok(())
+}
+
+// Warnings and stats all go through here, so that --quiet can shut them up.
+// Output that was explicitly asked for, like --explain or the reports, doesn't
+fn diagnostic(config: &ProcessingConfig, message: fmt::Arguments) {
+    if !config.quiet {
+        eprintln!("{message}");
+    }
+}
+
+// All the places where the spec is vague enough that someone might want it the other way
+#[derive(Debug, Default)]
+pub struct ProcessingConfig {
+    // Disputing a withdrawal moves money that has already left the account into held,
+    // which is what the spec says, but makes no physical sense.
+    // With this on, a disputed withdrawal is only marked as disputed, and balances stay put
+    pub withdrawal_dispute_noop: bool,
+    // Same idea, but a disputed withdrawal holds as much as the account has left instead of going negative
+    pub safe_withdrawal_dispute: bool,
+    // Error out on inconsistent data instead of skipping it
+    pub strict: bool,
+    pub error_on_locked_deposit: bool,
+    // Holds and releases come from operators, so they're only accepted when the input is trusted to be from one
+    pub allow_admin: bool,
+    // Skip records that are identical in every field to an earlier one. Unlike reusing a tx id,
+    // an exact copy of a whole row can't be anything but a duplicate
+    pub dedup: bool,
+    // Deposits and withdrawals above this get rejected. None is unbounded
+    pub max_amount: Option<Decimal>,
+    // Trace everything done by and to this tx id to stderr, balances before and after included
+    pub explain: Option<TransactionId>,
+    // Keep resolves and chargebacks for txs that aren't disputed, and apply them once the dispute shows up,
+    // instead of dropping them. For streams that got reordered on the way
+    pub buffer_ooo: bool,
+    // No warnings or stats on stderr
+    pub quiet: bool,
+    // Records of these types are no-ops, e.g. for staging, where chargebacks shouldn't be locking accounts
+    pub disabled: HashSet<TransactionType>,
+    // Skip rows where the amount isn't a number, instead of erroring out
+    pub lenient_amount: bool,
+    // Skip rows of a type that isn't one of ours (a newer upstream one, say) with a warning, instead of erroring out.
+    // Still an error with --strict
+    pub skip_unknown_types: bool,
+    // Disputes that are still open after this many more records get resolved, as a dispute timeout
+    pub auto_resolve_after: Option<u64>,
+    // Clients with no records for this many records get written out and dropped early, for streaming huge inputs.
+    // Their open disputes can't be resolved anymore after that
+    pub finalize_after: Option<u64>,
+    // Which record a tx id refers to when more than one has it, for what disputes see
+    pub tx_conflict: TxConflict,
+    // Say something about resolves of txs that aren't under dispute (an error with --strict),
+    // instead of quietly skipping them. They're counted either way
+    pub warn_orphan_resolve: bool,
+    // Which way money moves on disputes, resolves and chargebacks
+    pub dispute_policy: DisputePolicy,
+    // Put every amount on this many decimal places as it comes in, see `normalize_amount`. None leaves them as they are
+    pub normalize_scale: Option<u32>,
+    // Keep a list of every client's own txs, for --with-tx-ids. Off by default, it's a few bytes per record
+    pub track_tx_ids: bool,
+    // Which balance a withdrawal has to be covered by
+    pub withdraw_against: WithdrawAgainst,
+    // Say something (an error with --strict) when the input disagrees with a seeded client being locked
+    pub detect_lock_conflicts: bool,
+    pub chargeback_source: ChargebackSource,
+    // Leave txs for zero undisputed, rather than going through a dispute that doesn't move anything
+    pub skip_zero_disputes: bool,
+}
+
+// Which way a balance moves, by the disputed amount
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Up,
+    Down,
+    Unchanged,
+}
+
+impl Direction {
+    fn shift(self, balance: Decimal, amount: Decimal) -> Option<Decimal> {
+        match self {
+            Direction::Up => balance.checked_add(amount),
+            Direction::Down => balance.checked_sub(amount),
+            Direction::Unchanged => Some(balance),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Movement {
+    pub available: Direction,
+    pub held: Direction,
+}
+
+impl Movement {
+    // The client's new available and held, or None if either would overflow
+    fn apply(self, client: &ClientState, amount: Decimal) -> Option<(Decimal, Decimal)> {
+        Some((
+            self.available.shift(client.available, amount)?,
+            self.held.shift(client.held, amount)?,
+        ))
+    }
+}
+
+// What each step of a dispute does to the balances, for one kind of disputed transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisputeSemantics {
+    pub dispute: Movement,
+    pub resolve: Movement,
+    pub chargeback: Movement,
+}
+
+// A dispute moves the amount from available to held, a resolve moves it back, a chargeback takes it out of held
+const SPEC_SEMANTICS: DisputeSemantics = DisputeSemantics {
+    dispute: Movement {
+        available: Direction::Down,
+        held: Direction::Up,
+    },
+    resolve: Movement {
+        available: Direction::Up,
+        held: Direction::Down,
+    },
+    chargeback: Movement {
+        available: Direction::Unchanged,
+        held: Direction::Down,
+    },
+};
+
+// All of the interpretation of what disputing something means, in one place.
+// Locking on a chargeback and the dispute bookkeeping are the same whatever the policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisputePolicy {
+    pub deposit: DisputeSemantics,
+    pub withdrawal: DisputeSemantics,
+}
+
+impl DisputePolicy {
+    // The spec, to the letter, for both. That doesn't make sense for a withdrawal, the money has already left,
+    // so there's nothing in available to take. But "decrease" means "decrease", and the spec is the spec
+    pub const SPEC_LITERAL: Self = Self {
+        deposit: SPEC_SEMANTICS,
+        withdrawal: SPEC_SEMANTICS,
+    };
+    // Deposits go by the spec. A disputed withdrawal is money that might be coming back, so it gets held
+    // without touching available, a resolve (the withdrawal stands) drops it again,
+    // and a chargeback (the withdrawal gets reversed) puts it back into available
+    pub const REALISTIC: Self = Self {
+        deposit: SPEC_SEMANTICS,
+        withdrawal: DisputeSemantics {
+            dispute: Movement {
+                available: Direction::Unchanged,
+                held: Direction::Up,
+            },
+            resolve: Movement {
+                available: Direction::Unchanged,
+                held: Direction::Down,
+            },
+            chargeback: Movement {
+                available: Direction::Up,
+                held: Direction::Down,
+            },
+        },
+    };
+
+    // Only deposits and withdrawals can be disputed
+    fn for_type(&self, transaction_type: TransactionType) -> &DisputeSemantics {
+        match transaction_type {
+            TransactionType::Withdrawal => &self.withdrawal,
+            _ => &self.deposit,
+        }
+    }
+}
+
+impl Default for DisputePolicy {
+    fn default() -> Self {
+        Self::SPEC_LITERAL
+    }
+}
+
+// Where a chargeback takes the money from. Only `Held` goes by the dispute policy, the other two
+// take the whole disputed amount out, whatever was held for it (which is less with --safe-withdrawal-dispute and such)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ChargebackSource {
+    // What got held for the dispute is what goes
+    #[default]
+    Held,
+    // It all comes out of available. The hold stays on, as a manual one an operator can release
+    Available,
+    // The held part goes, and whatever's left of the disputed amount on top of it comes out of available
+    Total,
+}
+
+// Some specs let held money count towards what can be withdrawn. It still comes out of available either way,
+// held is tied to disputes and has to be there when they're resolved, so available can go negative with `Total`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WithdrawAgainst {
+    #[default]
+    Available,
+    Total,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TxConflict {
+    First,
+    #[default]
+    Last,
+    Error,
+}
+
+// Same as `ProcessingConfig`, but for what the output looks like
+#[derive(Debug, Clone)]
+pub struct OutputConfig {
+    pub with_overdraft_count: bool,
+    // A column with the client's own tx ids, which needs `ProcessingConfig::track_tx_ids` to have been on
+    pub with_tx_ids: bool,
+    // A column with the client's average deposit or withdrawal
+    pub with_avg_tx: bool,
+    // Integers in the smallest unit (1.2345 -> 12345) instead of decimals, for accounting systems that want that
+    pub minor_units: bool,
+    // How many decimal places a minor unit is, and how many every amount gets printed with. The spec says four
+    pub precision: u32,
+    // Downstream parsers can be picky, especially ones in locales that write decimals with commas
+    pub quote_style: QuoteStyle,
+    // Off for appending to an existing file that already has them
+    pub headers: bool,
+    // Overrides `precision` per currency code, since JPY has no minor units to speak of and BTC has eight
+    pub currency_precision: HashMap<String, u32>,
+    // A disputed withdrawal can drive a total below zero, see `ProcessingConfig::withdrawal_dispute_noop`
+    pub negative_total: NegativeTotal,
+    pub sort_by: SortBy,
+    // Some Windows consumers want \r\n
+    pub terminator: csv::Terminator,
+    // A made up last row with everything summed up, as a quick sanity check
+    pub with_house_row: bool,
+    // Its client id, which had better not be a real client
+    pub house_id: ClientId,
+    // How `locked` gets written out
+    pub bool_style: BoolStyle,
+    // And how `client` does
+    pub client_format: ClientFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortBy {
+    // The order the clients first showed up in
+    Insertion,
+    TotalDesc,
+    TotalAsc,
+    ClientAsc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BoolStyle {
+    TrueFalse,
+    OneZero,
+    YesNo,
+}
+
+impl BoolStyle {
+    fn format(self, value: bool) -> &'static str {
+        match (self, value) {
+            (BoolStyle::TrueFalse, true) => "true",
+            (BoolStyle::TrueFalse, false) => "false",
+            (BoolStyle::OneZero, true) => "1",
+            (BoolStyle::OneZero, false) => "0",
+            (BoolStyle::YesNo, true) => "yes",
+            (BoolStyle::YesNo, false) => "no",
+        }
+    }
+}
+
+// For matching against systems that have their ids in hex, or lining the column up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientFormat {
+    Decimal,
+    // Lowercase, without a 0x
+    Hex,
+    // Zeros in front up to this width. Anything wider is left as it is
+    Padded(usize),
+}
+
+impl ClientFormat {
+    fn format(self, client_id: ClientId) -> String {
+        match self {
+            ClientFormat::Decimal => client_id.to_string(),
+            ClientFormat::Hex => format!("{client_id:x}"),
+            ClientFormat::Padded(width) => format!("{client_id:0width$}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NegativeTotal {
+    Allow,
+    // Only the total gets clamped, available and held stay what they are, so they won't add up to it anymore
+    Clamp,
+    Error,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            with_overdraft_count: false,
+            with_tx_ids: false,
+            with_avg_tx: false,
+            minor_units: false,
+            precision: 4,
+            quote_style: QuoteStyle::Necessary,
+            headers: true,
+            currency_precision: HashMap::new(),
+            negative_total: NegativeTotal::Allow,
+            sort_by: SortBy::Insertion,
+            terminator: csv::Terminator::Any(b'\n'),
+            with_house_row: false,
+            // Still what it was when client ids were u16
+            house_id: u16::MAX as ClientId,
+            bool_style: BoolStyle::TrueFalse,
+            client_format: ClientFormat::Decimal,
+        }
+    }
+}
+
+impl OutputConfig {
+    pub fn precision_for(&self, currency: Option<&str>) -> u32 {
+        currency
+            .and_then(|currency| self.currency_precision.get(currency))
+            .copied()
+            .unwrap_or(self.precision)
+    }
+}
+
+// Where the client records end up. It's CSV on stdout for the CLI, but an embedder can send them anywhere
+pub trait OutputSink {
+    fn emit(&mut self, record: &ClientRecord) -> Result<(), anyhow::Error>;
+    // Called once all the records are out
+    fn finish(&mut self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+// For one sink that's written to from several places, like the finalized clients and the commits going to the same stdout
+impl<T: OutputSink + ?Sized> OutputSink for Rc<RefCell<T>> {
+    fn emit(&mut self, record: &ClientRecord) -> Result<(), anyhow::Error> {
+        self.borrow_mut().emit(record)
+    }
+    fn finish(&mut self) -> Result<(), anyhow::Error> {
+        self.borrow_mut().finish()
+    }
+}
+
+// A hook into processing, called right after each transaction with what it did and the client as it is after.
+// That's the resolves --auto-resolve-after makes up too, and the settlements --buffer-ooo held back, once they go through
+pub trait TransactionObserver {
+    fn observe(
+        &mut self,
+        transaction: &Transaction,
+        applied: &Applied,
+        client: &ClientState,
+    ) -> Result<(), anyhow::Error>;
+    // Called at the end of every `handle_transactions`
+    fn finish(&mut self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+// An audit trail of every deposit and withdrawal that went through, with the running available balance
+pub struct LedgerObserver<W: io::Write> {
+    csv_writer: Writer<W>,
+}
+
+impl<W: io::Write> LedgerObserver<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            csv_writer: Writer::from_writer(writer),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct LedgerRow {
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+    client: ClientId,
+    tx: TransactionId,
+    amount: Decimal,
+    available: Decimal,
+}
+
+impl<W: io::Write> TransactionObserver for LedgerObserver<W> {
+    fn observe(
+        &mut self,
+        transaction: &Transaction,
+        applied: &Applied,
+        client: &ClientState,
+    ) -> Result<(), anyhow::Error> {
+        let (Applied::Deposit { amount } | Applied::Withdrawal { amount }) = *applied else {
+            return Ok(());
+        };
+        self.csv_writer.serialize(LedgerRow {
+            transaction_type: transaction.transaction_type(),
+            client: transaction.client_id(),
+            tx: transaction.transaction_id(),
+            amount,
+            available: client.available,
+        })?;
+        Ok(())
+    }
+    fn finish(&mut self) -> Result<(), anyhow::Error> {
+        self.csv_writer.flush()?;
+        Ok(())
+    }
+}
+
+// Every dispute that got opened, resolved or charged back, with how much moved and where the client ended up.
+// Narrower than the ledger, and the other way around: nothing about plain deposits and withdrawals
+pub struct DisputeLogObserver<W: io::Write> {
+    csv_writer: Writer<W>,
+}
+
+impl<W: io::Write> DisputeLogObserver<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            csv_writer: Writer::from_writer(writer),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DisputeLogRow {
+    event: &'static str,
+    client: ClientId,
+    tx: TransactionId,
+    amount: Decimal,
+    available: Decimal,
+    held: Decimal,
+    locked: bool,
+}
+
+impl<W: io::Write> TransactionObserver for DisputeLogObserver<W> {
+    fn observe(
+        &mut self,
+        transaction: &Transaction,
+        applied: &Applied,
+        client: &ClientState,
+    ) -> Result<(), anyhow::Error> {
+        let (event, amount) = match *applied {
+            Applied::Dispute { held } => ("open", held),
+            Applied::Resolve { released } => ("resolve", released),
+            Applied::Chargeback { removed } => ("chargeback", removed),
+            _ => return Ok(()),
+        };
+        self.csv_writer.serialize(DisputeLogRow {
+            event,
+            client: transaction.client_id(),
+            tx: transaction.transaction_id(),
+            amount,
+            available: client.available,
+            held: client.held,
+            locked: client.locked,
+        })?;
+        Ok(())
+    }
+    fn finish(&mut self) -> Result<(), anyhow::Error> {
+        self.csv_writer.flush()?;
+        Ok(())
+    }
+}
+
+pub struct CsvSink<W: io::Write> {
+    csv_writer: Writer<W>,
+    bool_style: BoolStyle,
+    client_format: ClientFormat,
+}
+
+// What a `ClientRecord` looks like once it's in the csv. `locked` and `client` are the only things that need
+// formatting config of their own, and serde has no way of passing that to a field's serializer
+#[derive(Serialize)]
+struct CsvRow<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<&'a str>,
+    client: String,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    overdraft_attempts: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tx_ids: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avg_tx: Option<Option<Decimal>>,
+}
+
+impl<W: io::Write> CsvSink<W> {
+    pub fn new(writer: W, output_config: &OutputConfig) -> Self {
+        Self {
+            csv_writer: WriterBuilder::new()
+                .quote_style(output_config.quote_style)
+                .has_headers(output_config.headers)
+                .terminator(output_config.terminator)
+                .from_writer(writer),
+            bool_style: output_config.bool_style,
+            client_format: output_config.client_format,
+        }
+    }
+}
+
+impl<W: io::Write> OutputSink for CsvSink<W> {
+    fn emit(&mut self, record: &ClientRecord) -> Result<(), anyhow::Error> {
+        self.csv_writer.serialize(CsvRow {
+            source: record.source.as_deref(),
+            client: self.client_format.format(record.client_id),
+            available: record.available,
+            held: record.held,
+            total: record.total,
+            locked: self.bool_style.format(record.locked),
+            overdraft_attempts: record.overdraft_attempts,
+            tx_ids: record.tx_ids.as_deref(),
+            avg_tx: record.avg_tx,
+        })?;
+        Ok(())
+    }
+    fn finish(&mut self) -> Result<(), anyhow::Error> {
+        self.csv_writer.flush()?;
+        Ok(())
+    }
+}
+
+// Locked clients go to one sink and everyone else to another, for when they're dealt with separately downstream
+pub struct LockedSplitSink {
+    locked: Box<dyn OutputSink>,
+    unlocked: Box<dyn OutputSink>,
+}
+
+impl LockedSplitSink {
+    pub fn new(locked: Box<dyn OutputSink>, unlocked: Box<dyn OutputSink>) -> Self {
+        Self { locked, unlocked }
+    }
+}
+
+impl OutputSink for LockedSplitSink {
+    fn emit(&mut self, record: &ClientRecord) -> Result<(), anyhow::Error> {
+        if record.locked {
+            self.locked.emit(record)
+        } else {
+            self.unlocked.emit(record)
+        }
+    }
+    fn finish(&mut self) -> Result<(), anyhow::Error> {
+        self.locked.finish()?;
+        self.unlocked.finish()
+    }
+}
+
+// Each state comes with the namespace it belongs to, if we're namespacing
+pub fn write_output<'a, S: TxStore + 'a>(
+    sink: &mut dyn OutputSink,
+    states: impl IntoIterator<Item = (Option<&'a str>, &'a EngineState<S>)>,
+    output_config: &OutputConfig,
+) -> Result<(), anyhow::Error> {
+    for client_record in &output_records(states, output_config)? {
+        sink.emit(client_record)?;
+    }
+    sink.finish()
+}
+
+// Every row of the output, in order, house row included
+pub fn output_records<'a, S: TxStore + 'a>(
+    states: impl IntoIterator<Item = (Option<&'a str>, &'a EngineState<S>)>,
+    output_config: &OutputConfig,
+) -> Result<Vec<ClientRecord>, anyhow::Error> {
+    // Collected up front so that they can be sorted, all the states together
+    let mut client_records = Vec::new();
+    for (namespace, state) in states {
+        collect_client_records(&mut client_records, namespace, state, output_config)?;
+    }
+    // Stable sorts, so ties stay in the order the clients showed up in
+    match output_config.sort_by {
+        SortBy::Insertion => {}
+        SortBy::TotalDesc => {
+            client_records.sort_by_key(|client_record| cmp::Reverse(client_record.total))
+        }
+        SortBy::TotalAsc => client_records.sort_by_key(|client_record| client_record.total),
+        SortBy::ClientAsc => client_records.sort_by_key(|client_record| client_record.client_id),
+    }
+    if output_config.with_house_row {
+        // Two rows for the same client would be read as one of them being wrong
+        if client_records
+            .iter()
+            .any(|client_record| client_record.client_id == output_config.house_id)
+        {
+            anyhow::bail!(
+                "The house row's id {} is taken by a real client, pick another with --house-id",
+                output_config.house_id
+            );
+        }
+        let house_record = house_record(&client_records, output_config.house_id)?;
+        client_records.push(house_record);
+    }
+    Ok(client_records)
+}
+
+// What a `CsvSink` wrote, read back in, for --round-trip-check
+#[derive(Deserialize)]
+struct OutputRow {
+    source: Option<String>,
+    // As formatted with `ClientFormat`
+    client: String,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: String,
+    overdraft_attempts: Option<u32>,
+    tx_ids: Option<String>,
+    avg_tx: Option<Decimal>,
+}
+
+// Reads the csv output back in and checks it against the records it was written from, to catch anything
+// that got lost on the way to text. The display conversion already happened in the records, so amounts
+// have to come back exactly, 1.5 and 1.5000 being the same. Needs the header row to know what's what
+pub fn round_trip_check(
+    output: &[u8],
+    client_records: &[ClientRecord],
+    output_config: &OutputConfig,
+) -> Result<(), anyhow::Error> {
+    let mut csv_reader = ReaderBuilder::new().from_reader(output);
+    let mut rows = csv_reader.deserialize::<OutputRow>();
+    for (index, expected) in client_records.iter().enumerate() {
+        let Some(row) = rows.next() else {
+            anyhow::bail!(
+                "Output has {index} rows, but {} were written",
+                client_records.len()
+            );
+        };
+        let row = row?;
+        // An empty field comes back as None, and the house row has empty ones where the others have values
+        let same = row.source.as_deref().unwrap_or_default()
+            == expected.source.as_deref().unwrap_or_default()
+            && row.client == output_config.client_format.format(expected.client_id)
+            && row.available == expected.available
+            && row.held == expected.held
+            && row.total == expected.total
+            && row.locked == output_config.bool_style.format(expected.locked)
+            && row.overdraft_attempts == expected.overdraft_attempts
+            && row.tx_ids.as_deref().unwrap_or_default()
+                == expected.tx_ids.as_deref().unwrap_or_default()
+            && row.avg_tx == expected.avg_tx.flatten();
+        if !same {
+            anyhow::bail!(
+                "Output row {} doesn't match what was written: client {}, available {}, held {}, total {}, locked {} came back as client {}, available {}, held {}, total {}, locked {}",
+                index + 1,
+                expected.client_id,
+                expected.available,
+                expected.held,
+                expected.total,
+                expected.locked,
+                row.client,
+                row.available,
+                row.held,
+                row.total,
+                row.locked
+            );
+        }
+    }
+    if rows.next().is_some() {
+        anyhow::bail!(
+            "Output has more rows than the {} that were written",
+            client_records.len()
+        );
+    }
+    Ok(())
+}
+
+// Summed up from what's being output, so with --minor-units it's a sum of minor units, and so on
+fn house_record(
+    client_records: &[ClientRecord],
+    house_id: ClientId,
+) -> Result<ClientRecord, anyhow::Error> {
+    let mut house_record = ClientRecord {
+        // It's from all of them, but with namespacing, the column still has to be there
+        source: client_records
+            .iter()
+            .any(|client_record| client_record.source.is_some())
+            .then(String::new),
+        client_id: house_id,
+        available: Decimal::ZERO,
+        held: Decimal::ZERO,
+        total: Decimal::ZERO,
+        locked: false,
+        overdraft_attempts: None,
+        // The house has no txs of its own, but the column still has to be there
+        tx_ids: client_records
+            .iter()
+            .any(|client_record| client_record.tx_ids.is_some())
+            .then(String::new),
+        avg_tx: client_records
+            .iter()
+            .any(|client_record| client_record.avg_tx.is_some())
+            .then_some(None),
+    };
+    let sum = |sum: Decimal, amount: Decimal| {
+        sum.checked_add(amount)
+            .ok_or_else(|| anyhow::anyhow!("The house row's sums don't fit in a Decimal"))
+    };
+    for client_record in client_records {
+        house_record.available = sum(house_record.available, client_record.available)?;
+        house_record.held = sum(house_record.held, client_record.held)?;
+        house_record.total = sum(house_record.total, client_record.total)?;
+        if let Some(overdraft_attempts) = client_record.overdraft_attempts {
+            house_record.overdraft_attempts = Some(
+                house_record
+                    .overdraft_attempts
+                    .unwrap_or(0)
+                    .saturating_add(overdraft_attempts),
+            );
+        }
+    }
+    Ok(house_record)
+}
+
+fn collect_client_records<S: TxStore>(
+    client_records: &mut Vec<ClientRecord>,
+    namespace: Option<&str>,
+    state: &EngineState<S>,
+    output_config: &OutputConfig,
+) -> Result<(), anyhow::Error> {
+    for client_id in state.client_states.ids(&state.seen_clients) {
+        let Some(client_state) = state.client_states.get(client_id) else {
+            continue;
+        };
+        let mut client_record =
+            ClientRecord::from_id_and_state(&client_id, client_state, output_config)?;
+        client_record.source = namespace.map(str::to_owned);
+        client_records.push(client_record);
+    }
+    Ok(())
+}
+
+// Billions of transactions would overflow this. Widening it to u64 is just this line,
+// nothing else assumes it's 32 bits (tx ids are only ever hashmap keys), and the parsing below adapts on its own
+pub type TransactionId = u32;
+// The spec has u16, it's wider so that --storage hashmap and btree can take ids past that
+pub type ClientId = u32;
+
+#[derive(Serialize, Debug)]
+pub struct ClientRecord {
+    // Which input file the client came from, with --namespace-by-file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(rename = "client")]
+    pub client_id: ClientId,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+    // Optional columns are None when not asked for, so that they don't show up at all
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub overdraft_attempts: Option<u32>,
+    // Semicolon separated, so that it's still one csv column
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx_ids: Option<String>,
+    // Average deposit or withdrawal, on the output precision. Inside, None is a client without any, which gets an empty field
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avg_tx: Option<Option<Decimal>>,
+}
+
+impl ClientRecord {
+    // The one place amounts get converted for display. Processing only ever adds and subtracts Decimals,
+    // which is exact, so the total here is the exact sum, and any rounding happens once, to the final number
+    fn from_id_and_state(
+        id: &ClientId,
+        state: &ClientState,
+        output_config: &OutputConfig,
+    ) -> Result<Self, anyhow::Error> {
+        let client_id = *id;
+        // There's no currency column in the input (yet), so every client is on the default precision for now
+        let precision = output_config.precision_for(None);
+        let display = |mut amount: Decimal| -> Result<Decimal, anyhow::Error> {
+            if !output_config.minor_units {
+                // Banker's rounding, so that lots of halves don't all drift the same way, and then padded out
+                // to the same number of decimal places (5 -> 5.0000), so that the columns line up
+                amount =
+                    amount.round_dp_with_strategy(precision, RoundingStrategy::MidpointNearestEven);
+                amount.rescale(precision);
+                return Ok(amount);
+            }
+            // Silently rounding money away is the one thing this must not do
+            if amount.round_dp(precision) != amount {
+                anyhow::bail!(
+                    "Client {client_id} has an amount of {amount}, which has more than {precision} decimal places"
+                );
+            }
+            let minor = 10u64
+                .checked_pow(precision)
+                .and_then(|scale| amount.checked_mul(Decimal::from(scale)))
+                .ok_or_else(|| {
+                    anyhow::anyhow!("Client {client_id} has an amount of {amount}, which doesn't fit in minor units")
+                })?;
+            Ok(minor.round_dp(0))
+        };
+        let mut total = state.held.checked_add(state.available).ok_or_else(|| {
+            anyhow::anyhow!("Client {client_id} has a total that doesn't fit in a Decimal")
+        })?;
+        if total < Decimal::ZERO {
+            match output_config.negative_total {
+                NegativeTotal::Allow => {}
+                NegativeTotal::Clamp => total = Decimal::ZERO,
+                NegativeTotal::Error => {
+                    anyhow::bail!("Client {client_id} has a negative total of {total}")
+                }
+            }
+        }
+        let avg_tx = if output_config.with_avg_tx {
+            // Nothing to divide by for a client with neither, which checked_div says with a None
+            let count = Decimal::from(state.deposits) + Decimal::from(state.withdrawals);
+            let avg = state
+                .volume
+                .checked_div(count)
+                .map(|avg| display(avg.round_dp(precision)))
+                .transpose()?;
+            Some(avg)
+        } else {
+            None
+        };
+        Ok(Self {
+            source: None,
+            client_id,
+            available: display(state.available)?,
+            held: display(state.held)?,
+            total: display(total)?,
+            locked: state.locked,
+            overdraft_attempts: output_config
+                .with_overdraft_count
+                .then_some(state.overdraft_attempts),
+            tx_ids: output_config.with_tx_ids.then(|| {
+                let tx_ids: Vec<String> = state.tx_ids.iter().map(ToString::to_string).collect();
+                tx_ids.join(";")
+            }),
+            avg_tx,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ClientState {
+    available: Decimal,
+    held: Decimal,
+    locked: bool,
+    txns_under_dispute: HashMap<TransactionId, OpenDispute>,
+    // Resolves and chargebacks that came before their dispute, with --buffer-ooo
+    early_settlements: HashMap<TransactionId, Transaction>,
+    // Withdrawals that bounced because of insufficient funds. A decent sign of a risky account
+    overdraft_attempts: u32,
+    // The part of held that's from admin holds rather than disputes
+    manually_held: Decimal,
+    // Only the ones that went through, for --flag-suspicious
+    deposits: u32,
+    disputes: u32,
+    chargebacks: u32,
+    // Also only the ones that went through, for --with-avg-tx along with the deposits
+    withdrawals: u32,
+    // Deposits and withdrawals that went through, added up. Only ever shown, so it saturates rather than failing
+    volume: Decimal,
+    // Record number of the last one for this client, for --finalize-after
+    last_seen: u64,
+    // Deposits, withdrawals and holds that went through, in order, with `track_tx_ids`
+    tx_ids: Vec<TransactionId>,
+    // Came in locked from a seed, rather than getting locked by a chargeback in this run
+    seed_locked: bool,
+}
+
+impl ClientState {
+    pub fn new() -> Self {
+        Self::with(Decimal::ZERO, Decimal::ZERO, false)
+    }
+
+    // A client that already has history, e.g. carried over from a previous run.
+    // There's no dispute to tie a starting `held` to, so it counts as a manual hold, which an operator can release
+    pub fn with(available: Decimal, held: Decimal, locked: bool) -> Self {
+        Self {
+            available,
+            held,
+            locked,
+            txns_under_dispute: HashMap::new(),
+            early_settlements: HashMap::new(),
+            overdraft_attempts: 0,
+            manually_held: held,
+            deposits: 0,
+            disputes: 0,
+            chargebacks: 0,
+            withdrawals: 0,
+            volume: Decimal::ZERO,
+            last_seen: 0,
+            tx_ids: Vec::new(),
+            seed_locked: false,
+        }
+    }
+
+    pub fn available(&self) -> Decimal {
+        self.available
+    }
+
+    pub fn held(&self) -> Decimal {
+        self.held
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+}
+impl Default for ClientState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// One open dispute in a --dispute-ledger file
+#[derive(Serialize, Deserialize)]
+struct LedgerEntry {
+    #[serde(rename = "client")]
+    client_id: ClientId,
+    #[serde(rename = "tx")]
+    transaction_id: TransactionId,
+    amount: Decimal,
+    held: Decimal,
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+}
+
+// One client in --held-breakdown
+#[derive(Debug, Serialize)]
+pub struct HeldBreakdown {
+    // The namespace, with --namespace-by-file. Left to whoever has more than one state to fill in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    pub client: ClientId,
+    // All of it, manual holds included
+    pub held: Decimal,
+    pub disputes: Vec<DisputedHeld>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DisputedHeld {
+    pub tx: TransactionId,
+    pub held: Decimal,
+}
+
+#[derive(Debug)]
+struct OpenDispute {
+    // Of the disputed transaction
+    amount: Decimal,
+    // How much actually got held, which is less than `amount` with --safe-withdrawal-dispute or --withdrawal-dispute-noop
+    held: Decimal,
+    // Of the disputed transaction, for which half of the dispute policy settling it goes by
+    transaction_type: TransactionType,
+}
+
+// Where deposits and withdrawals get kept for disputes to refer to. `HashMapStore` is the one that's here,
+// this is so that something bounded, or on disk, can be swapped in without touching the processing
+pub trait TxStore {
+    fn query(&self, tx_id: TransactionId) -> Option<&StoredTransaction>;
+    // What happens when the id is already taken goes by `tx_conflict`
+    fn save(
+        &mut self,
+        transaction: StoredTransaction,
+        tx_conflict: TxConflict,
+    ) -> Result<(), anyhow::Error>;
+    // Nothing happens for a tx that isn't stored
+    fn set_dispute_status(&mut self, tx_id: TransactionId, dispute_status: DisputeStatus);
+    // Everything that's stored, in any order, for --dump-tx-db
+    fn transactions(&self) -> Box<dyn Iterator<Item = &StoredTransaction> + '_>;
+    // Roughly how much memory it takes up, for --report-memory. None if there's no telling, e.g. if it's on disk
+    fn approximate_bytes(&self) -> Option<usize> {
+        None
+    }
+}
+
+pub struct HashMapStore {
+    db: HashMap<TransactionId, StoredTransaction>,
+}
+
+impl HashMapStore {
+    pub fn new() -> Self {
+        Self {
+            db: HashMap::with_capacity(4096),
+        }
+    }
+}
+
+impl Default for HashMapStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TxStore for HashMapStore {
+    fn query(&self, tx_id: TransactionId) -> Option<&StoredTransaction> {
+        self.db.get(&tx_id)
+    }
+    fn save(
+        &mut self,
+        transaction: StoredTransaction,
+        tx_conflict: TxConflict,
+    ) -> Result<(), anyhow::Error> {
+        let tx_id = transaction.transaction_id;
+        match (self.db.entry(tx_id), tx_conflict) {
+            (Entry::Occupied(_), TxConflict::First) => {}
+            (Entry::Occupied(_), TxConflict::Error) => {
+                anyhow::bail!("tx id {tx_id} is used more than once")
+            }
+            (Entry::Occupied(mut entry), TxConflict::Last) => {
+                entry.insert(transaction);
+            }
+            (Entry::Vacant(entry), _) => {
+                entry.insert(transaction);
+            }
+        }
+        Ok(())
+    }
+    fn set_dispute_status(&mut self, tx_id: TransactionId, dispute_status: DisputeStatus) {
+        if let Some(transaction) = self.db.get_mut(&tx_id) {
+            transaction.dispute_status = dispute_status;
+        }
+    }
+    fn transactions(&self) -> Box<dyn Iterator<Item = &StoredTransaction> + '_> {
+        Box::new(self.db.values())
+    }
+    fn approximate_bytes(&self) -> Option<usize> {
+        Some(
+            self.db.capacity()
+                * (std::mem::size_of::<TransactionId>() + std::mem::size_of::<StoredTransaction>()),
+        )
+    }
+}
+
+fn load_tx_store(store: &mut impl TxStore, reader: impl io::Read) -> Result<(), anyhow::Error> {
+    for transaction in csv::Reader::from_reader(reader).into_deserialize() {
+        store.save(transaction?, TxConflict::Last)?;
+    }
+    Ok(())
+}
+
+// Sorted by tx id, so that dumps of the same input always come out the same
+fn dump_tx_store(store: &impl TxStore, writer: impl io::Write) -> Result<(), anyhow::Error> {
+    let mut transactions: Vec<&StoredTransaction> = store.transactions().collect();
+    transactions.sort_by_key(|transaction| transaction.transaction_id);
+    let mut csv_writer = Writer::from_writer(writer);
+    for transaction in transactions {
+        csv_writer.serialize(transaction)?;
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
+
+// What's kept of a deposit or a withdrawal (or an admin hold/release), so that disputes have something to refer to
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StoredTransaction {
+    #[serde(rename = "type")]
+    pub transaction_type: TransactionType,
+    #[serde(rename = "tx")]
+    pub transaction_id: TransactionId,
+    pub amount: Decimal,
+    // Where its latest dispute got to, mirroring what the client's open disputes say
+    #[serde(rename = "status")]
+    pub dispute_status: DisputeStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum DisputeStatus {
+    #[serde(rename = "undisputed")]
+    Undisputed,
+    #[serde(rename = "disputed")]
+    Disputed,
+    #[serde(rename = "resolved")]
+    Resolved,
+    #[serde(rename = "chargeback")]
+    ChargedBack,
+    // A deposit that got taken back with a reversal row, so there's nothing left of it to dispute
+    #[serde(rename = "reversed")]
+    Reversed,
+}
+
+impl StoredTransaction {
+    fn is_withdrawal(&self) -> bool {
+        matches!(self.transaction_type, TransactionType::Withdrawal)
+    }
+
+    fn is_disputable(&self) -> bool {
+        matches!(
+            self.transaction_type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        ) && !matches!(self.dispute_status, DisputeStatus::Reversed)
+    }
+}
+
+// The raw csv row. Go through `Transaction` to actually do anything with it
+#[derive(Deserialize, Debug)]
+pub struct TransactionRecord {
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+    #[serde(rename = "client")]
+    client_id: ClientId,
+    #[serde(rename = "tx")]
+    #[serde(deserialize_with = "deserialize_transaction_id")]
+    transaction_id: TransactionId,
+    // size unspecified in spec, so let's default to rust_decimal
+    #[serde(rename = "amount")]
+    #[serde(with = "rust_decimal::serde::str_option")]
+    value: Option<Decimal>,
+}
+// serde's own error for an id that's too big is an unhelpful "number too large to fit in target type"
+fn deserialize_transaction_id<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<TransactionId, D::Error> {
+    let id = u128::deserialize(deserializer)?;
+    TransactionId::try_from(id).map_err(|_| {
+        D::Error::custom(format!(
+            "tx id {id} is out of range, it has to fit in {}",
+            std::any::type_name::<TransactionId>()
+        ))
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransactionType {
+    #[serde(rename = "deposit")]
+    Deposit,
+    #[serde(rename = "withdrawal")]
+    Withdrawal,
+    #[serde(rename = "dispute")]
+    Dispute,
+    #[serde(rename = "resolve")]
+    Resolve,
+    #[serde(rename = "chargeback")]
+    Chargeback,
+    #[serde(rename = "hold")]
+    Hold,
+    #[serde(rename = "release")]
+    Release,
+    #[serde(rename = "reversal")]
+    Reversal,
+    #[serde(rename = "commit")]
+    Commit,
+}
+
+impl TransactionType {
+    // Every type there is, for anything that wants to list or check them without keeping its own copy.
+    // In the same order as they're declared in
+    pub const ALL: &'static [TransactionType] = &[
+        TransactionType::Deposit,
+        TransactionType::Withdrawal,
+        TransactionType::Dispute,
+        TransactionType::Resolve,
+        TransactionType::Chargeback,
+        TransactionType::Hold,
+        TransactionType::Release,
+        TransactionType::Reversal,
+        TransactionType::Commit,
+    ];
+
+    // What it's called in the `type` column. Has to match the serde renames above
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Hold => "hold",
+            TransactionType::Release => "release",
+            TransactionType::Reversal => "reversal",
+            TransactionType::Commit => "commit",
+        }
+    }
+}
+
+// What a record actually means, with the amount only being there when it's needed
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Transaction {
+    Deposit {
+        client: ClientId,
+        tx: TransactionId,
+        amount: Decimal,
+    },
+    Withdrawal {
+        client: ClientId,
+        tx: TransactionId,
+        amount: Decimal,
+    },
+    Dispute {
+        client: ClientId,
+        tx: TransactionId,
+    },
+    // These normally don't have an amount, but if one does, it has to match what was disputed
+    Resolve {
+        client: ClientId,
+        tx: TransactionId,
+        amount: Option<Decimal>,
+    },
+    Chargeback {
+        client: ClientId,
+        tx: TransactionId,
+        amount: Option<Decimal>,
+    },
+    // Admin only, they move funds between available and held directly
+    Hold {
+        client: ClientId,
+        tx: TransactionId,
+        amount: Decimal,
+    },
+    Release {
+        client: ClientId,
+        tx: TransactionId,
+        amount: Decimal,
+    },
+    // A refund the way some upstreams send them: it points at an earlier deposit and takes all of it back.
+    // Unlike a chargeback nothing gets locked, it's a normal thing to happen.
+    // If there's an amount, it's the negative of the deposit's
+    Reversal {
+        client: ClientId,
+        tx: TransactionId,
+        amount: Option<Decimal>,
+    },
+    // Not a transaction at all: a checkpoint that writes out every client that changed since the last one,
+    // without ending the stream. Only the type column matters, the rest can be left blank
+    Commit,
+}
+
+impl Transaction {
+    pub fn transaction_type(&self) -> TransactionType {
+        match self {
+            Transaction::Deposit { .. } => TransactionType::Deposit,
+            Transaction::Withdrawal { .. } => TransactionType::Withdrawal,
+            Transaction::Dispute { .. } => TransactionType::Dispute,
+            Transaction::Resolve { .. } => TransactionType::Resolve,
+            Transaction::Chargeback { .. } => TransactionType::Chargeback,
+            Transaction::Hold { .. } => TransactionType::Hold,
+            Transaction::Release { .. } => TransactionType::Release,
+            Transaction::Reversal { .. } => TransactionType::Reversal,
+            Transaction::Commit => TransactionType::Commit,
+        }
+    }
+
+    pub fn client_id(&self) -> ClientId {
+        match self {
+            Transaction::Deposit { client, .. }
+            | Transaction::Withdrawal { client, .. }
+            | Transaction::Dispute { client, .. }
+            | Transaction::Resolve { client, .. }
+            | Transaction::Chargeback { client, .. }
+            | Transaction::Hold { client, .. }
+            | Transaction::Release { client, .. }
+            | Transaction::Reversal { client, .. } => *client,
+            // Has to be something, but nothing looks a commit's client up
+            Transaction::Commit => 0,
+        }
+    }
+
+    pub fn transaction_id(&self) -> TransactionId {
+        match self {
+            Transaction::Deposit { tx, .. }
+            | Transaction::Withdrawal { tx, .. }
+            | Transaction::Dispute { tx, .. }
+            | Transaction::Resolve { tx, .. }
+            | Transaction::Chargeback { tx, .. }
+            | Transaction::Hold { tx, .. }
+            | Transaction::Release { tx, .. }
+            | Transaction::Reversal { tx, .. } => *tx,
+            Transaction::Commit => 0,
+        }
+    }
+
+    fn amount_mut(&mut self) -> Option<&mut Decimal> {
+        match self {
+            Transaction::Deposit { amount, .. }
+            | Transaction::Withdrawal { amount, .. }
+            | Transaction::Hold { amount, .. }
+            | Transaction::Release { amount, .. } => Some(amount),
+            Transaction::Resolve { amount, .. }
+            | Transaction::Chargeback { amount, .. }
+            | Transaction::Reversal { amount, .. } => amount.as_mut(),
+            Transaction::Dispute { .. } | Transaction::Commit => None,
+        }
+    }
+}
+
+impl TryFrom<TransactionRecord> for Transaction {
+    type Error = anyhow::Error;
+
+    fn try_from(record: TransactionRecord) -> Result<Self, Self::Error> {
+        let TransactionRecord {
+            transaction_type,
+            client_id: client,
+            transaction_id: tx,
+            value,
+        } = record;
+        let transaction = match (transaction_type, value) {
+            (TransactionType::Deposit, Some(amount)) => Transaction::Deposit { client, tx, amount },
+            (TransactionType::Withdrawal, Some(amount)) => {
+                Transaction::Withdrawal { client, tx, amount }
+            }
+            (TransactionType::Dispute, None) => Transaction::Dispute { client, tx },
+            (TransactionType::Resolve, amount) => Transaction::Resolve { client, tx, amount },
+            (TransactionType::Chargeback, amount) => Transaction::Chargeback { client, tx, amount },
+            (TransactionType::Hold, Some(amount)) => Transaction::Hold { client, tx, amount },
+            (TransactionType::Release, Some(amount)) => Transaction::Release { client, tx, amount },
+            (TransactionType::Reversal, amount) => Transaction::Reversal { client, tx, amount },
+            (TransactionType::Commit, _) => Transaction::Commit,
+            (
+                transaction_type @ (TransactionType::Deposit
+                | TransactionType::Withdrawal
+                | TransactionType::Hold
+                | TransactionType::Release),
+                None,
+            ) => {
+                anyhow::bail!("Invalid record: {transaction_type:?} {tx} has no amount")
+            }
+            (transaction_type, Some(_)) => {
+                anyhow::bail!("Invalid record: {transaction_type:?} {tx} should not have an amount")
+            }
+        };
+        Ok(transaction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    fn amount(amount: &str) -> Decimal {
+        Decimal::from_str(amount).unwrap()
+    }
+
+    fn new_state() -> EngineState {
+        EngineState::new(Arc::new(AtomicBool::new(false)))
+    }
+
+    // A whole csv, header row included, through a fresh state
+    fn process(csv: &str, config: &ProcessingConfig) -> Result<EngineState, anyhow::Error> {
+        let mut state = new_state();
+        process_into(&mut state, csv, config)?;
+        Ok(state)
+    }
+
+    fn process_into(
+        state: &mut EngineState,
+        csv: &str,
+        config: &ProcessingConfig,
+    ) -> Result<(), anyhow::Error> {
+        process_with(state, csv, &InputConfig::default(), config)
+    }
+
+    fn process_with(
+        state: &mut EngineState,
+        csv: &str,
+        input_config: &InputConfig,
+        config: &ProcessingConfig,
+    ) -> Result<(), anyhow::Error> {
+        let csv_reader = reader_builder(input_config).from_reader(csv.as_bytes());
+        handle_transactions(
+            deserialize_transactions(csv_reader, input_config),
+            state,
+            config,
+        )
+    }
+
+    fn output(state: &EngineState, output_config: &OutputConfig) -> String {
+        let mut output = Vec::new();
+        write_output(
+            &mut CsvSink::new(&mut output, output_config),
+            [(None, state)],
+            output_config,
+        )
+        .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    fn client(state: &EngineState, client_id: ClientId) -> &ClientState {
+        state.client_states.get(client_id).unwrap()
+    }
+
+    // Available, held, locked
+    fn balances(state: &EngineState, client_id: ClientId) -> (Decimal, Decimal, bool) {
+        let client = client(state, client_id);
+        (client.available, client.held, client.locked)
+    }
+
+    #[test]
+    fn withdrawal_dispute_noop_leaves_balances_but_records_the_dispute() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,10\n\
+                   withdrawal,1,2,4\n\
+                   dispute,1,2,\n";
+        let config = ProcessingConfig {
+            withdrawal_dispute_noop: true,
+            ..Default::default()
+        };
+        let state = process(csv, &config).unwrap();
+        assert_eq!(balances(&state, 1), (amount("6"), amount("0"), false));
+        assert!(client(&state, 1).txns_under_dispute.contains_key(&2));
+        assert!(matches!(
+            state.tx_database.query(2).unwrap().dispute_status,
+            DisputeStatus::Disputed
+        ));
+
+        // Without it, the spec's reading holds the withdrawal's amount
+        let state = process(csv, &ProcessingConfig::default()).unwrap();
+        assert_eq!(balances(&state, 1), (amount("2"), amount("4"), false));
+    }
+
+    fn record(
+        transaction_type: TransactionType,
+        tx: TransactionId,
+        value: Option<&str>,
+    ) -> TransactionRecord {
+        TransactionRecord {
+            transaction_type,
+            client_id: 1,
+            transaction_id: tx,
+            value: value.map(amount),
+        }
+    }
+
+    #[test]
+    fn transaction_from_record_needs_an_amount_exactly_where_it_should() {
+        for transaction_type in [
+            TransactionType::Deposit,
+            TransactionType::Withdrawal,
+            TransactionType::Hold,
+            TransactionType::Release,
+        ] {
+            let err = Transaction::try_from(record(transaction_type, 7, None)).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("Invalid record: {transaction_type:?} 7 has no amount")
+            );
+        }
+        let err =
+            Transaction::try_from(record(TransactionType::Dispute, 7, Some("1"))).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid record: Dispute 7 should not have an amount"
+        );
+    }
+
+    #[test]
+    fn transaction_from_record() {
+        assert_eq!(
+            Transaction::try_from(record(TransactionType::Deposit, 1, Some("2.5"))).unwrap(),
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: amount("2.5")
+            }
+        );
+        assert_eq!(
+            Transaction::try_from(record(TransactionType::Dispute, 1, None)).unwrap(),
+            Transaction::Dispute { client: 1, tx: 1 }
+        );
+        // Settlements can go either way, the amount gets checked against the dispute later
+        assert_eq!(
+            Transaction::try_from(record(TransactionType::Resolve, 1, Some("2.5"))).unwrap(),
+            Transaction::Resolve {
+                client: 1,
+                tx: 1,
+                amount: Some(amount("2.5"))
+            }
+        );
+        assert_eq!(
+            Transaction::try_from(record(TransactionType::Chargeback, 1, None)).unwrap(),
+            Transaction::Chargeback {
+                client: 1,
+                tx: 1,
+                amount: None
+            }
+        );
+    }
+
+    #[test]
+    fn failed_withdrawals_are_counted() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,5\n\
+                   withdrawal,1,2,6\n\
+                   withdrawal,1,3,10\n";
+        let state = process(csv, &ProcessingConfig::default()).unwrap();
+        assert_eq!(client(&state, 1).overdraft_attempts, 2);
+        assert_eq!(balances(&state, 1), (amount("5"), amount("0"), false));
+        let output_config = OutputConfig {
+            with_overdraft_count: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            output(&state, &output_config),
+            "client,available,held,total,locked,overdraft_attempts\n1,5.0000,0.0000,5.0000,false,2\n"
+        );
+    }
+
+    #[test]
+    fn snapshot_request_gets_dumped_and_reset() {
+        let snapshot_requested = Arc::new(AtomicBool::new(false));
+        let mut state = EngineState::new(snapshot_requested.clone());
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,10\n\
+                   deposit,2,2,5\n\
+                   withdrawal,1,3,3\n\
+                   dispute,2,2,\n";
+        process_into(&mut state, csv, &ProcessingConfig::default()).unwrap();
+        assert_eq!(
+            state.snapshot(),
+            "records: 4, duplicates skipped: 0, orphan resolves: 0, clients: 2, deposited: 15, withdrawn: 3, available: 7, held: 5"
+        );
+        // What the signal handler does, the next record picks it up
+        snapshot_requested.store(true, Ordering::Relaxed);
+        let config = ProcessingConfig {
+            quiet: true,
+            ..Default::default()
+        };
+        process_into(
+            &mut state,
+            "type,client,tx,amount\ndeposit,1,4,1\n",
+            &config,
+        )
+        .unwrap();
+        assert!(!snapshot_requested.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn minor_units() {
+        let output_config = OutputConfig {
+            minor_units: true,
+            ..Default::default()
+        };
+        let state = process(
+            "type,client,tx,amount\ndeposit,1,1,1.2345\n",
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            output(&state, &output_config),
+            "client,available,held,total,locked\n1,12345,0,12345,false\n"
+        );
+        // Money that doesn't fit in the precision can't just be rounded away
+        let state = process(
+            "type,client,tx,amount\ndeposit,1,1,1.23456\n",
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        let err =
+            ClientRecord::from_id_and_state(&1, client(&state, 1), &output_config).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Client 1 has an amount of 1.23456, which has more than 4 decimal places"
+        );
+    }
+
+    #[test]
+    fn settlement_with_the_wrong_amount_is_rejected() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,10\n\
+                   dispute,1,1,\n\
+                   resolve,1,1,3\n";
+        let config = ProcessingConfig {
+            quiet: true,
+            ..Default::default()
+        };
+        let state = process(csv, &config).unwrap();
+        // Still disputed
+        assert_eq!(balances(&state, 1), (amount("0"), amount("10"), false));
+        let strict = ProcessingConfig {
+            strict: true,
+            ..Default::default()
+        };
+        let err = process(csv, &strict).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "resolve of tx 1 claims an amount of 3, but 10 was disputed"
+        );
+        // The right amount is as good as none
+        let csv = csv.replace("resolve,1,1,3", "resolve,1,1,10.0");
+        let state = process(&csv, &strict).unwrap();
+        assert_eq!(balances(&state, 1), (amount("10"), amount("0"), false));
+    }
+
+    #[test]
+    fn process_str_is_csv_in_csv_out() {
+        assert_eq!(
+            process_str("type,client,tx,amount\ndeposit,1,1,1.5\ndeposit,2,2,2\nwithdrawal,1,3,0.5\n"),
+            Ok("client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n2,2.0000,0.0000,2.0000,false\n".to_owned())
+        );
+        assert_eq!(
+            process_str("type,client,tx\n"),
+            Err("Bad header row, missing columns: [amount], unexpected columns: []. Expected exactly type, client, tx, amount".to_owned())
+        );
+    }
+
+    #[test]
+    fn safe_withdrawal_dispute_only_holds_what_is_there() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,10\n\
+                   withdrawal,1,2,8\n\
+                   dispute,1,2,\n";
+        let config = ProcessingConfig {
+            safe_withdrawal_dispute: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let state = process(csv, &config).unwrap();
+        assert_eq!(balances(&state, 1), (amount("0"), amount("2"), false));
+        assert_eq!(client(&state, 1).txns_under_dispute[&2].held, amount("2"));
+        let state = process(csv, &ProcessingConfig::default()).unwrap();
+        assert_eq!(balances(&state, 1), (amount("-6"), amount("8"), false));
+    }
+
+    #[test]
+    fn open_disputes_report() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,10\n\
+                   deposit,1,2,2.5\n\
+                   deposit,2,3,4\n\
+                   dispute,1,1,\n\
+                   dispute,1,2,\n\
+                   resolve,1,1,\n\
+                   dispute,2,3,\n\
+                   resolve,2,3,\n";
+        let state = process(csv, &ProcessingConfig::default()).unwrap();
+        let mut report = Vec::new();
+        state.report_open_disputes(None, &mut report).unwrap();
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            "client 1: 1 open disputes, 2.5 held\n"
+        );
+    }
+
+    #[test]
+    fn tiny_buffer_gives_the_same_output() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,10\n\
+                   deposit,2,2,2.5\n\
+                   withdrawal,1,3,4\n\
+                   dispute,2,2,\n";
+        let input_config = InputConfig {
+            buffer_size: Some(3),
+            ..Default::default()
+        };
+        let mut state = new_state();
+        process_with(&mut state, csv, &input_config, &ProcessingConfig::default()).unwrap();
+        let default = process(csv, &ProcessingConfig::default()).unwrap();
+        assert_eq!(
+            output(&state, &OutputConfig::default()),
+            output(&default, &OutputConfig::default())
+        );
+        assert_eq!(balances(&state, 2), (amount("0"), amount("2.5"), false));
+    }
+
+    #[test]
+    fn deposit_into_a_locked_account_can_be_an_error() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,10\n\
+                   dispute,1,1,\n\
+                   chargeback,1,1,\n\
+                   deposit,1,2,5\n";
+        let config = ProcessingConfig {
+            error_on_locked_deposit: true,
+            ..Default::default()
+        };
+        let err = process(csv, &config).err().unwrap();
+        assert_eq!(err.to_string(), "Deposit 2 for client 1, which is locked");
+        let state = process(csv, &ProcessingConfig::default()).unwrap();
+        assert_eq!(balances(&state, 1), (amount("5"), amount("0"), true));
+    }
+
+    #[test]
+    fn hold_and_release() {
+        let config = ProcessingConfig {
+            allow_admin: true,
+            ..Default::default()
+        };
+        let mut state = new_state();
+        process_into(
+            &mut state,
+            "type,client,tx,amount\ndeposit,1,1,50\nhold,1,2,20\n",
+            &config,
+        )
+        .unwrap();
+        assert_eq!(balances(&state, 1), (amount("30"), amount("20"), false));
+        process_into(
+            &mut state,
+            "type,client,tx,amount\nrelease,1,3,20\n",
+            &config,
+        )
+        .unwrap();
+        assert_eq!(balances(&state, 1), (amount("50"), amount("0"), false));
+        assert_eq!(
+            state.tx_database.query(2).unwrap().transaction_type,
+            TransactionType::Hold
+        );
+        assert_eq!(
+            state.tx_database.query(3).unwrap().transaction_type,
+            TransactionType::Release
+        );
+
+        // Operators only
+        let config = ProcessingConfig {
+            quiet: true,
+            ..Default::default()
+        };
+        let state = process(
+            "type,client,tx,amount\ndeposit,1,1,50\nhold,1,2,20\n",
+            &config,
+        )
+        .unwrap();
+        assert_eq!(balances(&state, 1), (amount("50"), amount("0"), false));
+    }
+
+    #[test]
+    fn quote_style() {
+        let state = process(
+            "type,client,tx,amount\ndeposit,1,1,1\n",
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        let with_quote_style = |quote_style| OutputConfig {
+            quote_style,
+            ..Default::default()
+        };
+        assert_eq!(
+            output(&state, &with_quote_style(QuoteStyle::Always)),
+            "\"client\",\"available\",\"held\",\"total\",\"locked\"\n\"1\",\"1.0000\",\"0.0000\",\"1.0000\",\"false\"\n"
+        );
+        assert_eq!(
+            output(&state, &with_quote_style(QuoteStyle::Never)),
+            "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn dedup_skips_identical_rows() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,10\n\
+                   deposit,1,1,10\n\
+                   deposit,1,2,10\n";
+        let config = ProcessingConfig {
+            dedup: true,
+            ..Default::default()
+        };
+        let state = process(csv, &config).unwrap();
+        assert_eq!(balances(&state, 1), (amount("20"), amount("0"), false));
+        assert_eq!(state.stats.duplicates_skipped, 1);
+        let state = process(csv, &ProcessingConfig::default()).unwrap();
+        assert_eq!(balances(&state, 1), (amount("30"), amount("0"), false));
+    }
+
+    #[test]
+    fn no_output_header() {
+        let state = process(
+            "type,client,tx,amount\ndeposit,1,1,1\n",
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        let output_config = OutputConfig {
+            headers: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            output(&state, &output_config),
+            "1,1.0000,0.0000,1.0000,false\n"
+        );
+    }
+
+    // Goes by `TransactionId::MAX`, so it still holds as is if that ever gets widened
+    #[test]
+    fn tx_ids_up_to_the_max() {
+        let max = TransactionId::MAX;
+        let state = process(
+            &format!("type,client,tx,amount\ndeposit,1,{max},1\ndispute,1,{max},\n"),
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(balances(&state, 1), (amount("0"), amount("1"), false));
+
+        let too_big = u128::from(max) + 1;
+        let err = process(
+            &format!("type,client,tx,amount\ndeposit,1,{too_big},1\n"),
+            &ProcessingConfig::default(),
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains(&format!(
+            "tx id {too_big} is out of range, it has to fit in {}",
+            std::any::type_name::<TransactionId>()
+        )));
+    }
+
+    fn deposit(tx: TransactionId, value: &str) -> Transaction {
+        Transaction::Deposit {
+            client: 1,
+            tx,
+            amount: amount(value),
+        }
+    }
+
+    fn dispute(tx: TransactionId) -> Transaction {
+        Transaction::Dispute { client: 1, tx }
+    }
+
+    // A client with a deposit of 10 in tx 1
+    fn deposited() -> (ClientState, HashMapStore) {
+        let mut client = ClientState::new();
+        let mut tx_database = HashMapStore::new();
+        apply_one(
+            &deposit(1, "10"),
+            &mut client,
+            &mut tx_database,
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        (client, tx_database)
+    }
+
+    #[test]
+    fn apply_one_deposit() {
+        let (client, tx_database) = deposited();
+        assert_eq!(
+            (client.available, client.held, client.locked),
+            (amount("10"), amount("0"), false)
+        );
+        assert_eq!(tx_database.query(1).unwrap().amount, amount("10"));
+    }
+
+    #[test]
+    fn apply_one_withdrawal() {
+        let (mut client, mut tx_database) = deposited();
+        let config = ProcessingConfig::default();
+        let withdrawal = |tx, value| Transaction::Withdrawal {
+            client: 1,
+            tx,
+            amount: amount(value),
+        };
+        assert_eq!(
+            apply_one(&withdrawal(2, "4"), &mut client, &mut tx_database, &config).unwrap(),
+            Applied::Withdrawal {
+                amount: amount("4")
+            }
+        );
+        assert_eq!(
+            apply_one(&withdrawal(3, "7"), &mut client, &mut tx_database, &config).unwrap(),
+            Applied::Overdraft
+        );
+        assert_eq!(client.available, amount("6"));
+    }
+
+    #[test]
+    fn apply_one_dispute() {
+        let (mut client, mut tx_database) = deposited();
+        let config = ProcessingConfig::default();
+        assert_eq!(
+            apply_one(&dispute(1), &mut client, &mut tx_database, &config).unwrap(),
+            Applied::Dispute { held: amount("10") }
+        );
+        assert_eq!((client.available, client.held), (amount("0"), amount("10")));
+        assert_eq!(
+            apply_one(&dispute(2), &mut client, &mut tx_database, &config).unwrap(),
+            Applied::Skipped(SkipReason::UnknownTransaction)
+        );
+    }
+
+    #[test]
+    fn apply_one_resolve() {
+        let (mut client, mut tx_database) = deposited();
+        let config = ProcessingConfig::default();
+        let resolve = Transaction::Resolve {
+            client: 1,
+            tx: 1,
+            amount: None,
+        };
+        assert_eq!(
+            apply_one(&resolve, &mut client, &mut tx_database, &config).unwrap(),
+            Applied::Skipped(SkipReason::NotUnderDispute)
+        );
+        apply_one(&dispute(1), &mut client, &mut tx_database, &config).unwrap();
+        assert_eq!(
+            apply_one(&resolve, &mut client, &mut tx_database, &config).unwrap(),
+            Applied::Resolve {
+                released: amount("10")
+            }
+        );
+        assert_eq!(
+            (client.available, client.held, client.locked),
+            (amount("10"), amount("0"), false)
+        );
+    }
+
+    #[test]
+    fn apply_one_chargeback() {
+        let (mut client, mut tx_database) = deposited();
+        let config = ProcessingConfig::default();
+        let chargeback = Transaction::Chargeback {
+            client: 1,
+            tx: 1,
+            amount: None,
+        };
+        apply_one(&dispute(1), &mut client, &mut tx_database, &config).unwrap();
+        assert_eq!(
+            apply_one(&chargeback, &mut client, &mut tx_database, &config).unwrap(),
+            Applied::Chargeback {
+                removed: amount("10")
+            }
+        );
+        assert_eq!(
+            (client.available, client.held, client.locked),
+            (amount("0"), amount("0"), true)
+        );
+    }
+
+    #[test]
+    fn apply_one_hold_and_release() {
+        let (mut client, mut tx_database) = deposited();
+        let config = ProcessingConfig {
+            allow_admin: true,
+            ..Default::default()
+        };
+        let hold = Transaction::Hold {
+            client: 1,
+            tx: 2,
+            amount: amount("3"),
+        };
+        let release = Transaction::Release {
+            client: 1,
+            tx: 3,
+            amount: amount("3"),
+        };
+        assert_eq!(
+            apply_one(&hold, &mut client, &mut tx_database, &config).unwrap(),
+            Applied::Hold {
+                amount: amount("3")
+            }
+        );
+        assert_eq!((client.available, client.held), (amount("7"), amount("3")));
+        assert_eq!(
+            apply_one(&release, &mut client, &mut tx_database, &config).unwrap(),
+            Applied::Release {
+                amount: amount("3")
+            }
+        );
+        assert_eq!((client.available, client.held), (amount("10"), amount("0")));
+    }
+
+    #[test]
+    fn apply_one_reversal() {
+        let (mut client, mut tx_database) = deposited();
+        let reversal = Transaction::Reversal {
+            client: 1,
+            tx: 1,
+            amount: None,
+        };
+        assert_eq!(
+            apply_one(
+                &reversal,
+                &mut client,
+                &mut tx_database,
+                &ProcessingConfig::default()
+            )
+            .unwrap(),
+            Applied::Reversal {
+                amount: amount("10")
+            }
+        );
+        assert_eq!(client.available, amount("0"));
+    }
+
+    #[test]
+    fn apply_one_commit() {
+        let (mut client, mut tx_database) = deposited();
+        assert_eq!(
+            apply_one(
+                &Transaction::Commit,
+                &mut client,
+                &mut tx_database,
+                &ProcessingConfig::default()
+            )
+            .unwrap(),
+            Applied::Skipped(SkipReason::NotAClient)
+        );
+    }
+
+    #[test]
+    fn max_amount() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,10\n\
+                   deposit,1,2,1000000000\n";
+        let config = ProcessingConfig {
+            max_amount: Some(amount("1000")),
+            quiet: true,
+            ..Default::default()
+        };
+        let state = process(csv, &config).unwrap();
+        assert_eq!(balances(&state, 1), (amount("10"), amount("0"), false));
+        let state = process(csv, &ProcessingConfig::default()).unwrap();
+        assert_eq!(
+            balances(&state, 1),
+            (amount("1000000010"), amount("0"), false)
+        );
+
+        // Just as much of a fat finger the other way around
+        let (mut client, mut tx_database) = deposited();
+        assert_eq!(
+            apply_one(
+                &deposit(2, "-1000000000"),
+                &mut client,
+                &mut tx_database,
+                &config
+            )
+            .unwrap(),
+            Applied::Skipped(SkipReason::OverMaxAmount)
+        );
+        let strict = ProcessingConfig {
+            max_amount: Some(amount("1000")),
+            strict: true,
+            ..Default::default()
+        };
+        let err = process(csv, &strict).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "tx 2 of 1000000000 for client 1, over the max amount of 1000"
+        );
+    }
+
+    // Client, available, held
+    #[derive(Default)]
+    struct VecSink {
+        records: Vec<(ClientId, Decimal, Decimal)>,
+        finished: bool,
+    }
+
+    impl OutputSink for VecSink {
+        fn emit(&mut self, record: &ClientRecord) -> Result<(), anyhow::Error> {
+            self.records
+                .push((record.client_id, record.available, record.held));
+            Ok(())
+        }
+        fn finish(&mut self) -> Result<(), anyhow::Error> {
+            self.finished = true;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn output_into_a_sink_of_our_own() {
+        let state = process(
+            "type,client,tx,amount\ndeposit,1,1,1\ndeposit,2,2,2\ndispute,2,2,\n",
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        let mut sink = VecSink::default();
+        write_output(&mut sink, [(None, &state)], &OutputConfig::default()).unwrap();
+        assert!(sink.finished);
+        assert_eq!(
+            sink.records,
+            [(1, amount("1"), amount("0")), (2, amount("0"), amount("2"))]
+        );
+    }
+
+    fn parse_all(csv: &str, input_config: &InputConfig) -> Vec<Result<Transaction, String>> {
+        let csv_reader = reader_builder(input_config).from_reader(csv.as_bytes());
+        deserialize_transactions(csv_reader, input_config)
+            .map(|transaction| transaction.map_err(|err| err.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn strip_currency() {
+        let input_config = InputConfig {
+            strip_currency: vec!["$".to_owned(), "USD".to_owned()],
+            ..Default::default()
+        };
+        let transactions = parse_all(
+            "type,client,tx,amount\ndeposit,1,1,$100.00\ndeposit,1,2,100.00 USD\ndeposit,1,3,100\n",
+            &input_config,
+        );
+        for (tx, transaction) in (1..).zip(transactions) {
+            assert_eq!(transaction, Ok(deposit(tx, "100")));
+        }
+        // Not without being asked to
+        let transactions = parse_all(
+            "type,client,tx,amount\ndeposit,1,1,$100.00\n",
+            &InputConfig::default(),
+        );
+        assert_eq!(
+            transactions,
+            [Err(
+                "amount \"$100.00\" isn't a number, on line 2".to_owned()
+            )]
+        );
+    }
+
+    #[test]
+    fn suspicious_clients_get_flagged() {
+        let mut csv = "type,client,tx,amount\n\
+                       deposit,1,1,10\n\
+                       dispute,1,1,\n\
+                       chargeback,1,1,\n\
+                       deposit,2,2,10\n"
+            .to_owned();
+        // Client 2 keeps disputing the same deposit, and 3 is perfectly normal
+        for _ in 0..SUSPICIOUS_DISPUTE_COUNT {
+            csv.push_str("dispute,2,2,\nresolve,2,2,\n");
+        }
+        csv.push_str("deposit,3,3,10\ndeposit,3,4,10\ndispute,3,3,\nresolve,3,3,\n");
+        let state = process(&csv, &ProcessingConfig::default()).unwrap();
+        let mut report = Vec::new();
+        state.report_suspicious(None, &mut report).unwrap();
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            "client 1: 1 of 1 deposits charged back\nclient 2: 5 disputes\n"
+        );
+    }
+
+    #[test]
+    fn pre_locked_client() {
+        let mut client = ClientState::with(amount("5"), amount("1"), true);
+        assert_eq!(
+            (client.available(), client.held(), client.locked()),
+            (amount("5"), amount("1"), true)
+        );
+        let config = ProcessingConfig {
+            error_on_locked_deposit: true,
+            ..Default::default()
+        };
+        let err = apply_one(
+            &deposit(1, "10"),
+            &mut client,
+            &mut HashMapStore::new(),
+            &config,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Deposit 1 for client 1, which is locked");
+        assert_eq!(client.available(), amount("5"));
+    }
+
+    #[test]
+    fn misspelled_header() {
+        // It comes first, so that's what the run errors out with
+        assert_eq!(
+            parse_all("type,client,tx,amt\ndeposit,1,1,1\n", &InputConfig::default())[0],
+            Err("Bad header row, missing columns: [amount], unexpected columns: [amt]. Expected exactly type, client, tx, amount".to_owned())
+        );
+        // Any order is fine though
+        assert_eq!(
+            parse_all(
+                "amount,tx,type,client\n1,2,deposit,1\n",
+                &InputConfig::default()
+            ),
+            [Ok(deposit(2, "1"))]
+        );
+    }
+
+    #[test]
+    fn precision_per_currency() {
+        let output_config = OutputConfig {
+            currency_precision: HashMap::from([("USD".to_owned(), 2), ("JPY".to_owned(), 0)]),
+            ..Default::default()
+        };
+        let rounded = |currency| {
+            amount("1234.5678")
+                .round_dp(output_config.precision_for(currency))
+                .to_string()
+        };
+        assert_eq!(rounded(Some("USD")), "1234.57");
+        assert_eq!(rounded(Some("JPY")), "1235");
+        // Anything else is on the usual precision
+        assert_eq!(rounded(Some("EUR")), "1234.5678");
+        assert_eq!(rounded(None), "1234.5678");
+    }
+
+    #[test]
+    fn disputes_get_resolved_independently() {
+        let mut state = new_state();
+        let config = ProcessingConfig::default();
+        process_into(
+            &mut state,
+            "type,client,tx,amount\n\
+             deposit,1,1,10\n\
+             deposit,1,2,2.5\n\
+             dispute,1,1,\n\
+             dispute,1,2,\n",
+            &config,
+        )
+        .unwrap();
+        assert_eq!(balances(&state, 1), (amount("0"), amount("12.5"), false));
+        process_into(&mut state, "type,client,tx,amount\nresolve,1,2,\n", &config).unwrap();
+        assert_eq!(balances(&state, 1), (amount("2.5"), amount("10"), false));
+        assert_eq!(client(&state, 1).txns_under_dispute[&1].held, amount("10"));
+        process_into(
+            &mut state,
+            "type,client,tx,amount\nchargeback,1,1,\n",
+            &config,
+        )
+        .unwrap();
+        assert_eq!(balances(&state, 1), (amount("2.5"), amount("0"), true));
+    }
+
+    #[test]
+    fn disputing_twice_only_holds_once() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,10\n\
+                   dispute,1,1,\n\
+                   dispute,1,1,\n\
+                   resolve,1,1,\n";
+        let config = ProcessingConfig {
+            quiet: true,
+            ..Default::default()
+        };
+        let state = process(csv, &config).unwrap();
+        assert_eq!(balances(&state, 1), (amount("10"), amount("0"), false));
+        assert_eq!(client(&state, 1).disputes, 1);
+        let strict = ProcessingConfig {
+            strict: true,
+            ..Default::default()
+        };
+        let err = process(csv, &strict).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "dispute of tx 1 for client 1, which is already under dispute"
+        );
+        // Once it's settled, it's fair game again
+        let csv = csv.replace("dispute,1,1,\nresolve", "resolve,1,1,\ndispute");
+        let state = process(&csv, &strict).unwrap();
+        assert_eq!(balances(&state, 1), (amount("0"), amount("10"), false));
+    }
+
+    #[test]
+    fn explain_traces_a_disputed_deposit() {
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let mut state = new_state();
+        let traced = lines.clone();
+        state.explain_with(Box::new(move |line| {
+            traced.borrow_mut().push(line.to_string())
+        }));
+        let config = ProcessingConfig {
+            explain: Some(1),
+            ..Default::default()
+        };
+        process_into(
+            &mut state,
+            "type,client,tx,amount\n\
+             deposit,1,1,10\n\
+             deposit,1,2,5\n\
+             dispute,1,1,\n\
+             chargeback,1,1,\n",
+            &config,
+        )
+        .unwrap();
+        let lines = lines.borrow();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("explain: Deposit"));
+        assert!(lines[0].ends_with("available 0 -> 10, held 0 -> 0, locked false -> false"));
+        assert!(lines[1].starts_with("explain: Dispute"));
+        assert!(lines[1].ends_with("available 15 -> 5, held 0 -> 10, locked false -> false"));
+        assert!(lines[2].starts_with("explain: Chargeback"));
+        assert!(lines[2].ends_with("available 5 -> 5, held 10 -> 0, locked false -> true"));
+
+        // A chargeback that waited for its dispute is traced once it actually goes through
+        let traced_lines = |csv: &str, config: ProcessingConfig| {
+            let lines = Rc::new(RefCell::new(Vec::new()));
+            let mut state = new_state();
+            let traced = lines.clone();
+            state.explain_with(Box::new(move |line| {
+                traced.borrow_mut().push(line.to_string())
+            }));
+            process_into(&mut state, csv, &config).unwrap();
+            lines.take()
+        };
+        let lines = traced_lines(
+            "type,client,tx,amount
+deposit,1,1,10
+chargeback,1,1,
+dispute,1,1,
+",
+            ProcessingConfig {
+                explain: Some(1),
+                buffer_ooo: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(lines.len(), 4);
+        assert!(lines[1].starts_with("explain: Chargeback"));
+        assert!(lines[1].contains("-> Buffered"));
+        assert!(lines[2].starts_with("explain: Dispute"));
+        assert!(lines[3].starts_with("explain: Chargeback"));
+        assert!(lines[3].ends_with("available 0 -> 0, held 10 -> 0, locked false -> true"));
+        // And so is a resolve that --auto-resolve-after made up
+        let lines = traced_lines(
+            "type,client,tx,amount
+deposit,1,1,10
+dispute,1,1,
+deposit,1,2,5
+",
+            ProcessingConfig {
+                explain: Some(1),
+                auto_resolve_after: Some(1),
+                quiet: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(lines.len(), 3);
+        assert!(lines[2].starts_with("explain: Resolve"));
+        assert!(lines[2].ends_with("available 5 -> 15, held 10 -> 0, locked false -> false"));
+    }
+
+    #[test]
+    fn rounding_only_happens_on_the_way_out() {
+        let mut csv = String::from("type,client,tx,amount\n");
+        for tx in 1..=1000 {
+            csv.push_str(&format!("deposit,1,{tx},0.00015\n"));
+        }
+        csv.push_str("withdrawal,1,1001,0.00003\n");
+        let state = process(&csv, &ProcessingConfig::default()).unwrap();
+        // Rounding every deposit to four places on the way in would have made this 0.2
+        assert_eq!(balances(&state, 1), (amount("0.14997"), amount("0"), false));
+        assert_eq!(
+            output(&state, &OutputConfig::default()),
+            "client,available,held,total,locked\n1,0.1500,0.0000,0.1500,false\n"
+        );
+    }
+
+    #[test]
+    fn buffer_ooo_settles_once_the_dispute_shows_up() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,10\n\
+                   deposit,1,2,5\n\
+                   resolve,1,1,\n\
+                   chargeback,1,2,\n\
+                   dispute,1,1,\n\
+                   dispute,1,2,\n";
+        let config = ProcessingConfig {
+            buffer_ooo: true,
+            ..Default::default()
+        };
+        let state = process(csv, &config).unwrap();
+        assert_eq!(balances(&state, 1), (amount("10"), amount("0"), true));
+        assert!(client(&state, 1).early_settlements.is_empty());
+        // Without it, both get dropped and the disputes stay open
+        let config = ProcessingConfig {
+            quiet: true,
+            ..Default::default()
+        };
+        let state = process(csv, &config).unwrap();
+        assert_eq!(balances(&state, 1), (amount("0"), amount("15"), false));
+    }
+
+    #[test]
+    fn tx_database_dump() {
+        let state = process(
+            "type,client,tx,amount\n\
+             deposit,1,1,10\n\
+             deposit,1,2,5\n\
+             withdrawal,1,3,1\n\
+             deposit,2,4,1\n\
+             dispute,1,1,\n\
+             resolve,1,1,\n\
+             dispute,1,2,\n\
+             chargeback,1,2,\n\
+             dispute,2,4,\n",
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        let mut dump = Vec::new();
+        state.dump_tx_database(&mut dump).unwrap();
+        assert_eq!(
+            String::from_utf8(dump).unwrap(),
+            "type,tx,amount,status\n\
+             deposit,1,10,resolved\n\
+             deposit,2,5,chargeback\n\
+             withdrawal,3,1,undisputed\n\
+             deposit,4,1,disputed\n"
+        );
+    }
+
+    #[test]
+    fn negative_total_policies() {
+        // The withdrawal gets disputed and then charged back, on top of having already left
+        let state = process(
+            "type,client,tx,amount\n\
+             deposit,1,1,10\n\
+             withdrawal,1,2,10\n\
+             dispute,1,2,\n\
+             chargeback,1,2,\n",
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        let total = |negative_total| {
+            let output_config = OutputConfig {
+                negative_total,
+                ..Default::default()
+            };
+            state
+                .client_record(1, &output_config)
+                .unwrap()
+                .map(|record| record.total)
+        };
+        assert_eq!(total(NegativeTotal::Allow).unwrap(), amount("-10.0000"));
+        assert_eq!(total(NegativeTotal::Clamp).unwrap(), amount("0.0000"));
+        assert_eq!(
+            total(NegativeTotal::Error).err().unwrap().to_string(),
+            "Client 1 has a negative total of -10"
+        );
+    }
+
+    #[test]
+    fn disabled_chargebacks_dont_lock() {
+        let config = ProcessingConfig {
+            disabled: HashSet::from([TransactionType::Chargeback]),
+            quiet: true,
+            ..Default::default()
+        };
+        let state = process(
+            "type,client,tx,amount\n\
+             deposit,1,1,10\n\
+             dispute,1,1,\n\
+             chargeback,1,1,\n",
+            &config,
+        )
+        .unwrap();
+        assert_eq!(balances(&state, 1), (amount("0"), amount("10"), false));
+    }
+
+    #[test]
+    fn lenient_amount_skips_garbage() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,10\n\
+                   deposit,1,2,abc\n\
+                   dispute,1,1,\n";
+        assert!(process(csv, &ProcessingConfig::default()).is_err());
+        let config = ProcessingConfig {
+            lenient_amount: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let state = process(csv, &config).unwrap();
+        assert_eq!(balances(&state, 1), (amount("0"), amount("10"), false));
+    }
+
+    #[test]
+    fn disputes_against_a_loaded_tx_database() {
+        let earlier = process(
+            "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,5\n",
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        let mut dump = Vec::new();
+        earlier.dump_tx_database(&mut dump).unwrap();
+
+        let mut state = new_state();
+        state.load_tx_database(dump.as_slice()).unwrap();
+        process_into(
+            &mut state,
+            "type,client,tx,amount\ndispute,1,1,\ndispute,1,2,\nresolve,1,2,\n",
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        // The balances were never carried over, only what the disputes can point at
+        assert_eq!(balances(&state, 1), (amount("-10"), amount("10"), false));
+        assert!(matches!(
+            state.tx_database.query(2).unwrap().dispute_status,
+            DisputeStatus::Resolved
+        ));
+    }
+
+    #[test]
+    fn strict_schema() {
+        let csv = "type,client,tx,amount\n\
+                   dispute,1,1,5\n\
+                   deposit,1,2,\n";
+        let lenient = parse_all(csv, &InputConfig::default());
+        assert!(matches!(lenient[0], Ok(Transaction::Dispute { tx: 1, .. })));
+        assert_eq!(lenient[1], Err("Deposit 2 has no amount".to_owned()));
+        let strict_schema = InputConfig {
+            strict_schema: true,
+            ..Default::default()
+        };
+        let strict = parse_all(csv, &strict_schema);
+        assert_eq!(
+            strict[0],
+            Err("Invalid record: Dispute 1 should not have an amount".to_owned())
+        );
+        assert!(strict[1].is_err());
+    }
+
+    #[test]
+    fn sort_by_total() {
+        let state = process(
+            "type,client,tx,amount\n\
+             deposit,2,1,1\n\
+             deposit,3,2,10\n\
+             deposit,1,3,5\n",
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        let clients = |sort_by| {
+            let output_config = OutputConfig {
+                sort_by,
+                headers: false,
+                ..Default::default()
+            };
+            output(&state, &output_config)
+                .lines()
+                .map(|line| line.split(',').next().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(clients(SortBy::Insertion), ["2", "3", "1"]);
+        assert_eq!(clients(SortBy::TotalDesc), ["3", "1", "2"]);
+        assert_eq!(clients(SortBy::TotalAsc), ["2", "1", "3"]);
+        assert_eq!(clients(SortBy::ClientAsc), ["1", "2", "3"]);
+    }
+
+    #[test]
+    fn disputes_auto_resolve() {
+        let config = ProcessingConfig {
+            auto_resolve_after: Some(2),
+            quiet: true,
+            ..Default::default()
+        };
+        let mut state = new_state();
+        process_into(
+            &mut state,
+            "type,client,tx,amount\ndeposit,1,1,10\ndispute,1,1,\ndeposit,2,2,1\n",
+            &config,
+        )
+        .unwrap();
+        assert_eq!(balances(&state, 1), (amount("0"), amount("10"), false));
+        process_into(
+            &mut state,
+            "type,client,tx,amount\ndeposit,2,3,1\n",
+            &config,
+        )
+        .unwrap();
+        assert_eq!(balances(&state, 1), (amount("10"), amount("0"), false));
+        assert!(client(&state, 1).txns_under_dispute.is_empty());
+    }
+
+    #[test]
+    fn crlf_output() {
+        let state = process(
+            "type,client,tx,amount\ndeposit,1,1,1\n",
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        let output_config = OutputConfig {
+            terminator: csv::Terminator::CRLF,
+            ..Default::default()
+        };
+        assert_eq!(
+            output(&state, &output_config),
+            "client,available,held,total,locked\r\n1,1.0000,0.0000,1.0000,false\r\n"
+        );
+    }
+
+    #[test]
+    fn house_row_sums_everyone() {
+        let state = process(
+            "type,client,tx,amount\n\
+             deposit,1,1,10\n\
+             deposit,2,2,2.5\n\
+             dispute,2,2,\n",
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        let output_config = OutputConfig {
+            with_house_row: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            output(&state, &output_config),
+            "client,available,held,total,locked\n\
+             1,10.0000,0.0000,10.0000,false\n\
+             2,0.0000,2.5000,2.5000,false\n\
+             65535,10.0000,2.5000,12.5000,false\n"
+        );
+        let output_config = OutputConfig {
+            house_id: 2,
+            ..output_config
+        };
+        assert_eq!(
+            output_records([(None, &state)], &output_config)
+                .err()
+                .unwrap()
+                .to_string(),
+            "The house row's id 2 is taken by a real client, pick another with --house-id"
+        );
+    }
+
+    #[test]
+    fn skip_blank_type() {
+        let csv = "type,client,tx,amount\n  ,1,1,10\ndeposit,1,2,1\n";
+        let transactions = parse_all(csv, &InputConfig::default());
+        assert_eq!(transactions.len(), 2);
+        assert!(transactions[0].is_err());
+        let input_config = InputConfig {
+            skip_blank_type: true,
+            ..Default::default()
+        };
+        let transactions = parse_all(csv, &input_config);
+        assert_eq!(transactions.len(), 1);
+        assert!(matches!(
+            transactions[0],
+            Ok(Transaction::Deposit { tx: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn idle_clients_get_finalized_early() {
+        let sink = Rc::new(RefCell::new(VecSink::default()));
+        let mut state = new_state();
+        state.finalize_into(Box::new(sink.clone()), OutputConfig::default());
+        let config = ProcessingConfig {
+            finalize_after: Some(2),
+            ..Default::default()
+        };
+        process_into(
+            &mut state,
+            "type,client,tx,amount\n\
+             deposit,1,1,10\n\
+             deposit,2,2,1\n\
+             deposit,2,3,1\n\
+             deposit,2,4,1\n",
+            &config,
+        )
+        .unwrap();
+        // Out before the stream is over, and gone from the state
+        assert_eq!(sink.borrow().records, [(1, amount("10"), amount("0"))]);
+        assert!(state.client_states.get(1).is_none());
+        assert_eq!(state.finalized_count(), 1);
+        assert_eq!(
+            output(&state, &OutputConfig::default()),
+            "client,available,held,total,locked\n2,3.0000,0.0000,3.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn tx_conflict_policies() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,10\n\
+                   deposit,1,1,3\n\
+                   dispute,1,1,\n";
+        let with = |tx_conflict| {
+            let config = ProcessingConfig {
+                tx_conflict,
+                ..Default::default()
+            };
+            process(csv, &config)
+        };
+        let state = with(TxConflict::First).unwrap();
+        assert_eq!(balances(&state, 1), (amount("3"), amount("10"), false));
+        let state = with(TxConflict::Last).unwrap();
+        assert_eq!(balances(&state, 1), (amount("10"), amount("3"), false));
+        assert_eq!(
+            with(TxConflict::Error).err().unwrap().to_string(),
+            "tx id 1 is used more than once"
+        );
+    }
+
+    #[test]
+    fn overflow_leaves_everything_as_it_was() {
+        let strict = ProcessingConfig {
+            strict: true,
+            ..Default::default()
+        };
+        let (mut client, mut tx_database) = deposited();
+        apply_one(&dispute(1), &mut client, &mut tx_database, &strict).unwrap();
+        client.available = Decimal::MAX;
+
+        let overflowing = deposit(2, "1");
+        assert!(apply_one(&overflowing, &mut client, &mut tx_database, &strict).is_err());
+        assert!(tx_database.query(2).is_none());
+        let resolve = Transaction::Resolve {
+            client: 1,
+            tx: 1,
+            amount: None,
+        };
+        assert!(apply_one(&resolve, &mut client, &mut tx_database, &strict).is_err());
+        assert_eq!(
+            (client.available, client.held, client.deposits),
+            (Decimal::MAX, amount("10"), 1)
+        );
+        assert!(client.txns_under_dispute.contains_key(&1));
+        assert!(matches!(
+            tx_database.query(1).unwrap().dispute_status,
+            DisputeStatus::Disputed
+        ));
+        // Same without --strict, only it's a skip instead
+        let lenient = ProcessingConfig {
+            quiet: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            apply_one(&resolve, &mut client, &mut tx_database, &lenient).unwrap(),
+            Applied::Skipped(SkipReason::Overflow)
+        );
+        assert_eq!(client.held, amount("10"));
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        // In a row that's only a comment, with the type left blank
+        let csv = b"type,client,tx,amount\n,caf\xe9,,\ndeposit,1,1,10\n";
+        let parse = |input_config: &InputConfig| {
+            let csv_reader = reader_builder(input_config).from_reader(&csv[..]);
+            deserialize_transactions(csv_reader, input_config)
+                .map(|transaction| transaction.map_err(|err| err.to_string()))
+                .collect::<Vec<_>>()
+        };
+        let input_config = InputConfig {
+            skip_blank_type: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            parse(&input_config)[0],
+            Err("invalid UTF-8 at byte 26 (line 2, field 2)".to_owned())
+        );
+        let input_config = InputConfig {
+            lossy_utf8: true,
+            ..input_config
+        };
+        assert!(matches!(
+            parse(&input_config)[..],
+            [Ok(Transaction::Deposit { tx: 1, .. })]
+        ));
+    }
+
+    #[test]
+    fn oversized_rows_get_stopped() {
+        use std::io::Read;
+
+        let csv = format!(
+            "type,client,tx,amount\ndeposit,1,1,1\ndeposit,1,2,{}\n",
+            "9".repeat(64)
+        );
+        let mut reader = RowLimitReader::new(csv.as_bytes(), 32);
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Line 3 is longer than the max row size of 32 bytes"
+        );
+        // Same when it comes in bits smaller than the limit
+        let mut reader = RowLimitReader::new(csv.as_bytes(), 32);
+        let mut buffer = [0; 8];
+        let err = loop {
+            match reader.read(&mut buffer) {
+                Ok(0) => panic!("Got through the whole thing"),
+                Ok(_) => {}
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(
+            err.to_string(),
+            "Line 3 is longer than the max row size of 32 bytes"
+        );
+        // Anything under the limit is fine
+        let mut reader = RowLimitReader::new(csv.as_bytes(), 80);
+        assert!(reader.read_to_end(&mut Vec::new()).is_ok());
+    }
+
+    #[test]
+    fn transaction_types_round_trip() {
+        for (index, transaction_type) in TransactionType::ALL.iter().enumerate() {
+            let json = format!("\"{}\"", transaction_type.as_str());
+            let parsed: TransactionType = serde_json::from_str(&json).unwrap();
+            assert_eq!(parsed, *transaction_type);
+            // The stats index by it
+            assert_eq!(*transaction_type as usize, index);
+        }
+    }
+
+    #[test]
+    fn reversal_takes_a_deposit_back() {
+        let strict_schema = InputConfig {
+            strict_schema: true,
+            ..Default::default()
+        };
+        let strict = ProcessingConfig {
+            strict: true,
+            ..Default::default()
+        };
+        let mut state = new_state();
+        process_with(
+            &mut state,
+            "type,client,tx,amount\n\
+             deposit,1,1,5\n\
+             deposit,1,2,10\n\
+             reversal,1,2,-10\n",
+            &strict_schema,
+            &strict,
+        )
+        .unwrap();
+        assert_eq!(balances(&state, 1), (amount("5"), amount("0"), false));
+        assert!(matches!(
+            state.tx_database.query(2).unwrap().dispute_status,
+            DisputeStatus::Reversed
+        ));
+        // It's gone, so it can't be reversed twice
+        let err = process_with(
+            &mut state,
+            "type,client,tx,amount\nreversal,1,2,\n",
+            &strict_schema,
+            &strict,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "reversal of tx 2, which is a Deposit that's Reversed"
+        );
+    }
+
+    // Fails the first `failures` reads, then hands out the bytes
+    struct FlakyReader {
+        failures: u32,
+        inner: &'static [u8],
+    }
+
+    impl io::Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "flaky"));
+            }
+            self.inner.read(buf)
+        }
+    }
+
+    #[test]
+    fn reads_get_retried() {
+        use std::io::Read;
+
+        let csv = "type,client,tx,amount\ndeposit,1,1,10\n";
+        let flaky = |failures| FlakyReader {
+            failures,
+            inner: csv.as_bytes(),
+        };
+        let mut read = String::new();
+        RetryReader::new(flaky(1), 2, true)
+            .read_to_string(&mut read)
+            .unwrap();
+        assert_eq!(read, csv);
+        // Out of retries
+        let err = RetryReader::new(flaky(3), 2, true)
+            .read_to_string(&mut String::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        // And the whole run goes through
+        let mut state = new_state();
+        let input_config = InputConfig::default();
+        let csv_reader =
+            reader_builder(&input_config).from_reader(RetryReader::new(flaky(1), 1, true));
+        handle_transactions(
+            deserialize_transactions(csv_reader, &input_config),
+            &mut state,
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(balances(&state, 1), (amount("10"), amount("0"), false));
+    }
+
+    #[test]
+    fn every_amount_gets_the_same_scale() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,5\n\
+                   deposit,1,2,0.1234\n\
+                   dispute,1,2,\n\
+                   deposit,2,3,1.23456\n\
+                   deposit,2,4,0.00005\n";
+        let state = process(csv, &ProcessingConfig::default()).unwrap();
+        // Padded out when there's fewer places, rounded half to even when there's more
+        assert_eq!(
+            output(&state, &OutputConfig::default()),
+            "client,available,held,total,locked\n\
+             1,5.0000,0.1234,5.1234,false\n\
+             2,1.2346,0.0000,1.2346,false\n"
+        );
+        let state = process(
+            "type,client,tx,amount\ndeposit,1,1,0.00005\ndeposit,2,2,0.00015\n",
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        let output_config = OutputConfig {
+            precision: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            output(&state, &OutputConfig::default()),
+            "client,available,held,total,locked\n\
+             1,0.0000,0.0000,0.0000,false\n\
+             2,0.0002,0.0000,0.0002,false\n"
+        );
+        assert!(output(&state, &output_config).ends_with("2,0.00,0.00,0.00,false\n"));
+    }
+
+    #[test]
+    fn orphan_resolves() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,10\n\
+                   resolve,1,1,\n\
+                   resolve,1,2,\n\
+                   dispute,1,1,\n\
+                   resolve,1,1,\n";
+        let state = process(csv, &ProcessingConfig::default()).unwrap();
+        assert_eq!(state.stats.orphan_resolves, 2);
+        let warn = ProcessingConfig {
+            warn_orphan_resolve: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let state = process(csv, &warn).unwrap();
+        assert_eq!(state.stats.orphan_resolves, 2);
+        assert_eq!(balances(&state, 1), (amount("10"), amount("0"), false));
+        let strict = ProcessingConfig {
+            strict: true,
+            ..warn
+        };
+        assert_eq!(
+            process(csv, &strict).err().unwrap().to_string(),
+            "resolve of tx 1 for client 1, which isn't under dispute"
+        );
+        // Only with the warning turned on, an orphan resolve is otherwise fine even with --strict
+        let strict = ProcessingConfig {
+            strict: true,
+            ..Default::default()
+        };
+        assert!(process(csv, &strict).is_ok());
+    }
+
+    #[test]
+    fn unbalanced_clients_get_listed() {
+        let state = process(
+            "type,client,tx,amount\n\
+             deposit,1,1,10\n\
+             withdrawal,1,2,10\n\
+             dispute,1,2,\n\
+             deposit,2,3,1\n",
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            state.unbalanced_clients(None),
+            ["client 1: available -10, held 10"]
+        );
+        assert_eq!(
+            state.unbalanced_clients(Some("acme")),
+            ["client acme/1: available -10, held 10"]
+        );
+    }
+
+    #[test]
+    fn bool_styles() {
+        let state = process(
+            "type,client,tx,amount\n\
+             deposit,1,1,1\n\
+             dispute,1,1,\n\
+             chargeback,1,1,\n\
+             deposit,2,2,1\n",
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        let locked = |bool_style| {
+            let output_config = OutputConfig {
+                bool_style,
+                headers: false,
+                ..Default::default()
+            };
+            output(&state, &output_config)
+                .lines()
+                .map(|line| line.rsplit(',').next().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(locked(BoolStyle::TrueFalse), ["true", "false"]);
+        assert_eq!(locked(BoolStyle::OneZero), ["1", "0"]);
+        assert_eq!(locked(BoolStyle::YesNo), ["yes", "no"]);
+    }
+
+    #[test]
+    fn dispute_policies() {
+        let run = |dispute_policy: DisputePolicy, csv: &str| {
+            let config = ProcessingConfig {
+                dispute_policy,
+                ..Default::default()
+            };
+            let state = process(&format!("type,client,tx,amount\n{csv}"), &config).unwrap();
+            balances(&state, 1)
+        };
+        let withdrawn = "deposit,1,1,10\nwithdrawal,1,2,4\ndispute,1,2,\n";
+        // The same for deposits either way
+        for policy in [DisputePolicy::SPEC_LITERAL, DisputePolicy::REALISTIC] {
+            let disputed = "deposit,1,1,10\ndispute,1,1,\n";
+            assert_eq!(run(policy, disputed), (amount("0"), amount("10"), false));
+            assert_eq!(
+                run(policy, &format!("{disputed}resolve,1,1,\n")),
+                (amount("10"), amount("0"), false)
+            );
+            assert_eq!(
+                run(policy, &format!("{disputed}chargeback,1,1,\n")),
+                (amount("0"), amount("0"), true)
+            );
+        }
+        let spec = DisputePolicy::SPEC_LITERAL;
+        assert_eq!(run(spec, withdrawn), (amount("2"), amount("4"), false));
+        assert_eq!(
+            run(spec, &format!("{withdrawn}resolve,1,2,\n")),
+            (amount("6"), amount("0"), false)
+        );
+        assert_eq!(
+            run(spec, &format!("{withdrawn}chargeback,1,2,\n")),
+            (amount("2"), amount("0"), true)
+        );
+        let realistic = DisputePolicy::REALISTIC;
+        assert_eq!(run(realistic, withdrawn), (amount("6"), amount("4"), false));
+        assert_eq!(
+            run(realistic, &format!("{withdrawn}resolve,1,2,\n")),
+            (amount("6"), amount("0"), false)
+        );
+        assert_eq!(
+            run(realistic, &format!("{withdrawn}chargeback,1,2,\n")),
+            (amount("10"), amount("0"), true)
+        );
+    }
+
+    // Keeps a log of what it got asked, and hands the rest to a real store
+    #[derive(Default)]
+    struct MockStore {
+        inner: HashMapStore,
+        calls: Vec<String>,
+    }
+
+    impl TxStore for MockStore {
+        fn query(&self, tx_id: TransactionId) -> Option<&StoredTransaction> {
+            self.inner.query(tx_id)
+        }
+        fn save(
+            &mut self,
+            transaction: StoredTransaction,
+            tx_conflict: TxConflict,
+        ) -> Result<(), anyhow::Error> {
+            self.calls.push(format!(
+                "save {} {}",
+                transaction.transaction_id, transaction.amount
+            ));
+            self.inner.save(transaction, tx_conflict)
+        }
+        fn set_dispute_status(&mut self, tx_id: TransactionId, dispute_status: DisputeStatus) {
+            self.calls
+                .push(format!("status {tx_id} {dispute_status:?}"));
+            self.inner.set_dispute_status(tx_id, dispute_status);
+        }
+        fn transactions(&self) -> Box<dyn Iterator<Item = &StoredTransaction> + '_> {
+            self.inner.transactions()
+        }
+    }
+
+    #[test]
+    fn engine_over_a_store_of_our_own() {
+        let mut state = EngineState::with_store(Arc::default(), MockStore::default());
+        let input_config = InputConfig::default();
+        let csv_reader = reader_builder(&input_config).from_reader(
+            "type,client,tx,amount\n\
+             deposit,1,1,10\n\
+             withdrawal,1,2,3\n\
+             withdrawal,1,3,100\n\
+             dispute,1,1,\n\
+             dispute,1,9,\n\
+             chargeback,1,1,\n"
+                .as_bytes(),
+        );
+        handle_transactions(
+            deserialize_transactions(csv_reader, &input_config),
+            &mut state,
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        // The overdraft still gets stored, it's a withdrawal that happened, just not one that went through
+        assert_eq!(
+            state.tx_database.calls,
+            [
+                "save 1 10",
+                "save 2 3",
+                "save 3 100",
+                "status 1 Disputed",
+                "status 1 ChargedBack",
+            ]
+        );
+    }
+
+    #[test]
+    fn amounts_get_normalized() {
+        let csv = "type,client,tx,amount\ndeposit,1,1,1.23456\ndeposit,1,2,1.5\n";
+        let config = ProcessingConfig {
+            normalize_scale: Some(4),
+            quiet: true,
+            ..Default::default()
+        };
+        let state = process(csv, &config).unwrap();
+        assert_eq!(client(&state, 1).available.to_string(), "2.7346");
+        assert_eq!(
+            state.tx_database.query(1).unwrap().amount.to_string(),
+            "1.2346"
+        );
+        // Padded out too, not just rounded
+        assert_eq!(
+            state.tx_database.query(2).unwrap().amount.to_string(),
+            "1.5000"
+        );
+        let strict = ProcessingConfig {
+            strict: true,
+            ..config
+        };
+        assert_eq!(
+            process(csv, &strict).err().unwrap().to_string(),
+            "tx 1 has an amount of 1.23456, which has more than 4 decimal places"
+        );
+    }
+
+    #[test]
+    fn trim_modes() {
+        let csv = "type,client,tx,amount\ndeposit, 1, 1, 2.5\n";
+        assert!(matches!(
+            parse_all(csv, &InputConfig::default())[..],
+            [Ok(Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                ..
+            })]
+        ));
+        let untrimmed = InputConfig {
+            trim: csv::Trim::None,
+            ..Default::default()
+        };
+        assert!(parse_all(csv, &untrimmed)[0].is_err());
+        // Only the headers is just as bad for the values
+        let headers_only = InputConfig {
+            trim: csv::Trim::Headers,
+            ..Default::default()
+        };
+        assert!(parse_all(csv, &headers_only)[0].is_err());
+    }
+
+    #[test]
+    fn no_input_panics() {
+        let types = [
+            "deposit",
+            "withdrawal",
+            "dispute",
+            "resolve",
+            "chargeback",
+            "hold",
+            "release",
+            "reversal",
+            "commit",
+            "",
+            "bogus",
+        ];
+        let ids = ["1", "", "-1", "x", "4294967296", "99999999999999999999"];
+        let amounts = [
+            "",
+            "0",
+            "-1",
+            "abc",
+            "1e3",
+            "79228162514264337593543950335",
+            "-79228162514264337593543950335",
+            "0.0000000000000000000000000001",
+            "1.5",
+        ];
+        let configs = [
+            ProcessingConfig {
+                quiet: true,
+                allow_admin: true,
+                ..Default::default()
+            },
+            ProcessingConfig {
+                strict: true,
+                lenient_amount: true,
+                auto_resolve_after: Some(1),
+                buffer_ooo: true,
+                normalize_scale: Some(2),
+                ..Default::default()
+            },
+        ];
+        // Every row against a state that already has something to dispute and overflow
+        for transaction_type in types {
+            for id in ids {
+                for value in amounts {
+                    let csv = format!(
+                        "type,client,tx,amount\n\
+                         deposit,1,1,79228162514264337593543950335\n\
+                         deposit,1,2,1\n\
+                         dispute,1,2,\n\
+                         {transaction_type},{id},{id},{value}\n\
+                         {transaction_type},1,2,{value}\n\
+                         withdrawal,1,3,1\n"
+                    );
+                    for config in &configs {
+                        let _ = process(&csv, config);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn amounts_have_to_be_above_zero() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,10\n\
+                   deposit,1,2,-5\n\
+                   withdrawal,1,3,-5\n\
+                   withdrawal,1,4,0\n";
+        let config = ProcessingConfig {
+            quiet: true,
+            ..Default::default()
+        };
+        let state = process(csv, &config).unwrap();
+        assert_eq!(balances(&state, 1), (amount("10"), amount("0"), false));
+        assert!(state.tx_database.query(2).is_none());
+        let strict = ProcessingConfig {
+            strict: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            process(csv, &strict).err().unwrap().to_string(),
+            "Deposit 2 has an amount of -5, which isn't above zero"
+        );
+    }
+
+    #[test]
+    fn tx_ids_column() {
+        let config = ProcessingConfig {
+            track_tx_ids: true,
+            ..Default::default()
+        };
+        let state = process(
+            "type,client,tx,amount\n\
+             deposit,1,3,1\n\
+             deposit,2,4,1\n\
+             deposit,1,7,2\n\
+             dispute,1,3,\n",
+            &config,
+        )
+        .unwrap();
+        let output_config = OutputConfig {
+            with_tx_ids: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            output(&state, &output_config),
+            "client,available,held,total,locked,tx_ids\n\
+             1,2.0000,1.0000,3.0000,false,3;7\n\
+             2,1.0000,0.0000,1.0000,false,4\n"
+        );
+    }
+
+    #[test]
+    fn recovering_from_a_garbage_line() {
+        let csv = b"type,client,tx,amount\n\
+                    deposit,1,1,10\n\
+                    \"\xff\x00\x13garbage,,\n\
+                    deposit,1,2,5\n\
+                    withdrawal,1,3,1\n";
+        let config = ProcessingConfig {
+            quiet: true,
+            ..Default::default()
+        };
+        let mut state = new_state();
+        handle_transactions(
+            deserialize_lines(&csv[..], &InputConfig::default()),
+            &mut state,
+            &config,
+        )
+        .unwrap();
+        assert_eq!(balances(&state, 1), (amount("14"), amount("0"), false));
+        // One reader over the whole thing takes everything after the stray quote with it
+        let mut state = new_state();
+        let input_config = InputConfig {
+            lossy_utf8: true,
+            ..Default::default()
+        };
+        let csv_reader = reader_builder(&input_config).from_reader(&csv[..]);
+        let _ = handle_transactions(
+            deserialize_transactions(csv_reader, &input_config),
+            &mut state,
+            &config,
+        );
+        assert_eq!(balances(&state, 1), (amount("10"), amount("0"), false));
+        // And with --strict, the line is where it stops
+        let strict = ProcessingConfig {
+            strict: true,
+            ..Default::default()
+        };
+        let err = handle_transactions(
+            deserialize_lines(&csv[..], &InputConfig::default()),
+            &mut new_state(),
+            &strict,
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().starts_with("line 3 is broken"), "{err}");
+    }
+
+    #[test]
+    fn withdrawing_against_total() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,10\n\
+                   deposit,1,2,5\n\
+                   dispute,1,2,\n\
+                   withdrawal,1,3,12\n";
+        let state = process(csv, &ProcessingConfig::default()).unwrap();
+        assert_eq!(balances(&state, 1), (amount("10"), amount("5"), false));
+        assert_eq!(client(&state, 1).overdraft_attempts, 1);
+        let config = ProcessingConfig {
+            withdraw_against: WithdrawAgainst::Total,
+            ..Default::default()
+        };
+        let state = process(csv, &config).unwrap();
+        assert_eq!(balances(&state, 1), (amount("-2"), amount("5"), false));
+        assert_eq!(client(&state, 1).overdraft_attempts, 0);
+
+        // Held and available that don't add up to anything a Decimal holds is an overflow, and nothing gets stored
+        let strict = ProcessingConfig {
+            strict: true,
+            ..config
+        };
+        let (mut client, mut tx_database) = deposited();
+        client.held = Decimal::MAX;
+        let withdrawal = Transaction::Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: amount("1"),
+        };
+        assert!(apply_one(&withdrawal, &mut client, &mut tx_database, &strict).is_err());
+        assert!(tx_database.query(2).is_none());
+        assert_eq!(client.available, amount("10"));
+    }
+
+    #[test]
+    fn sparse_client_ids() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,4000000000,1,1\n\
+                   deposit,70000,2,2\n\
+                   deposit,3,3,3\n\
+                   withdrawal,4000000000,4,0.5\n";
+        let run = |storage| {
+            let mut state = new_state();
+            state.store_clients_in(storage).unwrap();
+            process_into(&mut state, csv, &ProcessingConfig::default()).unwrap();
+            output(&state, &OutputConfig::default())
+        };
+        assert_eq!(
+            run(ClientStorage::BTree),
+            "client,available,held,total,locked\n\
+             3,3.0000,0.0000,3.0000,false\n\
+             70000,2.0000,0.0000,2.0000,false\n\
+             4000000000,0.5000,0.0000,0.5000,false\n"
+        );
+        assert_eq!(
+            run(ClientStorage::HashMap),
+            "client,available,held,total,locked\n\
+             4000000000,0.5000,0.0000,0.5000,false\n\
+             70000,2.0000,0.0000,2.0000,false\n\
+             3,3.0000,0.0000,3.0000,false\n"
+        );
+        let mut state = new_state();
+        let err = process_into(&mut state, csv, &ProcessingConfig::default())
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Client 4000000000 doesn't fit in the array, that needs --storage hashmap or btree"
+        );
+    }
+
+    #[test]
+    fn reordered_columns() {
+        // With a header row, any order goes
+        let transactions = parse_all(
+            "amount,tx,type,client\n2.5,7,deposit,1\n",
+            &InputConfig::default(),
+        );
+        assert!(matches!(
+            transactions[..],
+            [Ok(Transaction::Deposit {
+                client: 1,
+                tx: 7,
+                ..
+            })]
+        ));
+        // Without one, the order has to be given
+        let input_config = InputConfig {
+            column_order: Some(StringRecord::from(vec!["type", "client", "amount", "tx"])),
+            ..Default::default()
+        };
+        let transactions = parse_all("deposit, 1, 2.5, 7\ndispute,1,,7\n", &input_config);
+        let [Ok(Transaction::Deposit {
+            client,
+            tx,
+            amount: value,
+        }), Ok(Transaction::Dispute { tx: disputed, .. })] = transactions[..]
+        else {
+            panic!("{transactions:?}");
+        };
+        assert_eq!((client, tx, value, disputed), (1, 7, amount("2.5"), 7));
+    }
+
+    #[test]
+    fn held_total() {
+        let state = process(
+            "type,client,tx,amount\n\
+             deposit,1,1,10\n\
+             deposit,2,2,2.5\n\
+             deposit,3,3,7\n\
+             dispute,1,1,\n\
+             dispute,2,2,\n\
+             dispute,3,3,\n\
+             resolve,3,3,\n",
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(state.held_total(), Some(amount("12.5")));
+        assert_eq!(new_state().held_total(), Some(Decimal::ZERO));
+    }
+
+    #[test]
+    fn shared_engine_on_many_threads() {
+        let engine = SharedEngine::new(4);
+        let config = ProcessingConfig::default();
+        // Every thread has clients (and txs) of its own, and goes back and forth between them
+        std::thread::scope(|scope| {
+            for thread in 0..8u32 {
+                let (engine, config) = (&engine, &config);
+                scope.spawn(move || {
+                    for round in 0..100u32 {
+                        for offset in 0..3u32 {
+                            let client = thread * 3 + offset;
+                            let tx = (thread * 100 + round) * 3 + offset;
+                            let transaction = match round % 4 {
+                                // Deposits of 10, a withdrawal of 5, and every fourth deposit disputed
+                                0 | 1 => Transaction::Deposit {
+                                    client,
+                                    tx,
+                                    amount: amount("10"),
+                                },
+                                2 => Transaction::Withdrawal {
+                                    client,
+                                    tx,
+                                    amount: amount("5"),
+                                },
+                                _ => Transaction::Dispute { client, tx: tx - 9 },
+                            };
+                            engine.apply(&transaction, config).unwrap();
+                        }
+                    }
+                });
+            }
+        });
+        let client_records = engine.client_records(&OutputConfig::default()).unwrap();
+        assert_eq!(client_records.len(), 24);
+        for (client_id, client_record) in client_records.iter().enumerate() {
+            assert_eq!(client_record.client_id, client_id as ClientId);
+            // 25 rounds of 10 + 10 - 5, with 10 of each moved to held
+            assert_eq!(
+                (
+                    client_record.available,
+                    client_record.held,
+                    client_record.total
+                ),
+                (amount("125"), amount("250"), amount("375"))
+            );
+        }
+    }
+
+    #[test]
+    fn round_trip_check_catches_lost_digits() {
+        let state = process(
+            "type,client,tx,amount\ndeposit,1,1,1.2345\ndeposit,2,2,10\ndispute,2,2,\n",
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        let output_config = OutputConfig::default();
+        let written = output(&state, &output_config);
+        let client_records = output_records([(None, &state)], &output_config).unwrap();
+        round_trip_check(written.as_bytes(), &client_records, &output_config).unwrap();
+        // As if the output had been rounded to two places on the way out
+        let rounded = written.replace("1.2345", "1.23");
+        assert_ne!(rounded, written);
+        let error = round_trip_check(rounded.as_bytes(), &client_records, &output_config)
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("Output row 1 doesn't match"), "{error}");
+        // And a row that went missing
+        let short = written.lines().take(2).collect::<Vec<_>>().join("\n");
+        assert!(round_trip_check(short.as_bytes(), &client_records, &output_config).is_err());
+    }
+
+    #[test]
+    fn lone_carriage_returns_become_newlines() {
+        let input = b"type,client,tx,amount\rdeposit,1,1,10\r\ndeposit,1,2,5\r\rwithdrawal,1,3,1\n";
+        let expected = "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,5\n\nwithdrawal,1,3,1\n";
+        let mut normalized = String::new();
+        NewlineNormalizer::new(&input[..])
+            .read_to_string(&mut normalized)
+            .unwrap();
+        assert_eq!(normalized, expected);
+        // A byte at a time, so that a `\r\n` always gets split between two reads
+        let mut normalizer = NewlineNormalizer::new(&input[..]);
+        let mut normalized = Vec::new();
+        let mut byte = [0];
+        while normalizer.read(&mut byte).unwrap() > 0 {
+            normalized.push(byte[0]);
+        }
+        assert_eq!(String::from_utf8(normalized).unwrap(), expected);
+        let state = process(expected, &ProcessingConfig::default()).unwrap();
+        assert_eq!(balances(&state, 1), (amount("14"), Decimal::ZERO, false));
+    }
+
+    #[test]
+    fn type_counts_for_a_mixed_file() {
+        let state = process(
+            "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,10\nwithdrawal,1,3,5\ndispute,1,1,\nresolve,1,1,\ndispute,1,2,\nchargeback,1,2,\nwithdrawal,1,4,100\n",
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        let counts: HashMap<_, _> = state.type_counts().collect();
+        // The withdrawal that bounced counts too
+        assert_eq!(counts[&TransactionType::Deposit], 2);
+        assert_eq!(counts[&TransactionType::Withdrawal], 2);
+        assert_eq!(counts[&TransactionType::Dispute], 2);
+        assert_eq!(counts[&TransactionType::Resolve], 1);
+        assert_eq!(counts[&TransactionType::Chargeback], 1);
+        assert_eq!(counts[&TransactionType::Hold], 0);
+        assert_eq!(counts.values().sum::<u64>(), state.records_processed());
+    }
+
+    #[test]
+    fn high_held_ratio_gets_flagged() {
+        // Client 1 has 90 of 100 held, client 2 only 10 of 100, client 3 nothing at all
+        let state = process(
+            "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,90\ndispute,1,2,\ndeposit,2,3,90\ndeposit,2,4,10\ndispute,2,4,\ndeposit,3,5,100\n",
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        let mut report = Vec::new();
+        state
+            .report_high_held(None, amount("0.5"), &mut report)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            "client 1: 90 of a total of 100 held\n"
+        );
+        let mut report = Vec::new();
+        state
+            .report_high_held(Some("a.csv"), amount("0.05"), &mut report)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            "client a.csv/1: 90 of a total of 100 held\nclient a.csv/2: 10 of a total of 100 held\n"
+        );
+    }
+
+    #[test]
+    fn commit_writes_out_what_changed() {
+        let sink = Rc::new(RefCell::new(VecSink::default()));
+        let mut state = new_state();
+        state.commit_into(Box::new(sink.clone()), OutputConfig::default());
+        process_into(
+            &mut state,
+            "type,client,tx,amount\ndeposit,2,1,10\ndeposit,1,2,5\ncommit,,,\ndeposit,1,3,1\ncommit,,,\ncommit,,,\n",
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        // Sorted within each commit, and a commit with nothing new writes nothing
+        assert_eq!(
+            sink.borrow().records,
+            vec![
+                (1, amount("5"), Decimal::ZERO),
+                (2, amount("10"), Decimal::ZERO),
+                (1, amount("6"), Decimal::ZERO),
+            ]
+        );
+        assert_eq!(state.committed_count(), 3);
+        assert_eq!(
+            state
+                .type_counts()
+                .nth(TransactionType::Commit as usize)
+                .unwrap()
+                .1,
+            3
+        );
+        // Without anywhere to go, a commit is an error even when lenient
+        let error = process(
+            "type,client,tx,amount\ndeposit,1,1,10\ncommit,,,\n",
+            &ProcessingConfig::default(),
+        )
+        .err()
+        .unwrap()
+        .to_string();
+        assert!(error.starts_with("Got a commit row"), "{error}");
+    }
+
+    #[test]
+    fn resolve_of_a_seed_locked_client_is_a_conflict() {
+        let csv = "type,client,tx,amount\ndeposit,1,1,10\ndispute,1,1,\nresolve,1,1,\n";
+        let seeded = || {
+            let mut state = new_state();
+            state
+                .load_seed("client,available,held,total,locked\n1,5,0,5,true\n".as_bytes())
+                .unwrap();
+            state
+        };
+        let config = ProcessingConfig {
+            strict: true,
+            detect_lock_conflicts: true,
+            ..Default::default()
+        };
+        let error = process_into(&mut seeded(), csv, &config)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            "Client 1 is locked in the seed, but got a resolve of tx 1"
+        );
+        // Not looked for without the flag
+        let mut state = seeded();
+        process_into(
+            &mut state,
+            csv,
+            &ProcessingConfig {
+                strict: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(balances(&state, 1), (amount("15"), Decimal::ZERO, true));
+        // A resolve that had to wait for its dispute gets looked at once it's applied, same as one in order
+        let buffered = ProcessingConfig {
+            strict: true,
+            detect_lock_conflicts: true,
+            buffer_ooo: true,
+            ..Default::default()
+        };
+        let mut state = seeded();
+        let error = process_into(
+            &mut state,
+            "type,client,tx,amount\ndeposit,1,1,10\nresolve,1,1,\ndispute,1,1,\n",
+            &buffered,
+        )
+        .unwrap_err()
+        .to_string();
+        assert_eq!(
+            error,
+            "Client 1 is locked in the seed, but got a resolve of tx 1"
+        );
+        // And a client that only got locked in this run can have its other disputes resolved
+        process_into(
+            &mut seeded(),
+            "type,client,tx,amount\ndeposit,2,1,10\ndeposit,2,2,10\ndispute,2,1,\ndispute,2,2,\nchargeback,2,1,\nresolve,2,2,\n",
+            &config,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn held_breakdown_of_two_open_disputes() {
+        let state = process(
+            "type,client,tx,amount\ndeposit,1,3,10\ndeposit,1,1,2.5\ndeposit,1,2,4\ndeposit,2,4,1\ndispute,1,3,\ndispute,1,1,\n",
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        let breakdown = state.held_breakdown();
+        // Client 2 has nothing open, so isn't in there
+        assert_eq!(breakdown.len(), 1);
+        let client = &breakdown[0];
+        assert_eq!((client.client, client.held), (1, amount("12.5")));
+        let disputes: Vec<_> = client
+            .disputes
+            .iter()
+            .map(|dispute| (dispute.tx, dispute.held))
+            .collect();
+        assert_eq!(disputes, vec![(1, amount("2.5")), (3, amount("10"))]);
+    }
+
+    #[test]
+    fn unknown_types_only_get_skipped_when_asked() {
+        let csv = "type,client,tx,amount\ndeposit,1,1,10\ntransfer,1,2,5\n";
+        let error = process(csv, &ProcessingConfig::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert_eq!(error, "transfer 2 is of an unknown type");
+        let skipping = ProcessingConfig {
+            skip_unknown_types: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let state = process(csv, &skipping).unwrap();
+        assert_eq!(balances(&state, 1), (amount("10"), Decimal::ZERO, false));
+        // --strict still wins
+        let strict = ProcessingConfig {
+            strict: true,
+            skip_unknown_types: true,
+            ..Default::default()
+        };
+        assert!(process(csv, &strict).is_err());
+        // A blank type isn't a new one, so that's still an error, unless it's --skip-blank-type
+        let blank = "type,client,tx,amount\n  ,1,1,10\n";
+        assert!(process(blank, &skipping).is_err());
+        let mut state = new_state();
+        let input_config = InputConfig {
+            skip_blank_type: true,
+            ..Default::default()
+        };
+        process_with(
+            &mut state,
+            blank,
+            &input_config,
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        assert!(state.client_states.get(1).is_none());
+    }
+
+    // For writers the state keeps a hold of, so that what got written can still be looked at
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn dispute_log(csv: &str, config: &ProcessingConfig) -> String {
+        let buffer = SharedBuffer::default();
+        let mut state = new_state();
+        state.observe_with(Box::new(DisputeLogObserver::new(buffer.clone())));
+        process_into(&mut state, csv, config).unwrap();
+        let log = buffer.0.borrow().clone();
+        String::from_utf8(log).unwrap()
+    }
+
+    #[test]
+    fn dispute_log_of_a_whole_cycle() {
+        let header = "event,client,tx,amount,available,held,locked\n";
+        assert_eq!(
+            dispute_log(
+                "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,5\ndispute,1,1,\nresolve,1,1,\ndispute,1,2,\nchargeback,1,2,\n",
+                &ProcessingConfig::default(),
+            ),
+            format!("{header}open,1,1,10,5,10,false\nresolve,1,1,10,15,0,false\nopen,1,2,5,10,5,false\nchargeback,1,2,5,10,0,true\n")
+        );
+        // The resolves --auto-resolve-after makes up are in there too
+        assert_eq!(
+            dispute_log(
+                "type,client,tx,amount\ndeposit,1,1,10\ndispute,1,1,\ndeposit,1,2,5\ndeposit,1,3,5\n",
+                &ProcessingConfig {
+                    auto_resolve_after: Some(2),
+                    quiet: true,
+                    ..Default::default()
+                },
+            ),
+            format!("{header}open,1,1,10,0,10,false\nresolve,1,1,10,20,0,false\n")
+        );
+        // And so are the settlements that had to wait for their dispute
+        assert_eq!(
+            dispute_log(
+                "type,client,tx,amount\ndeposit,1,1,10\nchargeback,1,1,\ndispute,1,1,\n",
+                &ProcessingConfig {
+                    buffer_ooo: true,
+                    ..Default::default()
+                },
+            ),
+            format!("{header}open,1,1,10,0,10,false\nchargeback,1,1,10,0,0,true\n")
+        );
+    }
+
+    #[test]
+    fn client_formats() {
+        let state = process(
+            "type,client,tx,amount\ndeposit,1,1,1\ndeposit,255,2,2\n",
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        let clients = |client_format| {
+            let output_config = OutputConfig {
+                client_format,
+                ..Default::default()
+            };
+            output(&state, &output_config)
+                .lines()
+                .skip(1)
+                .map(|line| line.split(',').next().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(clients(ClientFormat::Decimal), ["1", "255"]);
+        assert_eq!(clients(ClientFormat::Hex), ["1", "ff"]);
+        assert_eq!(clients(ClientFormat::Padded(5)), ["00001", "00255"]);
+        // Too narrow a width doesn't cut anything off
+        assert_eq!(clients(ClientFormat::Padded(2)), ["01", "255"]);
+    }
+
+    #[test]
+    fn memory_report_is_plausible() {
+        let report = |state: &EngineState| {
+            let mut report = Vec::new();
+            state.report_memory(None, &mut report).unwrap();
+            let report = String::from_utf8(report).unwrap();
+            // Every number in there, in the order they're listed
+            report
+                .trim_end()
+                .strip_prefix("memory: ")
+                .unwrap()
+                .split(", ")
+                .map(|part| part.rsplit(' ').next().unwrap().parse::<usize>().unwrap())
+                .collect::<Vec<_>>()
+        };
+        let few = report(
+            &process(
+                "type,client,tx,amount\ndeposit,1,1,1\n",
+                &ProcessingConfig::default(),
+            )
+            .unwrap(),
+        );
+        assert!(few.iter().all(|bytes| *bytes > 0), "{few:?}");
+        let mut csv = String::from("type,client,tx,amount\n");
+        for tx in 0..100_000 {
+            csv.push_str(&format!("deposit,{},{tx},1\n", tx % 1000));
+        }
+        let many = report(&process(&csv, &ProcessingConfig::default()).unwrap());
+        assert!(many[1] > few[1], "{few:?} {many:?}");
+        assert!(many[1] >= 100_000 * std::mem::size_of::<TransactionId>());
+    }
+
+    #[test]
+    fn chargeback_sources() {
+        let charged_back = |csv: &str, chargeback_source, safe_withdrawal_dispute| {
+            let config = ProcessingConfig {
+                chargeback_source,
+                safe_withdrawal_dispute,
+                quiet: true,
+                ..Default::default()
+            };
+            balances(&process(csv, &config).unwrap(), 1)
+        };
+        // 5 left over after the 10 got disputed
+        let deposit =
+            "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,5\ndispute,1,1,\nchargeback,1,1,\n";
+        assert_eq!(
+            charged_back(deposit, ChargebackSource::Held, false),
+            (amount("5"), amount("0"), true)
+        );
+        // The hold stays on top of it all coming out of available
+        assert_eq!(
+            charged_back(deposit, ChargebackSource::Available, false),
+            (amount("-5"), amount("10"), true)
+        );
+        assert_eq!(
+            charged_back(deposit, ChargebackSource::Total, false),
+            (amount("5"), amount("0"), true)
+        );
+        let withdrawal = "type,client,tx,amount\ndeposit,1,1,20\nwithdrawal,1,2,5\ndispute,1,2,\nchargeback,1,2,\n";
+        assert_eq!(
+            charged_back(withdrawal, ChargebackSource::Held, false),
+            (amount("10"), amount("0"), true)
+        );
+        assert_eq!(
+            charged_back(withdrawal, ChargebackSource::Available, false),
+            (amount("5"), amount("5"), true)
+        );
+        assert_eq!(
+            charged_back(withdrawal, ChargebackSource::Total, false),
+            (amount("10"), amount("0"), true)
+        );
+        // Only 2 of the 8 could be held, which is where the three of them actually differ
+        let short = "type,client,tx,amount\ndeposit,1,1,10\nwithdrawal,1,2,8\ndispute,1,2,\nchargeback,1,2,\n";
+        assert_eq!(
+            charged_back(short, ChargebackSource::Held, true),
+            (amount("0"), amount("0"), true)
+        );
+        assert_eq!(
+            charged_back(short, ChargebackSource::Available, true),
+            (amount("-8"), amount("2"), true)
+        );
+        assert_eq!(
+            charged_back(short, ChargebackSource::Total, true),
+            (amount("-6"), amount("0"), true)
+        );
+    }
+
+    #[test]
+    fn dialects_get_detected() {
+        assert_eq!(
+            detect_dialect(b"type;client;tx;amount\ndeposit;1;1;1,5\n", true),
+            (b';', b'"')
+        );
+        assert_eq!(
+            detect_dialect(b"type\tclient\ttx\tamount\r\ndeposit\t1\t1\t10\r\n", true),
+            (b'\t', b'"')
+        );
+        assert_eq!(
+            detect_dialect(b"type,client,tx,amount\n'deposit',1,1,'10'\n", true),
+            (b',', b'\'')
+        );
+        // A delimiter in quotes doesn't count
+        assert_eq!(
+            detect_dialect(b"type;client;tx;amount\n\"a,b,c,d,e\";1;1;1\n", true),
+            (b';', b'"')
+        );
+        // As many of both, so there's no telling
+        assert_eq!(detect_dialect(b"a;b,c\n1;2,3\n", true), (b',', b'"'));
+        assert_eq!(detect_dialect(b"", true), (b',', b'"'));
+        // The last line of a sample that got cut off doesn't get a say
+        assert_eq!(
+            detect_dialect(b"type;client;tx;amount\ndeposit;1;1;10\ndepo", false),
+            (b';', b'"')
+        );
+    }
+
+    #[test]
+    fn avg_tx_of_deposits_and_withdrawals() {
+        let state = process(
+            "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,20\ndeposit,2,3,10\nwithdrawal,2,4,5\nwithdrawal,2,5,100\ndispute,3,1,\n",
+            &ProcessingConfig::default(),
+        )
+        .unwrap();
+        let output_config = OutputConfig {
+            with_avg_tx: true,
+            ..Default::default()
+        };
+        let avg_tx: Vec<_> = output_records([(None, &state)], &output_config)
+            .unwrap()
+            .into_iter()
+            .map(|client_record| (client_record.client_id, client_record.avg_tx))
+            .collect();
+        // The withdrawal that bounced didn't move anything, and client 3 has nothing to take an average of
+        assert_eq!(
+            avg_tx,
+            vec![
+                (1, Some(Some(amount("15")))),
+                (2, Some(Some(amount("7.5")))),
+                (3, Some(None)),
+            ]
+        );
+        assert!(output(&state, &output_config)
+            .starts_with("client,available,held,total,locked,avg_tx\n"));
+        let plain = output_records([(None, &state)], &OutputConfig::default()).unwrap();
+        assert!(plain
+            .iter()
+            .all(|client_record| client_record.avg_tx.is_none()));
+    }
+
+    #[test]
+    fn zero_disputes_with_and_without_skipping() {
+        // Amounts of zero don't get past parsing, but can still end up in the store from elsewhere
+        let zero_deposit = || {
+            let mut client = ClientState::new();
+            let mut tx_database = HashMapStore::new();
+            apply_one(
+                &deposit(1, "0"),
+                &mut client,
+                &mut tx_database,
+                &ProcessingConfig::default(),
+            )
+            .unwrap();
+            (client, tx_database)
+        };
+        let (mut client, mut tx_database) = zero_deposit();
+        assert_eq!(
+            apply_one(
+                &dispute(1),
+                &mut client,
+                &mut tx_database,
+                &ProcessingConfig::default()
+            )
+            .unwrap(),
+            Applied::Dispute {
+                held: Decimal::ZERO
+            }
+        );
+        assert!(client.txns_under_dispute.contains_key(&1));
+        assert!(matches!(
+            tx_database.query(1).unwrap().dispute_status,
+            DisputeStatus::Disputed
+        ));
+
+        let skipping = ProcessingConfig {
+            skip_zero_disputes: true,
+            quiet: true,
+            ..Default::default()
+        };
+        let (mut client, mut tx_database) = zero_deposit();
+        assert_eq!(
+            apply_one(&dispute(1), &mut client, &mut tx_database, &skipping).unwrap(),
+            Applied::Skipped(SkipReason::ZeroAmount)
+        );
+        assert!(client.txns_under_dispute.is_empty());
+        assert!(matches!(
+            tx_database.query(1).unwrap().dispute_status,
+            DisputeStatus::Undisputed
+        ));
+        // Anything that isn't zero gets disputed as ever
+        let (mut client, mut tx_database) = deposited();
+        assert_eq!(
+            apply_one(&dispute(1), &mut client, &mut tx_database, &skipping).unwrap(),
+            Applied::Dispute { held: amount("10") }
+        );
+    }
+
+    #[test]
+    fn zero_buffer_size_still_reads() {
+        for buffer_size in [Some(0), Some(1), None] {
+            let input_config = InputConfig {
+                buffer_size,
+                ..Default::default()
+            };
+            let mut state = new_state();
+            process_with(
+                &mut state,
+                "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,5\n",
+                &input_config,
+                &ProcessingConfig::default(),
+            )
+            .unwrap();
+            assert_eq!(balances(&state, 1), (amount("15"), Decimal::ZERO, false));
+        }
+    }
+
+    #[test]
+    fn dispute_leaves_the_waiting_settlement_to_the_caller() {
+        let config = ProcessingConfig {
+            buffer_ooo: true,
+            strict: true,
+            ..Default::default()
+        };
+        // A resolve that claims the wrong amount, which is an error with --strict once it's applied
+        let resolve = Transaction::Resolve {
+            client: 1,
+            tx: 1,
+            amount: Some(amount("3")),
+        };
+        let (mut client, mut tx_database) = deposited();
+        assert_eq!(
+            apply_one(&resolve, &mut client, &mut tx_database, &config).unwrap(),
+            Applied::Buffered
+        );
+        assert_eq!(
+            apply_one(&dispute(1), &mut client, &mut tx_database, &config).unwrap(),
+            Applied::Dispute { held: amount("10") }
+        );
+        assert!(client.early_settlements.contains_key(&1));
+        assert_eq!((client.available, client.held), (amount("0"), amount("10")));
+
+        // The dispute stays applied when the settlement fails after it
+        let engine = SharedEngine::new(1);
+        engine.apply(&deposit(1, "10"), &config).unwrap();
+        engine.apply(&resolve, &config).unwrap();
+        assert!(engine.apply(&dispute(1), &config).is_err());
+        let client_record = engine
+            .client_record(1, &OutputConfig::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (client_record.available, client_record.held),
+            (amount("0"), amount("10"))
+        );
+        let mut state = new_state();
+        let error = process_into(
+            &mut state,
+            "type,client,tx,amount\ndeposit,1,1,10\nresolve,1,1,3\ndispute,1,1,\n",
+            &config,
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("resolve"), "{error}");
+        assert_eq!(balances(&state, 1), (amount("0"), amount("10"), false));
+    }
+}
@@ -1,260 +1,1571 @@
 use std::{
+    cell::RefCell,
     collections::{HashMap, HashSet},
-    env, io,
+    env,
+    fs::{self, File},
+    io::{self, Read, Write},
+    iter,
+    path::Path,
+    rc::Rc,
+    str::FromStr,
+    sync::{atomic::AtomicBool, Arc},
+    thread,
+    time::{Duration, Instant},
 };
 
-use csv::{ReaderBuilder, Writer};
+use csv::{QuoteStyle, StringRecord, Terminator, Trim};
+use recruitment::{
+    decode_record, deserialize_lines, deserialize_transactions, detect_dialect,
+    handle_transactions, output_records, parse_record, reader_builder, validate_headers,
+    write_output, BoolStyle, ChargebackSource, ClientFormat, ClientId, ClientStorage, CsvSink,
+    DisputeLogObserver, DisputePolicy, EngineState, InputConfig, LedgerObserver, LockedSplitSink,
+    NegativeTotal, NewlineNormalizer, OutputConfig, OutputSink, ProcessingConfig, RetryReader,
+    RowLimitReader, SortBy, Transaction, TransactionType, TxConflict, WithdrawAgainst,
+};
 use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
+
+mod generate;
 
 fn main() -> Result<(), anyhow::Error> {
     // Type stuff: There's not many record cases, and I'm using serde and enums for parsing, so no case can possibly go unhandled.
-    //             It would be nice to get the information of a record having an associated value into the type syatems as well
-    //             But the type machinery for that would be too long for this kind of a toy assignment
-    // No unit tests, because it's getting late when I'm writing this. No complicated bits though anyway.
-
-    // Clap would be cooler, but also massive for this
-    let in_path = env::args().skip(1).take(1).next().expect("No arg");
-
-    // A HashMap would be marginally more readable, but let's go fast and preallocate a big array, because we can with u16 keys and 500kb is nothing
-    // Massive overkill for small examples though
-    let mut seen_clients: Vec<ClientId> = Vec::with_capacity(1 << 13);
-    const NONE: std::option::Option<Box<ClientState>> = None;
-    let mut client_states: Box<[Option<Box<ClientState>>; 1 << 16]> = Box::new([NONE; 1 << 16]);
-
-    // Clients are ok for in-memory, but this would probably need disk storage and memory cache for real life applications
-    // As this is just a wrapper over a hashmap, so I hope you're not throwing gigabytes of csv at this, because it could OOM easily
-    // I'm only saving the transactions that have IDs of their own, so deposit and withdraw, as the others do not (which is insane for real life ofc)
-    let mut tx_database = TxDatabase::new();
-
-    let mut csv_reader = ReaderBuilder::new()
-        .flexible(true)
-        .trim(csv::Trim::All)
-        .from_path(in_path)?;
-
-    // Let's have it like this so we could easily change it to a tokio stream if needed
-    let transaction_iter: Box<dyn Iterator<Item = Result<TransactionRecord, anyhow::Error>>> =
-        Box::new(csv_reader.deserialize().map(
-            |result| -> Result<TransactionRecord, anyhow::Error> {
-                let record: TransactionRecord = result?;
-                Ok(record)
-            },
-        ));
-
-    handle_transactions(
-        transaction_iter,
-        &mut client_states,
-        &mut seen_clients,
-        &mut tx_database,
-    )?;
-
-    write_output(seen_clients, &client_states)?;
-    Ok(())
-}
+    //             Raw csv rows get turned into `Transaction`s right after parsing, so whether a record has an amount
+    //             is checked once, there, and the processing code doesn't have to take it on faith
+    // Tests: the engine's are at the bottom of lib.rs, and the flags that only exist here are run against the real binary in tests/cli.rs
 
-fn handle_transactions(
-    record_iter: impl Iterator<Item = Result<TransactionRecord, anyhow::Error>>,
-    client_states: &mut [Option<Box<ClientState>>; 1 << 16],
-    seen_clients: &mut Vec<ClientId>,
-    tx_database: &mut TxDatabase,
-) -> Result<(), anyhow::Error> {
-    for record in record_iter {
-        let record = record?;
+    // The only subcommand, everything else is flags for the default one
+    let mut args = env::args().skip(1).peekable();
+    if args.peek().map(String::as_str) == Some("generate") {
+        args.next();
+        return generate::run(args);
+    }
+
+    let Args {
+        in_paths,
+        input_config,
+        config,
+        output_config,
+        follow,
+        compression,
+        namespace_by_file,
+        report_open_disputes,
+        report_held_total,
+        report_memory,
+        held_breakdown,
+        flag_suspicious,
+        flag_high_held_ratio,
+        flagged_out,
+        output_partial,
+        limit,
+        diff_against,
+        validate_totals_against,
+        totals_epsilon,
+        round_trip_check,
+        dump_tx_db,
+        timing,
+        stats,
+        load_tx_db,
+        seed,
+        repl,
+        parquet_paths,
+        assert_balanced,
+        dispute_ledger,
+        count_only,
+        ledger_out,
+        dispute_log,
+        preview,
+        output_buffer,
+        locked_out,
+        unlocked_out,
+        storage,
+    } = Args::parse(args, env::var("TX_INPUT").ok())?;
+
+    let snapshot_requested = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    signal_hook::flag::register(signal_hook::consts::SIGUSR1, snapshot_requested.clone())?;
+
+    if repl {
+        return run_repl(&input_config, &config, &output_config, storage);
+    }
+
+    if count_only {
+        return count_types(
+            &in_paths,
+            compression,
+            &parquet_paths,
+            &input_config,
+            &config,
+            limit,
+        );
+    }
+
+    if let Some(rows) = preview {
+        return preview_transactions(
+            &in_paths,
+            compression,
+            &parquet_paths,
+            &input_config,
+            rows,
+            config.quiet,
+        );
+    }
 
-        // For disputes etc we're modifying the client id in the dispute transaction,
-        // And not in the original transaction
-        // Quite unsafe if we do not trust the data source (but we do)
-        let client = &mut client_states[record.client_id as usize];
-        // Create a default client if none exists
-        if client.is_none() {
-            seen_clients.push(record.client_id);
-            *client = Some(Box::new(ClientState::new()));
+    if let Some(interval) = follow {
+        if limit.is_some() {
+            anyhow::bail!("Can't limit a file that's being followed");
+        }
+        // It's still growing, a map would only ever see what was there at the start
+        if input_config.mmap {
+            anyhow::bail!("Can't memory map a file that's being followed");
+        }
+        let [in_path] = in_paths.as_slice() else {
+            anyhow::bail!("Can only follow a single file");
         };
-        let client = client.as_deref_mut().unwrap();
-
-        // Handle transaction
-        match record.transaction_type {
-            TransactionType::Deposit => {
-                client.available += record.value.expect("Invalid record");
-                // Store transaction for posterity
-                tx_database.save(record);
+        if compression.unwrap_or_else(|| Compression::from_path(in_path)) != Compression::None {
+            anyhow::bail!("Can't follow a compressed file");
+        }
+        // The metadata is at the end, so a parquet file can't be read before it's done anyway
+        if in_path.ends_with(".parquet") || parquet_paths.contains(in_path) {
+            anyhow::bail!("Can't follow a parquet file");
+        }
+        let mut state = EngineState::new(snapshot_requested);
+        state.store_clients_in(storage)?;
+        if let Some(load_path) = &load_tx_db {
+            state.load_tx_database(File::open(load_path)?)?;
+        }
+        if let Some(seed) = &seed {
+            state.load_seed(File::open(seed)?)?;
+        }
+        if let Some(ledger_out) = &ledger_out {
+            let writer = io::BufWriter::new(File::create(ledger_out)?);
+            state.observe_with(Box::new(LedgerObserver::new(writer)));
+        }
+        if let Some(dispute_log) = &dispute_log {
+            let writer = io::BufWriter::new(File::create(dispute_log)?);
+            state.observe_with(Box::new(DisputeLogObserver::new(writer)));
+        }
+        if dispute_ledger.is_some() {
+            anyhow::bail!("Can't keep a dispute ledger while following, there's no end of the run to write it out at");
+        }
+        return follow_file(
+            in_path,
+            interval,
+            &mut state,
+            &input_config,
+            &config,
+            &output_config,
+            output_buffer,
+        );
+    }
+
+    // What got computed, along with its namespace if there's --namespace-by-file
+    let mut states: Vec<(Option<String>, EngineState)> = Vec::with_capacity(in_paths.len());
+    // States get pushed even if processing them blew up midway, for --output-partial
+    let mut process = || -> Result<(), anyhow::Error> {
+        // Counts data rows across all the files, so a header (there's one per file) never eats into it
+        let mut remaining = limit.unwrap_or(usize::MAX);
+        if namespace_by_file {
+            // Every file gets its own clients and its own transactions, as if it was a separate run,
+            // and the output has a column saying which file a row came from
+            for in_path in &in_paths {
+                let namespace = file_namespace(in_path)?;
+                if states
+                    .iter()
+                    .any(|(existing, _)| existing.as_ref() == Some(&namespace))
+                {
+                    anyhow::bail!("Two input files would both be namespaced as {namespace}");
+                }
+                let mut state = EngineState::new(snapshot_requested.clone());
+                state.store_clients_in(storage)?;
+                let processed = handle_transactions(
+                    read_transactions(
+                        in_path,
+                        compression,
+                        &parquet_paths,
+                        &input_config,
+                        config.quiet,
+                    )?
+                    .take(remaining)
+                    .inspect(|_| remaining -= 1),
+                    &mut state,
+                    &config,
+                );
+                states.push((Some(namespace), state));
+                processed?;
             }
-            TransactionType::Withdrawal => {
-                let value = record.value.expect("Invalid record");
-                if client.available < value {
-                    // The spec does not mention if a failed withdrawal is disputable
-                    // There's no harm in treating it as such, but it needs to be specified
-                    tx_database.save(record);
-                    continue;
+        } else {
+            // Multiple files just get chained one after another, so a dispute in one file can refer to a deposit from an earlier one
+            // Files get opened lazily, when the previous one runs out
+            let transaction_iter = in_paths.iter().flat_map(|in_path| {
+                match read_transactions(
+                    in_path,
+                    compression,
+                    &parquet_paths,
+                    &input_config,
+                    config.quiet,
+                ) {
+                    Ok(transaction_iter) => transaction_iter,
+                    Err(err) => Box::new(iter::once(Err(err))),
                 }
+            });
 
-                client.available -= value;
-                tx_database.save(record);
+            let mut state = EngineState::new(snapshot_requested.clone());
+            state.store_clients_in(storage)?;
+            // Each `commit` row in the input writes the clients that changed since the previous one straight to stdout,
+            // ahead of the full output at the end. Finalized clients go there too, and one sink between them
+            // means one header between them
+            let early_sink = Rc::new(RefCell::new(CsvSink::new(
+                stdout_writer(output_buffer),
+                &output_config,
+            )));
+            if config.finalize_after.is_some() {
+                state.finalize_into(Box::new(early_sink.clone()), output_config.clone());
             }
-            TransactionType::Dispute => {
-                // Note that there's no checking that the dispute belongs to the same client as the transaction, as that was not specified
-                let Some(referenced_tx) = tx_database.query(record.transaction_id) else {
-                    continue;
-                };
-
-                // No logic protects you with disputing the same transaction twice in a row
-                // Also not specified
-                client
-                    .txns_under_dispute
-                    .insert(referenced_tx.transaction_id);
-
-                let value = referenced_tx.value.expect("can't happen. It's possible to make the compiler know this, but I'm not duplicating half my types just for that");
+            state.commit_into(Box::new(early_sink), output_config.clone());
+            if let Some(load_path) = &load_tx_db {
+                state.load_tx_database(File::open(load_path)?)?;
+            }
+            if let Some(seed) = &seed {
+                state.load_seed(File::open(seed)?)?;
+            }
+            if let Some(ledger_out) = &ledger_out {
+                let writer = io::BufWriter::new(File::create(ledger_out)?);
+                state.observe_with(Box::new(LedgerObserver::new(writer)));
+            }
+            if let Some(dispute_log) = &dispute_log {
+                let writer = io::BufWriter::new(File::create(dispute_log)?);
+                state.observe_with(Box::new(DisputeLogObserver::new(writer)));
+            }
+            // It not being there yet is the first run
+            if let Some(ledger_path) = dispute_ledger
+                .as_ref()
+                .filter(|path| Path::new(path).exists())
+            {
+                state.load_dispute_ledger(File::open(ledger_path)?)?;
+            }
+            let processed =
+                handle_transactions(transaction_iter.take(remaining), &mut state, &config);
+            states.push((None, state));
+            processed?;
+        }
+        Ok(())
+    };
+    let started = Instant::now();
+    let processed = process();
+    if timing {
+        let elapsed = started.elapsed();
+        let records: u64 = states
+            .iter()
+            .map(|(_, state)| state.records_processed())
+            .sum();
+        eprintln!(
+            "Processed {records} records in {elapsed:.2?}, {:.0} records/s",
+            records as f64 / elapsed.as_secs_f64()
+        );
+    }
+    if stats {
+        let mut counts: Vec<(TransactionType, u64)> =
+            TransactionType::ALL.iter().map(|t| (*t, 0)).collect();
+        for (_, state) in &states {
+            for ((_, count), (_, state_count)) in counts.iter_mut().zip(state.type_counts()) {
+                *count += state_count;
+            }
+        }
+        let records: u64 = counts.iter().map(|(_, count)| count).sum();
+        for (transaction_type, count) in counts {
+            let percent = count as f64 / records.max(1) as f64 * 100.0;
+            eprintln!(
+                "{:<10} {count:>10} {percent:>5.1}%",
+                transaction_type.as_str()
+            );
+        }
+    }
 
-                // This doesn't make sense if the disputed transaction is a withdrawal
-                // But "decrease" means "decrease", and the spec is the spec
-                // Same logic follows for resolving and chargebacks
-                client.available -= value;
-                client.held += value;
+    // There's at most 65536 clients per state, so buffering the whole output is cheap, and --diff-against gets to look at it
+    let mut output = Vec::new();
+    if processed.is_ok() || output_partial {
+        // Finalized and committed clients already went out, header included, so stdout doesn't get it twice.
+        // Files and the buffered output still do, --diff-against and the like need it
+        let header_sent = states
+            .iter()
+            .any(|(_, state)| state.finalized_count() > 0 || state.committed_count() > 0);
+        let stdout_config = OutputConfig {
+            headers: output_config.headers && !header_sent,
+            ..output_config.clone()
+        };
+        let states = states
+            .iter()
+            .map(|(namespace, state)| (namespace.as_deref(), state));
+        if locked_out.is_some() || unlocked_out.is_some() {
+            // Whichever one has no file of its own goes to stdout
+            let sink_for = |path: &Option<String>| -> Result<Box<dyn OutputSink>, anyhow::Error> {
+                Ok(match path {
+                    Some(path) => Box::new(CsvSink::new(
+                        io::BufWriter::new(File::create(path)?),
+                        &output_config,
+                    )),
+                    None => Box::new(CsvSink::new(stdout_writer(output_buffer), &stdout_config)),
+                })
+            };
+            let mut sink = LockedSplitSink::new(sink_for(&locked_out)?, sink_for(&unlocked_out)?);
+            write_output(&mut sink, states, &output_config)?;
+        } else {
+            write_output(
+                &mut CsvSink::new(&mut output, &output_config),
+                states.clone(),
+                &output_config,
+            )?;
+            let mut stdout = stdout_writer(output_buffer);
+            // Both terminators end in a `\n`, and nothing in the header gets quoted
+            let skip = match output.iter().position(|byte| *byte == b'\n') {
+                Some(header_end) if output_config.headers && header_sent => header_end + 1,
+                _ => 0,
+            };
+            stdout.write_all(&output[skip..])?;
+            stdout.flush()?;
+            if round_trip_check {
+                let client_records = output_records(states, &output_config)?;
+                recruitment::round_trip_check(&output, &client_records, &output_config)?;
             }
-            TransactionType::Resolve => {
-                let Some(referenced_tx) = tx_database.query(record.transaction_id) else {
-                    continue;
-                };
-                if !client
-                    .txns_under_dispute
-                    .contains(&referenced_tx.transaction_id)
-                {
-                    continue;
-                }
+        }
+    }
+    // The output is there for debugging, but the run still failed
+    processed?;
+
+    if report_open_disputes {
+        for (namespace, state) in &states {
+            state.report_open_disputes(namespace.as_deref(), io::stderr())?;
+        }
+    }
+    if report_held_total {
+        let held_total = states
+            .iter()
+            .try_fold(Decimal::ZERO, |total, (_, state)| {
+                total.checked_add(state.held_total()?)
+            })
+            .ok_or_else(|| anyhow::anyhow!("The held total doesn't fit in a Decimal"))?;
+        eprintln!("held total: {held_total}");
+    }
+    if report_memory {
+        for (namespace, state) in &states {
+            state.report_memory(namespace.as_deref(), io::stderr())?;
+        }
+    }
+    if let Some(held_breakdown) = &held_breakdown {
+        let mut breakdown = Vec::new();
+        for (namespace, state) in &states {
+            breakdown.extend(state.held_breakdown().into_iter().map(|mut client| {
+                client.source.clone_from(namespace);
                 client
-                    .txns_under_dispute
-                    .remove(&referenced_tx.transaction_id);
+            }));
+        }
+        let mut writer = io::BufWriter::new(File::create(held_breakdown)?);
+        serde_json::to_writer_pretty(&mut writer, &breakdown)?;
+        writeln!(writer)?;
+        writer.flush()?;
+    }
+    if let Some(ratio) = flag_high_held_ratio {
+        let mut writer: Box<dyn Write> = match &flagged_out {
+            Some(path) => Box::new(io::BufWriter::new(File::create(path)?)),
+            None => Box::new(io::stderr()),
+        };
+        for (namespace, state) in &states {
+            state.report_high_held(namespace.as_deref(), ratio, &mut writer)?;
+        }
+        writer.flush()?;
+    }
+    if flag_suspicious {
+        for (namespace, state) in &states {
+            state.report_suspicious(namespace.as_deref(), io::stderr())?;
+        }
+    }
+    // Without --namespace-by-file there's only the one state
+    if let (Some(dump_path), Some((_, state))) = (dump_tx_db, states.first()) {
+        state.dump_tx_database(File::create(dump_path)?)?;
+    }
+    if let (Some(ledger_path), Some((_, state))) = (dispute_ledger, states.first()) {
+        state.dump_dispute_ledger(File::create(ledger_path)?)?;
+    }
+    if assert_balanced {
+        let unbalanced: Vec<String> = states
+            .iter()
+            .flat_map(|(namespace, state)| state.unbalanced_clients(namespace.as_deref()))
+            .collect();
+        if !unbalanced.is_empty() {
+            anyhow::bail!(
+                "{} clients with a negative balance:\n{}",
+                unbalanced.len(),
+                unbalanced.join("\n")
+            );
+        }
+    }
+    if let Some(expected_path) = diff_against {
+        if !diff_outputs(&expected_path, &output)? {
+            anyhow::bail!("Output differs from {expected_path}");
+        }
+    }
+    if let Some(expected_path) = validate_totals_against {
+        if !totals_match(&expected_path, &output, totals_epsilon)? {
+            anyhow::bail!("Totals differ from {expected_path}");
+        }
+    }
+    Ok(())
+}
+
+// Like `diff_outputs`, but only the totals, and only to within `epsilon`, for checking against another
+// implementation that gets the same numbers but rounds or formats them its own way, or has other columns
+fn totals_match(
+    expected_path: &str,
+    actual: &[u8],
+    epsilon: Decimal,
+) -> Result<bool, anyhow::Error> {
+    let expected = read_totals(File::open(expected_path)?)?;
+    let mut actual = read_totals(actual)?;
+    let mut same = true;
+    for (client, expected) in expected {
+        let Some(actual) = actual.remove(&client) else {
+            eprintln!("Client {client} is missing");
+            same = false;
+            continue;
+        };
+        let close_enough = actual
+            .checked_sub(expected)
+            .is_some_and(|difference| difference.abs() <= epsilon);
+        if !close_enough {
+            eprintln!("Client {client}: expected total {expected}, got {actual}");
+            same = false;
+        }
+    }
+    for client in actual.keys() {
+        eprintln!("Client {client} is not in {expected_path}");
+        same = false;
+    }
+    Ok(same)
+}
 
-                let value = referenced_tx.value.expect("can't happen. It's possible to make the compiler know this, but I'm not duplicating half my types just for that");
+// Keyed the same way as in `diff_outputs`
+fn read_totals(reader: impl Read) -> Result<HashMap<String, Decimal>, anyhow::Error> {
+    let mut csv_reader = csv::Reader::from_reader(reader);
+    let headers = csv_reader.headers()?.clone();
+    let column = |name: &str| headers.iter().position(|header| header == name);
+    let Some(total_column) = column("total") else {
+        anyhow::bail!("No total column to compare");
+    };
+    let key_columns = [column("source"), column("client")];
+    let mut totals = HashMap::new();
+    for record in csv_reader.records() {
+        let record = record?;
+        let key: Vec<&str> = key_columns
+            .into_iter()
+            .flatten()
+            .filter_map(|index| record.get(index))
+            .collect();
+        let total = record.get(total_column).unwrap_or_default();
+        totals.insert(key.join("/"), Decimal::from_str(total)?);
+    }
+    Ok(totals)
+}
+
+// Every difference goes to stderr, and the return value is whether there were none.
+// Rows are matched up by client (and source, if namespaced), so their order doesn't matter,
+// and amounts are compared as numbers, so `1.5` and `1.5000` are the same
+fn diff_outputs(expected_path: &str, actual: &[u8]) -> Result<bool, anyhow::Error> {
+    let mut expected_reader = csv::Reader::from_path(expected_path)?;
+    let mut actual_reader = csv::Reader::from_reader(actual);
+    let headers = actual_reader.headers()?.clone();
+    if expected_reader.headers()? != &headers {
+        eprintln!(
+            "Columns differ: expected {:?}, got {:?}",
+            expected_reader.headers()?,
+            headers
+        );
+        return Ok(false);
+    }
+    let key_columns: Vec<usize> = headers
+        .iter()
+        .enumerate()
+        .filter(|(_, header)| *header == "source" || *header == "client")
+        .map(|(index, _)| index)
+        .collect();
+    let key = |record: &StringRecord| -> String {
+        key_columns
+            .iter()
+            .filter_map(|index| record.get(*index))
+            .collect::<Vec<_>>()
+            .join("/")
+    };
+    let same_value = |expected: &str, actual: &str| match (
+        Decimal::from_str(expected),
+        Decimal::from_str(actual),
+    ) {
+        (Ok(expected), Ok(actual)) => expected == actual,
+        _ => expected == actual,
+    };
 
-                client.held -= value;
-                client.available += value;
+    let mut actual_records: HashMap<String, StringRecord> = HashMap::new();
+    for record in actual_reader.records() {
+        let record = record?;
+        actual_records.insert(key(&record), record);
+    }
+    let mut same = true;
+    for expected_record in expected_reader.records() {
+        let expected_record = expected_record?;
+        let client = key(&expected_record);
+        let Some(actual_record) = actual_records.remove(&client) else {
+            eprintln!("Client {client} is missing");
+            same = false;
+            continue;
+        };
+        for ((header, expected), actual) in headers
+            .iter()
+            .zip(expected_record.iter())
+            .zip(actual_record.iter())
+        {
+            if !same_value(expected, actual) {
+                eprintln!("Client {client}: expected {header} {expected}, got {actual}");
+                same = false;
             }
-            TransactionType::Chargeback => {
-                let Some(referenced_tx) = tx_database.query(record.transaction_id) else {
-                    continue;
-                };
-                if !client
-                    .txns_under_dispute
-                    .contains(&referenced_tx.transaction_id)
-                {
-                    continue;
-                }
-                client
-                    .txns_under_dispute
-                    .remove(&referenced_tx.transaction_id);
+        }
+    }
+    for client in actual_records.keys() {
+        eprintln!("Client {client} is not in {expected_path}");
+        same = false;
+    }
+    Ok(same)
+}
+
+// Let's have it like this so we could easily change it to a tokio stream if needed
+type TransactionIter = Box<dyn Iterator<Item = Result<Transaction, anyhow::Error>>>;
+
+// A few dozen rows' worth, which is plenty to tell what's separating the fields
+const DIALECT_SAMPLE_BYTES: usize = 4 << 10;
+
+// Quiet is --quiet, for the retry warnings
+fn read_transactions(
+    in_path: &str,
+    compression: Option<Compression>,
+    parquet_paths: &HashSet<String>,
+    input_config: &InputConfig,
+    quiet: bool,
+) -> Result<TransactionIter, anyhow::Error> {
+    if in_path.ends_with(".parquet") || parquet_paths.contains(in_path) {
+        return read_parquet(in_path, input_config);
+    }
+    let compression = compression.unwrap_or_else(|| Compression::from_path(in_path));
+    let mut input = open_input(in_path, compression, input_config, quiet)?;
+    if input_config.normalize_newlines {
+        input = Box::new(NewlineNormalizer::new(input));
+    }
+    if let Some(max_row_bytes) = input_config.max_row_bytes {
+        input = Box::new(RowLimitReader::new(input, max_row_bytes));
+    }
+    // Every file gets a guess of its own, so a directory with a mix of them still works
+    let detected;
+    let input_config = if input_config.detect_dialect {
+        let mut sample = Vec::with_capacity(DIALECT_SAMPLE_BYTES);
+        (&mut input)
+            .take(DIALECT_SAMPLE_BYTES as u64)
+            .read_to_end(&mut sample)?;
+        let (delimiter, quote) = detect_dialect(&sample, sample.len() < DIALECT_SAMPLE_BYTES);
+        input = Box::new(io::Cursor::new(sample).chain(input));
+        detected = InputConfig {
+            delimiter,
+            quote,
+            ..input_config.clone()
+        };
+        &detected
+    } else {
+        input_config
+    };
+    if input_config.recover_lines {
+        let capacity = input_config.buffer_size.unwrap_or(8 << 10);
+        let lines = io::BufReader::with_capacity(capacity, input);
+        return Ok(Box::new(deserialize_lines(lines, input_config)));
+    }
+    let csv_reader = reader_builder(input_config).from_reader(input);
+    Ok(Box::new(deserialize_transactions(csv_reader, input_config)))
+}
+
+// Columnar input, for pipelines that have it as parquet already. Every row gets turned into the strings
+// a csv row would have been, so that everything from there on (amount parsing, --strip-currency, ...) is the same.
+// Parquet has compression of its own, so that's not a thing here
+#[cfg(feature = "parquet")]
+fn read_parquet(
+    in_path: &str,
+    input_config: &InputConfig,
+) -> Result<TransactionIter, anyhow::Error> {
+    use parquet::{
+        file::reader::{FileReader, SerializedFileReader},
+        record::Field,
+    };
+
+    let reader = SerializedFileReader::new(File::open(in_path)?)?;
+    let headers: StringRecord = reader
+        .metadata()
+        .file_metadata()
+        .schema_descr()
+        .root_schema()
+        .get_fields()
+        .iter()
+        .map(|column| column.name())
+        .collect();
+    validate_headers(&headers)?;
+    let input_config = input_config.clone();
+    let transactions = reader.into_iter().filter_map(move |row| {
+        row.map_err(anyhow::Error::from)
+            .and_then(|row| {
+                let record: StringRecord = row
+                    .get_column_iter()
+                    .map(|(_, field)| match field {
+                        Field::Null => String::new(),
+                        // Display would put quotes around it
+                        Field::Str(value) => value.trim().to_owned(),
+                        field => field.to_string(),
+                    })
+                    .collect();
+                parse_record(&record, &headers, &input_config)
+            })
+            .transpose()
+    });
+    Ok(Box::new(transactions))
+}
+
+#[cfg(not(feature = "parquet"))]
+fn read_parquet(in_path: &str, _: &InputConfig) -> Result<TransactionIter, anyhow::Error> {
+    anyhow::bail!("Can't read {in_path}, built without parquet support (the `parquet` feature)")
+}
+
+// Every .csv in the directory (not in subdirectories), sorted by name, so that daily exports go in date order
+fn csv_files_in(dir: &str) -> Result<Vec<String>, anyhow::Error> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() || path.extension().is_none_or(|extension| extension != "csv") {
+            continue;
+        }
+        let Some(path) = path.to_str() else {
+            anyhow::bail!("{} isn't valid UTF-8", path.display());
+        };
+        paths.push(path.to_owned());
+    }
+    if paths.is_empty() {
+        anyhow::bail!("No csv files in {dir}");
+    }
+    paths.sort_unstable();
+    Ok(paths)
+}
+
+// Stdout is line buffered, which is a write per row for everything that streams its rows out.
+// With a buffer of its own, a slow reader on the other end of a pipe gets them in bigger chunks instead.
+// write_all and flush already retry partial and interrupted writes, so there's nothing more to it than that
+fn stdout_writer(output_buffer: Option<usize>) -> Box<dyn Write> {
+    match output_buffer {
+        Some(capacity) => Box::new(io::BufWriter::with_capacity(capacity, io::stdout())),
+        None => Box::new(io::stdout()),
+    }
+}
+
+// The file name without the directory and extension(s), so `partners/acme.csv.zst` is `acme`
+fn file_namespace(in_path: &str) -> Result<String, anyhow::Error> {
+    let file_name = Path::new(in_path)
+        .file_name()
+        .and_then(|file_name| file_name.to_str())
+        .ok_or_else(|| anyhow::anyhow!("Can't make a namespace out of {in_path}"))?;
+    let namespace = file_name.split('.').next().unwrap_or(file_name);
+    Ok(namespace.to_owned())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    None,
+    Zstd,
+    Bzip2,
+}
+
+impl Compression {
+    // Guessing by extension, `--compression` overrides this
+    fn from_path(path: &str) -> Self {
+        if path.ends_with(".zst") {
+            Compression::Zstd
+        } else if path.ends_with(".bz2") {
+            Compression::Bzip2
+        } else {
+            Compression::None
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "zstd" => Ok(Compression::Zstd),
+            "bzip2" => Ok(Compression::Bzip2),
+            _ => anyhow::bail!("Unknown compression {s}, expected one of none, zstd, bzip2"),
+        }
+    }
+}
+
+// The decoders all stream, so the csv reader never sees more than its buffer's worth of decompressed data
+fn open_input(
+    path: &str,
+    compression: Compression,
+    input_config: &InputConfig,
+    quiet: bool,
+) -> Result<Box<dyn Read>, anyhow::Error> {
+    if path.starts_with("http://") || path.starts_with("https://") {
+        if input_config.mmap {
+            anyhow::bail!("Can't memory map {path}, it's not a file");
+        }
+        let response = fetch(path)?;
+        return decompress(retrying(response, input_config, quiet), compression);
+    }
+    let file = File::open(path)?;
+    // Mapping saves copying from the page cache into the csv reader's buffer, but on the 2M row file
+    // it came out within noise of plain reads, as parsing dominates either way
+    let source: Box<dyn Read> = match input_config.mmap {
+        false => Box::new(file),
+        // Safety: the file changing under us while mapped is UB, so this is only for files nothing else is writing to
+        #[cfg(not(target_arch = "wasm32"))]
+        true => Box::new(io::Cursor::new(unsafe { memmap2::Mmap::map(&file)? })),
+        #[cfg(target_arch = "wasm32")]
+        true => anyhow::bail!("Can't memory map on wasm"),
+    };
+    decompress(retrying(source, input_config, quiet), compression)
+}
+
+fn retrying(source: Box<dyn Read>, input_config: &InputConfig, quiet: bool) -> Box<dyn Read> {
+    match input_config.io_retries {
+        0 => source,
+        retries => Box::new(RetryReader::new(source, retries, quiet)),
+    }
+}
+
+fn decompress(
+    source: Box<dyn Read>,
+    compression: Compression,
+) -> Result<Box<dyn Read>, anyhow::Error> {
+    let reader: Box<dyn Read> = match compression {
+        Compression::None => source,
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => Box::new(zstd::Decoder::new(source)?),
+        #[cfg(feature = "bzip2")]
+        Compression::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(source)),
+        #[allow(unreachable_patterns)]
+        _ => anyhow::bail!("Built without support for {compression:?} compression"),
+    };
+    Ok(reader)
+}
+
+// The body streams too, it's never all in memory. Gzip content-encoding is undone by reqwest,
+// anything compressed as a file (`.zst` URLs etc.) still goes through `decompress`
+#[cfg(feature = "http")]
+fn fetch(url: &str) -> Result<Box<dyn Read>, anyhow::Error> {
+    let response = reqwest::blocking::Client::builder()
+        .timeout(None)
+        .build()?
+        .get(url)
+        .send()?
+        .error_for_status()?;
+    Ok(Box::new(response))
+}
 
-                let value = referenced_tx.value.expect("can't happen. It's possible to make the compiler know this, but I'm not duplicating half my types just for that");
+#[cfg(not(feature = "http"))]
+fn fetch(url: &str) -> Result<Box<dyn Read>, anyhow::Error> {
+    anyhow::bail!("Built without support for fetching {url}, needs the http feature")
+}
 
-                client.held -= value;
-                // Spec does not mention if an account being frozen blocks future transactions, so I'm not doing that
-                client.locked = true;
+// How many of each type a file has, without doing any of the accounting. There's no tx database to fill up
+// and no clients, so it's roughly just the cost of parsing.
+// Rows that don't parse get counted as invalid instead of ending it, unless it's --strict
+fn count_types(
+    in_paths: &[String],
+    compression: Option<Compression>,
+    parquet_paths: &HashSet<String>,
+    input_config: &InputConfig,
+    config: &ProcessingConfig,
+    limit: Option<usize>,
+) -> Result<(), anyhow::Error> {
+    let mut counts: HashMap<TransactionType, u64> = HashMap::new();
+    let mut invalid = 0u64;
+    let mut remaining = limit.unwrap_or(usize::MAX);
+    for in_path in in_paths {
+        let transactions = read_transactions(
+            in_path,
+            compression,
+            parquet_paths,
+            input_config,
+            config.quiet,
+        )?;
+        for transaction in transactions.take(remaining).inspect(|_| remaining -= 1) {
+            match transaction {
+                Ok(transaction) => *counts.entry(transaction.transaction_type()).or_default() += 1,
+                Err(err) if !config.strict => {
+                    if !config.quiet {
+                        eprintln!("Invalid row, {err}");
+                    }
+                    invalid += 1;
+                }
+                Err(err) => return Err(err),
             }
         }
     }
+    let mut writer = csv::Writer::from_writer(io::stdout());
+    writer.write_record(["type", "count"])?;
+    for transaction_type in TransactionType::ALL {
+        let count = counts.get(transaction_type).copied().unwrap_or(0);
+        writer.write_record([transaction_type.as_str(), &count.to_string()])?;
+    }
+    writer.write_record(["invalid", &invalid.to_string()])?;
+    writer.flush()?;
     Ok(())
 }
 
-fn write_output(
-    seen_clients: Vec<ClientId>,
-    client_states: &[Option<Box<ClientState>>; 1 << 16],
+// What the first `rows` rows got parsed as, to check that the columns end up where they should.
+// Nothing gets applied, and a row that doesn't parse gets its error printed in its place
+fn preview_transactions(
+    in_paths: &[String],
+    compression: Option<Compression>,
+    parquet_paths: &HashSet<String>,
+    input_config: &InputConfig,
+    rows: usize,
+    quiet: bool,
 ) -> Result<(), anyhow::Error> {
-    let mut csv_writer = Writer::from_writer(io::stdout());
-    for seen_client_id in seen_clients {
-        let client_state = client_states[seen_client_id as usize].as_ref().unwrap();
-        csv_writer.serialize(ClientRecord::from_id_and_state(
-            &seen_client_id,
-            client_state,
-        ))?;
-    }
-    csv_writer.flush()?;
+    let mut remaining = rows;
+    for in_path in in_paths {
+        let transactions =
+            read_transactions(in_path, compression, parquet_paths, input_config, quiet)?;
+        for transaction in transactions.take(remaining).inspect(|_| remaining -= 1) {
+            match transaction {
+                Ok(transaction) => println!("{transaction:#?}"),
+                Err(err) => println!("error: {err}"),
+            }
+        }
+    }
+    Ok(())
+}
+
+// For poking at the engine by hand. Every line on stdin is a transaction, same as a csv row but without
+// the header (`deposit, 1, 1, 2.5`), after which the client's new state gets printed. `print <client>` shows
+// a client without changing anything, `quit` (or EOF) ends it. Errors get printed and the session goes on
+fn run_repl(
+    input_config: &InputConfig,
+    config: &ProcessingConfig,
+    output_config: &OutputConfig,
+    storage: ClientStorage,
+) -> Result<(), anyhow::Error> {
+    let mut state = EngineState::new(Arc::new(AtomicBool::new(false)));
+    state.store_clients_in(storage)?;
+    let headers = input_config
+        .column_order
+        .clone()
+        .unwrap_or_else(|| StringRecord::from(vec!["type", "client", "tx", "amount"]));
+    for line in io::stdin().lines() {
+        let line = line?;
+        let client_id = match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [] => continue,
+            ["quit"] => break,
+            ["print", client_id] => client_id.parse().map_err(anyhow::Error::from),
+            _ => repl_apply(&line, &headers, &mut state, input_config, config),
+        };
+        if let Err(err) =
+            client_id.and_then(|client_id| repl_print(&state, client_id, output_config))
+        {
+            println!("error: {err}");
+        }
+    }
     Ok(())
 }
 
-type TransactionId = u32;
-type ClientId = u16;
+// What's applied is the client, so that's what gets printed after
+fn repl_apply(
+    line: &str,
+    headers: &StringRecord,
+    state: &mut EngineState,
+    input_config: &InputConfig,
+    config: &ProcessingConfig,
+) -> Result<ClientId, anyhow::Error> {
+    let mut csv_reader = reader_builder(input_config)
+        .has_headers(false)
+        .from_reader(line.as_bytes());
+    let mut record = csv_reader
+        .byte_records()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Nothing to apply"))??;
+    // The csv reader doesn't trim the very first record when there's no header row, and here that's all of them
+    if matches!(input_config.trim, Trim::All | Trim::Fields) {
+        record.trim();
+    }
+    let Some(transaction) =
+        parse_record(&decode_record(record, input_config)?, headers, input_config)?
+    else {
+        anyhow::bail!("Nothing to apply, that's a row without a type");
+    };
+    if let Transaction::Commit = transaction {
+        anyhow::bail!("Nothing to apply, every line already gets its client printed");
+    }
+    let client_id = transaction.client_id();
+    // Going through the whole loop rather than just apply_one, so that dedup, --explain and everything else still work
+    handle_transactions(iter::once(Ok(transaction)), state, config)?;
+    Ok(client_id)
+}
 
-#[derive(Serialize, Debug)]
-struct ClientRecord {
-    #[serde(rename = "client")]
+fn repl_print(
+    state: &EngineState,
     client_id: ClientId,
-    available: Decimal,
-    held: Decimal,
-    total: Decimal,
-    locked: bool,
+    output_config: &OutputConfig,
+) -> Result<(), anyhow::Error> {
+    match state.client_record(client_id, output_config).transpose()? {
+        Some(record) => println!(
+            "client {client_id}: available {}, held {}, total {}, locked {}",
+            record.available, record.held, record.total, record.locked
+        ),
+        None => println!("client {client_id}: never seen"),
+    }
+    Ok(())
 }
 
-impl ClientRecord {
-    fn from_id_and_state(id: &ClientId, state: &ClientState) -> Self {
-        Self {
-            client_id: *id,
-            available: state.available,
-            held: state.held,
-            total: state.held + state.available,
-            locked: state.locked,
+// tail -f, but for transactions
+// Every `interval`, whatever complete lines got appended to the file since the last look get processed,
+// and if there were any, a full snapshot of all the clients gets dumped to stdout. Runs until killed
+fn follow_file(
+    in_path: &str,
+    interval: Duration,
+    state: &mut EngineState,
+    input_config: &InputConfig,
+    config: &ProcessingConfig,
+    output_config: &OutputConfig,
+    output_buffer: Option<usize>,
+) -> Result<(), anyhow::Error> {
+    let mut file: Box<dyn Read> = Box::new(File::open(in_path)?);
+    if input_config.normalize_newlines {
+        file = Box::new(NewlineNormalizer::new(file));
+    }
+    // Wrapping the file itself means it keeps counting across rounds, for a line that's still being written
+    if let Some(max_row_bytes) = input_config.max_row_bytes {
+        file = Box::new(RowLimitReader::new(file, max_row_bytes));
+    }
+    // Bytes after the last newline we've seen. The writer might be in the middle of a line,
+    // and the csv reader would happily parse half a row as a complete one, so those wait for the next round
+    let mut pending: Vec<u8> = Vec::new();
+    let mut headers = input_config.column_order.clone();
+    loop {
+        file.read_to_end(&mut pending)?;
+        if let Some(last_newline) = pending.iter().rposition(|byte| *byte == b'\n') {
+            let chunk: Vec<u8> = pending.drain(..=last_newline).collect();
+            let mut csv_reader = reader_builder(input_config)
+                .has_headers(headers.is_none())
+                .from_reader(chunk.as_slice());
+            // The headers only show up in the first chunk, so the later ones have to borrow them
+            let headers = match &headers {
+                Some(headers) => headers,
+                None => {
+                    let first_headers = csv_reader.headers()?.clone();
+                    validate_headers(&first_headers)?;
+                    headers.insert(first_headers)
+                }
+            };
+
+            // Positions in errors are relative to the chunk, not the whole file
+            let transaction_iter = csv_reader.byte_records().filter_map(|result| {
+                result
+                    .map_err(anyhow::Error::from)
+                    .and_then(|record| decode_record(record, input_config))
+                    .and_then(|record| parse_record(&record, headers, input_config))
+                    .transpose()
+            });
+            handle_transactions(transaction_iter, state, config)?;
+            write_output(
+                &mut CsvSink::new(stdout_writer(output_buffer), output_config),
+                [(None, &*state)],
+                output_config,
+            )?;
         }
+        thread::sleep(interval);
     }
 }
 
-#[derive(Debug)]
-struct ClientState {
-    available: Decimal,
-    held: Decimal,
-    locked: bool,
-    txns_under_dispute: HashSet<TransactionId>,
+// Clap would be cooler, but also massive for this
+struct Args {
+    in_paths: Vec<String>,
+    input_config: InputConfig,
+    config: ProcessingConfig,
+    output_config: OutputConfig,
+    // Polling interval if we're following the file instead of reading it once
+    follow: Option<Duration>,
+    // None means guess from the extension
+    compression: Option<Compression>,
+    namespace_by_file: bool,
+    // Disputes that were neither resolved nor charged back by the end, to stderr
+    report_open_disputes: bool,
+    // What's held over all the clients at the end, to stderr
+    report_held_total: bool,
+    // How many bytes the client states, the tx database and so on take up, roughly, also to stderr
+    report_memory: bool,
+    // Where to write every open dispute's held amount, per client, as json
+    held_breakdown: Option<String>,
+    // Clients with a worrying pattern of chargebacks or disputes, also to stderr
+    flag_suspicious: bool,
+    // Clients with more than this fraction of their total held, to stderr or `flagged_out`
+    flag_high_held_ratio: Option<Decimal>,
+    flagged_out: Option<String>,
+    // Write out whatever got computed even if processing fails partway through
+    output_partial: bool,
+    // Only process this many rows, for a quick look at a huge file
+    limit: Option<usize>,
+    // A reference output to compare ours against, e.g. from before a refactor
+    diff_against: Option<String>,
+    // Same idea, but only the totals, and they only have to be within `totals_epsilon`
+    validate_totals_against: Option<String>,
+    totals_epsilon: Decimal,
+    // Read the output back in and check that it says what it was meant to, see `round_trip_check`
+    round_trip_check: bool,
+    // Where to write out every stored transaction and its dispute status after processing
+    dump_tx_db: Option<String>,
+    // How long processing took, to stderr
+    timing: bool,
+    // How many of each type there were, as a histogram to stderr
+    stats: bool,
+    // A --dump-tx-db from an earlier run, for disputes to refer to
+    load_tx_db: Option<String>,
+    // An earlier run's output, for clients to start from
+    seed: Option<String>,
+    // Transactions typed in one by one on stdin instead of read from a file
+    repl: bool,
+    // Inputs that are parquet rather than csv
+    parquet_paths: HashSet<String>,
+    // Fail the run if any client ends up with a negative available or held
+    assert_balanced: bool,
+    // Open disputes get loaded from here at the start (if it's there) and written back at the end,
+    // so that one run's disputes can be settled in the next
+    dispute_ledger: Option<String>,
+    // Only tally up the types, see `count_types`
+    count_only: bool,
+    // Every deposit and withdrawal that went through, with the available balance after it, as csv
+    ledger_out: Option<String>,
+    // Every dispute, resolve and chargeback that went through, with the balances after it, as csv
+    dispute_log: Option<String>,
+    // Only print how this many rows got parsed, see `preview_transactions`
+    preview: Option<usize>,
+    // Bytes of stdout to buffer up before writing, see `stdout_writer`
+    output_buffer: Option<usize>,
+    // Locked and unlocked clients get written to these instead of stdout
+    locked_out: Option<String>,
+    unlocked_out: Option<String>,
+    // What the clients are kept in, which decides how big their ids can get
+    storage: ClientStorage,
 }
 
-impl ClientState {
-    fn new() -> Self {
-        Self {
-            available: Decimal::default(),
-            held: Decimal::default(),
-            locked: false,
-            txns_under_dispute: HashSet::new(),
+impl Args {
+    // Input paths come from the positional args, and if there's none, from `env_input` (the TX_INPUT env var),
+    // which is handy for containers. Args always win, the env var is never merged with them
+    fn parse(
+        mut args: impl Iterator<Item = String>,
+        env_input: Option<String>,
+    ) -> Result<Self, anyhow::Error> {
+        let mut in_paths = Vec::new();
+        let mut input_config = InputConfig::default();
+        let mut config = ProcessingConfig::default();
+        let mut output_config = OutputConfig::default();
+        let mut follow = false;
+        let mut follow_interval = Duration::from_secs(1);
+        let mut compression = None;
+        let mut namespace_by_file = false;
+        let mut report_open_disputes = false;
+        let mut report_held_total = false;
+        let mut report_memory = false;
+        let mut held_breakdown = None;
+        let mut flag_suspicious = false;
+        let mut flag_high_held_ratio = None;
+        let mut flagged_out = None;
+        let mut output_partial = false;
+        let mut limit = None;
+        let mut diff_against = None;
+        let mut validate_totals_against = None;
+        let mut totals_epsilon = Decimal::ZERO;
+        let mut round_trip_check = false;
+        let mut dump_tx_db = None;
+        let mut timing = false;
+        let mut stats = false;
+        let mut load_tx_db = None;
+        let mut seed = None;
+        let mut repl = false;
+        let mut parquet_paths = HashSet::new();
+        let mut assert_balanced = false;
+        let mut dispute_ledger = None;
+        let mut count_only = false;
+        let mut ledger_out = None;
+        let mut output_buffer = None;
+        let mut dispute_log = None;
+        let mut normalize_amounts = false;
+        let mut preview = None;
+        let mut locked_out = None;
+        let mut unlocked_out = None;
+        let mut storage = ClientStorage::default();
+        let mut no_headers = false;
+        let mut column_order_flag = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                // Same as passing the URL as a path, which also works
+                "--url" => in_paths.push(flag_value(&mut args, &arg)?),
+                "--input-dir" => in_paths.extend(csv_files_in(&flag_value(&mut args, &arg)?)?),
+                // For parquet files without a .parquet extension, which get picked up on their own
+                "--parquet" => {
+                    let path = flag_value(&mut args, &arg)?;
+                    parquet_paths.insert(path.clone());
+                    in_paths.push(path);
+                }
+                "--buffer-size" => {
+                    let buffer_size = flag_value(&mut args, &arg)?.parse()?;
+                    // The csv reader takes an empty buffer as the end of the file, and reads nothing at all
+                    if buffer_size == 0 {
+                        anyhow::bail!("--buffer-size has to be above 0");
+                    }
+                    input_config.buffer_size = Some(buffer_size);
+                }
+                "--output-buffer" => output_buffer = Some(flag_value(&mut args, &arg)?.parse()?),
+                "--mmap" => input_config.mmap = true,
+                "--strict-schema" => input_config.strict_schema = true,
+                "--skip-blank-type" => input_config.skip_blank_type = true,
+                "--lossy-utf8" => input_config.lossy_utf8 = true,
+                "--recover-lines" => input_config.recover_lines = true,
+                "--detect-dialect" => input_config.detect_dialect = true,
+                "--normalize-newlines" => input_config.normalize_newlines = true,
+                "--no-headers" => no_headers = true,
+                // Like `--column-order type,client,amount,tx`, for a file without a header row
+                "--column-order" => {
+                    let column_order: StringRecord =
+                        flag_value(&mut args, &arg)?.split(',').collect();
+                    validate_headers(&column_order)?;
+                    column_order_flag = Some(column_order);
+                }
+                "--trim" => input_config.trim = parse_trim(&flag_value(&mut args, &arg)?)?,
+                "--io-retries" => input_config.io_retries = flag_value(&mut args, &arg)?.parse()?,
+                // 0 turns the limit off
+                "--max-row-bytes" => {
+                    input_config.max_row_bytes =
+                        Some(flag_value(&mut args, &arg)?.parse()?).filter(|max| *max > 0);
+                }
+                // Comma separated, like `--strip-currency '$,USD'`
+                "--strip-currency" => {
+                    input_config.strip_currency = flag_value(&mut args, &arg)?
+                        .split(',')
+                        .filter(|symbol| !symbol.is_empty())
+                        .map(str::to_owned)
+                        .collect();
+                }
+                "--withdrawal-dispute-noop" => config.withdrawal_dispute_noop = true,
+                "--safe-withdrawal-dispute" => config.safe_withdrawal_dispute = true,
+                "--strict" => config.strict = true,
+                "--quiet" => config.quiet = true,
+                "--error-on-locked-deposit" => config.error_on_locked_deposit = true,
+                "--allow-admin" => config.allow_admin = true,
+                "--dedup" => config.dedup = true,
+                "--tx-conflict" => {
+                    config.tx_conflict = parse_tx_conflict(&flag_value(&mut args, &arg)?)?;
+                }
+                "--lenient-amount" => config.lenient_amount = true,
+                "--skip-unknown-types" => config.skip_unknown_types = true,
+                "--withdraw-against" => {
+                    config.withdraw_against =
+                        parse_withdraw_against(&flag_value(&mut args, &arg)?)?;
+                }
+                "--warn-orphan-resolve" => config.warn_orphan_resolve = true,
+                "--skip-zero-disputes" => config.skip_zero_disputes = true,
+                "--normalize-amounts" => normalize_amounts = true,
+                "--dispute-policy" => {
+                    config.dispute_policy = parse_dispute_policy(&flag_value(&mut args, &arg)?)?
+                }
+                "--chargeback-source" => {
+                    config.chargeback_source =
+                        parse_chargeback_source(&flag_value(&mut args, &arg)?)?
+                }
+                // Can be given more than once
+                "--disable" => {
+                    config
+                        .disabled
+                        .insert(parse_transaction_type(&flag_value(&mut args, &arg)?)?);
+                }
+                "--buffer-ooo" => config.buffer_ooo = true,
+                "--explain" => config.explain = Some(flag_value(&mut args, &arg)?.parse()?),
+                "--finalize-after" => {
+                    config.finalize_after = Some(flag_value(&mut args, &arg)?.parse()?);
+                }
+                "--auto-resolve-after" => {
+                    config.auto_resolve_after = Some(flag_value(&mut args, &arg)?.parse()?);
+                }
+                "--max-amount" => config.max_amount = Some(flag_value(&mut args, &arg)?.parse()?),
+                "--with-overdraft-count" => output_config.with_overdraft_count = true,
+                "--with-avg-tx" => output_config.with_avg_tx = true,
+                "--with-tx-ids" => {
+                    output_config.with_tx_ids = true;
+                    config.track_tx_ids = true;
+                }
+                "--minor-units" => output_config.minor_units = true,
+                "--precision" => output_config.precision = flag_value(&mut args, &arg)?.parse()?,
+                // The lookup is there in `OutputConfig::precision_for`, but with nothing to say which currency
+                // a client is in, a mapping like `USD=2,JPY=0` couldn't ever change anything
+                "--currency-precision" => anyhow::bail!(
+                    "--currency-precision needs a currency column, and there's no such thing in the input yet"
+                ),
+                "--no-output-header" => output_config.headers = false,
+                "--bool-style" => {
+                    output_config.bool_style = parse_bool_style(&flag_value(&mut args, &arg)?)?
+                }
+                "--client-format" => {
+                    output_config.client_format =
+                        parse_client_format(&flag_value(&mut args, &arg)?)?
+                }
+                "--sort-by" => {
+                    output_config.sort_by = parse_sort_by(&flag_value(&mut args, &arg)?)?
+                }
+                "--negative-total" => {
+                    output_config.negative_total =
+                        parse_negative_total(&flag_value(&mut args, &arg)?)?;
+                }
+                "--with-house-row" => output_config.with_house_row = true,
+                "--house-id" => output_config.house_id = flag_value(&mut args, &arg)?.parse()?,
+                "--line-terminator" => {
+                    output_config.terminator = parse_terminator(&flag_value(&mut args, &arg)?)?;
+                }
+                "--quote-style" => {
+                    output_config.quote_style = parse_quote_style(&flag_value(&mut args, &arg)?)?;
+                }
+                "--follow" => follow = true,
+                "--repl" => repl = true,
+                "--count-only" => count_only = true,
+                "--follow-interval-ms" => {
+                    follow_interval = Duration::from_millis(flag_value(&mut args, &arg)?.parse()?);
+                }
+                "--compression" => compression = Some(flag_value(&mut args, &arg)?.parse()?),
+                "--namespace-by-file" => namespace_by_file = true,
+                "--report-open-disputes" => report_open_disputes = true,
+                "--report-held-total" => report_held_total = true,
+                "--report-memory" => report_memory = true,
+                "--held-breakdown" => held_breakdown = Some(flag_value(&mut args, &arg)?),
+                "--flag-suspicious" => flag_suspicious = true,
+                "--flag-high-held-ratio" => {
+                    flag_high_held_ratio = Some(flag_value(&mut args, &arg)?.parse()?);
+                }
+                "--flagged-out" => flagged_out = Some(flag_value(&mut args, &arg)?),
+                "--output-partial" => output_partial = true,
+                "--timing" => timing = true,
+                "--stats" => stats = true,
+                "--assert-balanced" => assert_balanced = true,
+                "--load-tx-db" => load_tx_db = Some(flag_value(&mut args, &arg)?),
+                "--seed" => seed = Some(flag_value(&mut args, &arg)?),
+                "--detect-lock-conflicts" => config.detect_lock_conflicts = true,
+                "--dump-tx-db" => dump_tx_db = Some(flag_value(&mut args, &arg)?),
+                "--dispute-ledger" => dispute_ledger = Some(flag_value(&mut args, &arg)?),
+                "--ledger-out" => ledger_out = Some(flag_value(&mut args, &arg)?),
+                "--dispute-log" => dispute_log = Some(flag_value(&mut args, &arg)?),
+                "--locked-out" => locked_out = Some(flag_value(&mut args, &arg)?),
+                "--unlocked-out" => unlocked_out = Some(flag_value(&mut args, &arg)?),
+                "--storage" => storage = parse_storage(&flag_value(&mut args, &arg)?)?,
+                "--diff-against" => diff_against = Some(flag_value(&mut args, &arg)?),
+                "--validate-totals-against" => {
+                    validate_totals_against = Some(flag_value(&mut args, &arg)?);
+                }
+                "--totals-epsilon" => totals_epsilon = flag_value(&mut args, &arg)?.parse()?,
+                "--round-trip-check" => round_trip_check = true,
+                "--limit" => limit = Some(flag_value(&mut args, &arg)?.parse()?),
+                "--preview" => preview = Some(flag_value(&mut args, &arg)?.parse()?),
+                flag if flag.starts_with("--") => anyhow::bail!("Unknown flag {flag}"),
+                _ => in_paths.push(arg),
+            }
+        }
+        // Same scale as the output, which --precision could have changed after this flag
+        if normalize_amounts {
+            config.normalize_scale = Some(output_config.precision);
+        }
+        // Every file has its own tx database, and tx ids can repeat between them
+        if (dump_tx_db.is_some()
+            || load_tx_db.is_some()
+            || dispute_ledger.is_some()
+            || seed.is_some())
+            && namespace_by_file
+        {
+            anyhow::bail!(
+                "Can't --dump-tx-db, --load-tx-db, --dispute-ledger or --seed with --namespace-by-file"
+            );
+        }
+        // These are stats on stderr, which is what --quiet is there to keep quiet
+        if config.quiet && (stats || timing || report_held_total) {
+            anyhow::bail!("Can't --stats, --timing or --report-held-total with --quiet");
+        }
+        // Without a seed there's nothing to be in conflict with
+        if config.detect_lock_conflicts && seed.is_none() {
+            anyhow::bail!("--detect-lock-conflicts needs a --seed");
+        }
+        // Same tx ids in different files would be indistinguishable in it
+        if (ledger_out.is_some() || dispute_log.is_some()) && namespace_by_file {
+            anyhow::bail!("Can't --ledger-out or --dispute-log with --namespace-by-file");
+        }
+        // Finalized clients get written out as they go, so nothing that needs all of the rows at once works with it
+        if config.finalize_after.is_some()
+            && (namespace_by_file
+                || follow
+                || diff_against.is_some()
+                || validate_totals_against.is_some()
+                || output_config.with_house_row
+                || output_config.sort_by != SortBy::Insertion)
+        {
+            anyhow::bail!("Can't --finalize-after with --namespace-by-file, --follow, --diff-against, --validate-totals-against, --with-house-row or --sort-by");
+        }
+        // Rows get matched up by column name
+        if (diff_against.is_some() || validate_totals_against.is_some()) && !output_config.headers {
+            anyhow::bail!(
+                "Can't --diff-against or --validate-totals-against with --no-output-header"
+            );
+        }
+        // The house row is a sum over both halves, and the finalized clients and the diff go by stdout
+        if (locked_out.is_some() || unlocked_out.is_some())
+            && (follow
+                || output_config.with_house_row
+                || config.finalize_after.is_some()
+                || diff_against.is_some()
+                || validate_totals_against.is_some())
+        {
+            anyhow::bail!("Can't --locked-out or --unlocked-out with --follow, --with-house-row, --finalize-after, --diff-against or --validate-totals-against");
+        }
+        // Without a header row, the columns are in the spec's order unless said otherwise
+        match (no_headers, column_order_flag) {
+            (true, column_order) => {
+                input_config.column_order =
+                    Some(column_order.unwrap_or_else(|| {
+                        StringRecord::from(vec!["type", "client", "tx", "amount"])
+                    }));
+            }
+            (false, Some(_)) => anyhow::bail!(
+                "--column-order is for files without a header row, needs --no-headers"
+            ),
+            (false, None) => {}
+        }
+        // Only what's in the buffered output can be checked, and it takes the header row to read it back
+        if round_trip_check
+            && (!output_config.headers
+                || config.finalize_after.is_some()
+                || locked_out.is_some()
+                || unlocked_out.is_some())
+        {
+            anyhow::bail!("Can't --round-trip-check with --no-output-header, --finalize-after, --locked-out or --unlocked-out");
+        }
+        if flagged_out.is_some() && flag_high_held_ratio.is_none() {
+            anyhow::bail!(
+                "--flagged-out is where --flag-high-held-ratio goes, it does nothing without it"
+            );
+        }
+        // Following has a line by line reader of its own
+        if input_config.recover_lines && follow {
+            anyhow::bail!("Can't --recover-lines with --follow");
+        }
+        if preview.is_some() && (follow || count_only) {
+            anyhow::bail!("Can't --preview with --follow or --count-only");
+        }
+        if repl {
+            if !in_paths.is_empty() || follow || count_only || preview.is_some() {
+                anyhow::bail!("--repl reads from stdin, it can't also have input files, --follow, --count-only or --preview");
+            }
+        } else if in_paths.is_empty() {
+            match env_input {
+                Some(env_input) if !env_input.is_empty() => in_paths.push(env_input),
+                _ => anyhow::bail!("No arg, and no TX_INPUT either"),
+            }
         }
+        Ok(Self {
+            in_paths,
+            input_config,
+            config,
+            output_config,
+            follow: follow.then_some(follow_interval),
+            compression,
+            namespace_by_file,
+            report_open_disputes,
+            report_held_total,
+            report_memory,
+            held_breakdown,
+            flag_suspicious,
+            flag_high_held_ratio,
+            flagged_out,
+            output_partial,
+            limit,
+            diff_against,
+            validate_totals_against,
+            totals_epsilon,
+            round_trip_check,
+            dump_tx_db,
+            timing,
+            stats,
+            load_tx_db,
+            seed,
+            repl,
+            parquet_paths,
+            assert_balanced,
+            dispute_ledger,
+            count_only,
+            ledger_out,
+            dispute_log,
+            preview,
+            output_buffer,
+            locked_out,
+            unlocked_out,
+            storage,
+        })
     }
 }
-struct TxDatabase {
-    db: HashMap<TransactionId, TransactionRecord>,
+
+fn parse_quote_style(quote_style: &str) -> Result<QuoteStyle, anyhow::Error> {
+    match quote_style {
+        "always" => Ok(QuoteStyle::Always),
+        "necessary" => Ok(QuoteStyle::Necessary),
+        "never" => Ok(QuoteStyle::Never),
+        _ => anyhow::bail!(
+            "Unknown quote style {quote_style}, expected one of always, necessary, never"
+        ),
+    }
 }
 
-impl TxDatabase {
-    fn new() -> Self {
-        Self {
-            db: HashMap::with_capacity(4096),
-        }
+fn parse_trim(trim: &str) -> Result<Trim, anyhow::Error> {
+    match trim {
+        "all" => Ok(Trim::All),
+        "headers" => Ok(Trim::Headers),
+        "fields" => Ok(Trim::Fields),
+        "none" => Ok(Trim::None),
+        _ => anyhow::bail!("Unknown trim {trim}, expected one of all, headers, fields, none"),
     }
-    fn query(&self, tx_id: TransactionId) -> Option<&TransactionRecord> {
-        self.db.get(&tx_id)
+}
+
+fn parse_transaction_type(transaction_type: &str) -> Result<TransactionType, anyhow::Error> {
+    TransactionType::ALL
+        .iter()
+        .find(|known| known.as_str() == transaction_type)
+        .copied()
+        .ok_or_else(|| {
+            let known: Vec<&str> = TransactionType::ALL.iter().map(|t| t.as_str()).collect();
+            anyhow::anyhow!(
+                "Unknown transaction type {transaction_type}, expected one of {}",
+                known.join(", ")
+            )
+        })
+}
+
+fn parse_terminator(terminator: &str) -> Result<Terminator, anyhow::Error> {
+    match terminator {
+        "lf" => Ok(Terminator::Any(b'\n')),
+        "crlf" => Ok(Terminator::CRLF),
+        _ => anyhow::bail!("Unknown line terminator {terminator}, expected one of lf, crlf"),
     }
-    fn save(&mut self, record: TransactionRecord) {
-        self.db.insert(record.transaction_id, record);
+}
+
+fn parse_tx_conflict(tx_conflict: &str) -> Result<TxConflict, anyhow::Error> {
+    match tx_conflict {
+        "first" => Ok(TxConflict::First),
+        "last" => Ok(TxConflict::Last),
+        "error" => Ok(TxConflict::Error),
+        _ => anyhow::bail!(
+            "Unknown tx conflict policy {tx_conflict}, expected one of first, last, error"
+        ),
     }
 }
 
-#[derive(Deserialize, Debug)]
-struct TransactionRecord {
-    #[serde(rename = "type")]
-    transaction_type: TransactionType,
-    #[serde(rename = "client")]
-    client_id: ClientId,
-    #[serde(rename = "tx")]
-    transaction_id: TransactionId,
-    // size unspecified in spec, so let's default to rust_decimal
-    #[serde(rename = "amount")]
-    #[serde(with = "rust_decimal::serde::str_option")]
-    value: Option<Decimal>,
-}
-#[derive(Deserialize, Debug)]
-enum TransactionType {
-    #[serde(rename = "deposit")]
-    Deposit,
-    #[serde(rename = "withdrawal")]
-    Withdrawal,
-    #[serde(rename = "dispute")]
-    Dispute,
-    #[serde(rename = "resolve")]
-    Resolve,
-    #[serde(rename = "chargeback")]
-    Chargeback,
+fn parse_withdraw_against(withdraw_against: &str) -> Result<WithdrawAgainst, anyhow::Error> {
+    match withdraw_against {
+        "available" => Ok(WithdrawAgainst::Available),
+        "total" => Ok(WithdrawAgainst::Total),
+        _ => anyhow::bail!(
+            "Unknown balance to withdraw against {withdraw_against}, expected one of available, total"
+        ),
+    }
+}
+
+fn parse_storage(storage: &str) -> Result<ClientStorage, anyhow::Error> {
+    match storage {
+        "array" => Ok(ClientStorage::Array),
+        "hashmap" => Ok(ClientStorage::HashMap),
+        "btree" => Ok(ClientStorage::BTree),
+        _ => anyhow::bail!("Unknown storage {storage}, expected one of array, hashmap, btree"),
+    }
+}
+
+fn parse_sort_by(sort_by: &str) -> Result<SortBy, anyhow::Error> {
+    match sort_by {
+        "total-desc" => Ok(SortBy::TotalDesc),
+        "total-asc" => Ok(SortBy::TotalAsc),
+        "client-asc" => Ok(SortBy::ClientAsc),
+        _ => anyhow::bail!(
+            "Unknown sort order {sort_by}, expected one of total-desc, total-asc, client-asc"
+        ),
+    }
+}
+
+fn parse_chargeback_source(chargeback_source: &str) -> Result<ChargebackSource, anyhow::Error> {
+    match chargeback_source {
+        "held" => Ok(ChargebackSource::Held),
+        "available" => Ok(ChargebackSource::Available),
+        "total" => Ok(ChargebackSource::Total),
+        _ => anyhow::bail!(
+            "Unknown chargeback source {chargeback_source}, expected one of held, available, total"
+        ),
+    }
+}
+
+fn parse_dispute_policy(dispute_policy: &str) -> Result<DisputePolicy, anyhow::Error> {
+    match dispute_policy {
+        "spec-literal" => Ok(DisputePolicy::SPEC_LITERAL),
+        "realistic" => Ok(DisputePolicy::REALISTIC),
+        _ => anyhow::bail!(
+            "Unknown dispute policy {dispute_policy}, expected one of spec-literal, realistic"
+        ),
+    }
+}
+
+fn parse_bool_style(bool_style: &str) -> Result<BoolStyle, anyhow::Error> {
+    match bool_style {
+        "true-false" => Ok(BoolStyle::TrueFalse),
+        "one-zero" => Ok(BoolStyle::OneZero),
+        "yes-no" => Ok(BoolStyle::YesNo),
+        _ => anyhow::bail!(
+            "Unknown bool style {bool_style}, expected one of true-false, one-zero, yes-no"
+        ),
+    }
+}
+
+fn parse_client_format(client_format: &str) -> Result<ClientFormat, anyhow::Error> {
+    match client_format {
+        "decimal" => Ok(ClientFormat::Decimal),
+        "hex" => Ok(ClientFormat::Hex),
+        _ => match client_format.strip_prefix("padded:") {
+            Some(width) => {
+                let width: usize = width.parse()?;
+                // A client id is at most 10 digits, and a width past this panics when formatting
+                if width > u16::MAX as usize {
+                    anyhow::bail!("A padded width of {width} is far wider than any client id, it can be at most {}", u16::MAX);
+                }
+                Ok(ClientFormat::Padded(width))
+            }
+            None => anyhow::bail!(
+                "Unknown client format {client_format}, expected one of decimal, hex, padded:<width>"
+            ),
+        },
+    }
+}
+
+fn parse_negative_total(negative_total: &str) -> Result<NegativeTotal, anyhow::Error> {
+    match negative_total {
+        "allow" => Ok(NegativeTotal::Allow),
+        "clamp" => Ok(NegativeTotal::Clamp),
+        "error" => Ok(NegativeTotal::Error),
+        _ => anyhow::bail!(
+            "Unknown negative total policy {negative_total}, expected one of allow, clamp, error"
+        ),
+    }
+}
+
+fn flag_value(
+    args: &mut impl Iterator<Item = String>,
+    flag: &str,
+) -> Result<String, anyhow::Error> {
+    args.next()
+        .ok_or_else(|| anyhow::anyhow!("{flag} needs a value"))
 }
@@ -0,0 +1,1171 @@
+// The flags that only exist in main.rs, run against the actual binary
+use std::{
+    env, fs,
+    io::{BufRead, BufReader, Read, Write},
+    path::PathBuf,
+    process::{Command, Output, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+// Tests run in parallel, so every one of them needs files with names of its own
+fn temp_file(name: &str, contents: &str) -> PathBuf {
+    let path = temp_path(name);
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("recruitment-{}-{name}", std::process::id()))
+}
+
+fn command() -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_recruitment"));
+    command.env_remove("TX_INPUT");
+    command
+}
+
+fn run(args: &[&str]) -> Output {
+    command().args(args).output().unwrap()
+}
+
+fn stdout(output: &Output) -> &str {
+    std::str::from_utf8(&output.stdout).unwrap()
+}
+
+const INPUT: &str = "type,client,tx,amount\ndeposit,1,1,2.5\nwithdrawal,1,2,1\n";
+const OUTPUT: &str = "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n";
+
+#[test]
+fn follow_picks_up_appended_rows() {
+    let path = temp_file("follow.csv", "type,client,tx,amount\ndeposit,1,1,1\n");
+    let mut child = command()
+        .arg(&path)
+        .args(["--follow", "--follow-interval-ms", "20"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in stdout.lines() {
+            if sender.send(line.unwrap()).is_err() {
+                break;
+            }
+        }
+    });
+    // Every round dumps everyone, so it's whichever snapshot has this row in it
+    let wait_for = |expected: &str| loop {
+        let line = receiver
+            .recv_timeout(Duration::from_secs(10))
+            .unwrap_or_else(|_| panic!("No {expected} in the output"));
+        if line == expected {
+            break;
+        }
+    };
+    wait_for("1,1.0000,0.0000,1.0000,false");
+    let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(b"deposit,1,2,2\n").unwrap();
+    wait_for("1,3.0000,0.0000,3.0000,false");
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn reads_zstd() {
+    let compressed = zstd::encode_all(INPUT.as_bytes(), 0).unwrap();
+    let path = temp_path("input.csv.zst");
+    fs::write(&path, compressed).unwrap();
+    let output = run(&[path.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), OUTPUT);
+}
+
+#[cfg(feature = "bzip2")]
+#[test]
+fn reads_bzip2() {
+    let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+    encoder.write_all(INPUT.as_bytes()).unwrap();
+    let path = temp_path("input.csv.bz2");
+    fs::write(&path, encoder.finish().unwrap()).unwrap();
+    // Named like it's something else, so it's --compression that has to make it work
+    let renamed = temp_path("input-bz2.csv");
+    fs::rename(&path, &renamed).unwrap();
+    let output = run(&[renamed.to_str().unwrap(), "--compression", "bzip2"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), OUTPUT);
+}
+
+#[cfg(not(feature = "zstd"))]
+#[test]
+fn zstd_needs_the_feature() {
+    let path = temp_file("no-zstd.csv.zst", INPUT);
+    let output = run(&[path.to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Built without support for Zstd"));
+}
+
+#[test]
+fn compression_overrides_the_extension() {
+    let path = temp_file("plain.csv.zst", INPUT);
+    let output = run(&[path.to_str().unwrap(), "--compression", "none"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), OUTPUT);
+}
+
+#[test]
+fn namespace_by_file_keeps_clients_apart() {
+    let acme = temp_file("acme.csv", "type,client,tx,amount\ndeposit,1,1,2\n");
+    let globex = temp_file("globex.csv", "type,client,tx,amount\ndeposit,1,1,3\n");
+    let output = run(&[
+        acme.to_str().unwrap(),
+        globex.to_str().unwrap(),
+        "--namespace-by-file",
+    ]);
+    assert!(output.status.success());
+    let pid = std::process::id();
+    assert_eq!(
+        stdout(&output),
+        format!(
+            "source,client,available,held,total,locked\n\
+             recruitment-{pid}-acme,1,2.0000,0.0000,2.0000,false\n\
+             recruitment-{pid}-globex,1,3.0000,0.0000,3.0000,false\n"
+        )
+    );
+}
+
+#[test]
+fn output_partial_still_fails_the_run() {
+    let path = temp_file(
+        "partial.csv",
+        "type,client,tx,amount\ndeposit,1,1,2\nwithdrawal,1,2,\ndeposit,1,3,5\n",
+    );
+    let output = run(&[path.to_str().unwrap(), "--strict", "--output-partial"]);
+    assert!(!output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n1,2.0000,0.0000,2.0000,false\n"
+    );
+    // Nothing at all without it
+    let output = run(&[path.to_str().unwrap(), "--strict"]);
+    assert!(!output.status.success());
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn input_from_tx_input() {
+    let from_env = temp_file("from-env.csv", INPUT);
+    let output = command().env("TX_INPUT", &from_env).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), OUTPUT);
+    // An arg wins over it
+    let from_arg = temp_file("from-arg.csv", "type,client,tx,amount\ndeposit,2,1,1\n");
+    let output = command()
+        .arg(&from_arg)
+        .env("TX_INPUT", &from_env)
+        .output()
+        .unwrap();
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n2,1.0000,0.0000,1.0000,false\n"
+    );
+    let output = command().output().unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn currency_precision_needs_a_currency_column() {
+    let path = temp_file("currency-precision.csv", INPUT);
+    let output = run(&[path.to_str().unwrap(), "--currency-precision", "USD=2"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("needs a currency column"));
+}
+
+#[test]
+fn limit_stops_after_that_many_rows() {
+    let path = temp_file(
+        "limit.csv",
+        "type,client,tx,amount\n\
+         deposit,1,1,1\n\
+         deposit,1,2,2\n\
+         deposit,2,3,4\n\
+         withdrawal,1,4,1\n\
+         deposit,1,5,8\n",
+    );
+    let output = run(&[path.to_str().unwrap(), "--limit", "2"]);
+    assert!(output.status.success());
+    // The header doesn't count as one of them
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n1,3.0000,0.0000,3.0000,false\n"
+    );
+}
+
+#[test]
+fn diff_against_a_reference() {
+    let input = temp_file("diff-input.csv", INPUT);
+    // Formatted differently is still the same
+    let matching = temp_file(
+        "diff-matching.csv",
+        "client,available,held,total,locked\n1,1.5,0,1.5,false\n",
+    );
+    let output = run(&[
+        input.to_str().unwrap(),
+        "--diff-against",
+        matching.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    let mismatching = temp_file(
+        "diff-mismatching.csv",
+        "client,available,held,total,locked\n1,2.5,0,2.5,false\n2,1,0,1,false\n",
+    );
+    let output = run(&[
+        input.to_str().unwrap(),
+        "--diff-against",
+        mismatching.to_str().unwrap(),
+    ]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Client 1: expected available 2.5, got 1.5000"));
+    assert!(stderr.contains("Client 2 is missing"));
+}
+
+#[test]
+fn quiet_means_nothing_on_stderr() {
+    let path = temp_file(
+        "quiet.csv",
+        "type,client,tx,amount\ndeposit,1,1,1\nwithdrawal,1,2,5\ndeposit,1,3,\n",
+    );
+    let output = run(&[path.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Skipping"));
+    let output = run(&[path.to_str().unwrap(), "--quiet"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n"
+    );
+    // Stats are on stderr too, so asking for them and quiet at once doesn't say what's wanted
+    for flag in ["--stats", "--timing", "--report-held-total"] {
+        let output = run(&[path.to_str().unwrap(), "--quiet", flag]);
+        assert!(!output.status.success(), "{flag}");
+        assert_eq!(stdout(&output), "", "{flag}");
+        assert!(String::from_utf8_lossy(&output.stderr).contains("with --quiet"));
+    }
+}
+
+fn generated(name: &str, deposits: &str) -> PathBuf {
+    let path = temp_path(name);
+    let output = run(&[
+        "generate",
+        "--deposits",
+        deposits,
+        "--output",
+        path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    path
+}
+
+#[test]
+fn mmap_gives_the_same_output() {
+    let path = generated("mmap.csv", "5000");
+    let buffered = run(&[path.to_str().unwrap()]);
+    let mapped = run(&[path.to_str().unwrap(), "--mmap"]);
+    assert!(buffered.status.success() && mapped.status.success());
+    assert_eq!(stdout(&buffered), stdout(&mapped));
+}
+
+// Not much of a benchmark, but it's enough to see whether --mmap is worth it at all.
+// cargo test --release --test cli -- --ignored --nocapture
+#[test]
+#[ignore]
+fn mmap_benchmark() {
+    let path = generated("mmap-benchmark.csv", "5000000");
+    for args in [
+        vec![path.to_str().unwrap()],
+        vec![path.to_str().unwrap(), "--mmap"],
+    ] {
+        let start = Instant::now();
+        assert!(run(&args).status.success());
+        println!("{args:?}: {:?}", start.elapsed());
+    }
+    // Hundreds of megabytes, unlike the rest
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn timing_leaves_the_output_alone() {
+    let path = temp_file("timing.csv", INPUT);
+    let output = run(&[path.to_str().unwrap(), "--timing"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), OUTPUT);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("records/s"));
+}
+
+// Just enough of a server to hand out one response
+#[cfg(feature = "http")]
+fn serve_once(body: &'static str) -> String {
+    use std::{io::Read, net::TcpListener};
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/input.csv", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buffer = [0; 1024];
+        while !request.ends_with(b"\r\n\r\n") {
+            let read = stream.read(&mut buffer).unwrap();
+            if read == 0 {
+                break;
+            }
+            request.extend_from_slice(&buffer[..read]);
+        }
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/csv\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+        .unwrap();
+    });
+    url
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn reads_from_a_url() {
+    let url = serve_once(INPUT);
+    let output = run(&["--url", &url]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), OUTPUT);
+}
+
+#[cfg(not(feature = "http"))]
+#[test]
+fn url_needs_the_feature() {
+    let output = run(&["--url", "http://127.0.0.1:1/input.csv"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("needs the http feature"));
+}
+
+#[test]
+fn generated_files_go_through_cleanly() {
+    let path = temp_path("generated.csv");
+    let output = run(&[
+        "generate",
+        "--clients",
+        "70000",
+        "--deposits",
+        "2000",
+        "--withdrawals",
+        "1000",
+        "--dispute-ratio",
+        "0.5",
+        "--output",
+        path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    // Past what the default array of clients holds. Withdrawals can still overdraw, which isn't an error even with --strict
+    let output = run(&[path.to_str().unwrap(), "--strict", "--storage", "hashmap"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).lines().count() > 1);
+}
+
+#[test]
+fn repl_session() {
+    let mut child = command()
+        .arg("--repl")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(
+            b"deposit,1,1,10\n\
+              dispute,1,1,\n\
+              print 2\n\
+              withdrawal,nope\n\
+              resolve,1,1,\n\
+              print 1\n\
+              quit\n\
+              deposit,1,2,5\n",
+        )
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let lines: Vec<&str> = stdout(&output).lines().collect();
+    assert_eq!(
+        lines[..3],
+        [
+            "client 1: available 10.0000, held 0.0000, total 10.0000, locked false",
+            "client 1: available 0.0000, held 10.0000, total 10.0000, locked false",
+            "client 2: never seen",
+        ]
+    );
+    assert!(lines[3].starts_with("error: "));
+    // Nothing after the quit
+    assert_eq!(
+        lines[4..],
+        [
+            "client 1: available 10.0000, held 0.0000, total 10.0000, locked false",
+            "client 1: available 10.0000, held 0.0000, total 10.0000, locked false",
+        ]
+    );
+}
+
+#[cfg(feature = "parquet")]
+#[test]
+fn reads_parquet() {
+    use std::sync::Arc;
+
+    use parquet::{
+        data_type::{ByteArray, ByteArrayType, Int32Type},
+        file::{properties::WriterProperties, writer::SerializedFileWriter},
+        schema::parser::parse_message_type,
+    };
+
+    // The same as INPUT, plus a dispute with no amount
+    let schema = parse_message_type(
+        "message transactions {
+            REQUIRED BYTE_ARRAY type (UTF8);
+            REQUIRED INT32 client;
+            REQUIRED INT32 tx;
+            OPTIONAL BYTE_ARRAY amount (UTF8);
+        }",
+    )
+    .unwrap();
+    let path = temp_path("input.parquet");
+    let mut writer = SerializedFileWriter::new(
+        fs::File::create(&path).unwrap(),
+        Arc::new(schema),
+        Arc::new(WriterProperties::builder().build()),
+    )
+    .unwrap();
+    let mut row_group = writer.next_row_group().unwrap();
+    let mut column = row_group.next_column().unwrap().unwrap();
+    let types = ["deposit", "withdrawal", "dispute"].map(ByteArray::from);
+    column
+        .typed::<ByteArrayType>()
+        .write_batch(&types, None, None)
+        .unwrap();
+    column.close().unwrap();
+    for values in [[1, 1, 1], [1, 2, 1]] {
+        let mut column = row_group.next_column().unwrap().unwrap();
+        column
+            .typed::<Int32Type>()
+            .write_batch(&values, None, None)
+            .unwrap();
+        column.close().unwrap();
+    }
+    let mut column = row_group.next_column().unwrap().unwrap();
+    let amounts = ["2.5", "1"].map(ByteArray::from);
+    column
+        .typed::<ByteArrayType>()
+        .write_batch(&amounts, Some(&[1, 1, 0]), None)
+        .unwrap();
+    column.close().unwrap();
+    row_group.close().unwrap();
+    writer.close().unwrap();
+
+    let csv = temp_file(
+        "parquet-equivalent.csv",
+        "type,client,tx,amount\ndeposit,1,1,2.5\nwithdrawal,1,2,1\ndispute,1,1,\n",
+    );
+    let from_parquet = run(&[path.to_str().unwrap()]);
+    let from_csv = run(&[csv.to_str().unwrap()]);
+    assert!(from_parquet.status.success());
+    assert_eq!(stdout(&from_parquet), stdout(&from_csv));
+    assert_eq!(
+        stdout(&from_parquet),
+        "client,available,held,total,locked\n1,-1.0000,2.5000,1.5000,false\n"
+    );
+}
+
+#[cfg(not(feature = "parquet"))]
+#[test]
+fn parquet_needs_the_feature() {
+    let path = temp_file("no-parquet.parquet", "");
+    let output = run(&[path.to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("built without parquet support"));
+}
+
+#[test]
+fn assert_balanced_fails_the_run() {
+    let path = temp_file(
+        "unbalanced.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\nwithdrawal,1,2,10\ndispute,1,2,\n",
+    );
+    let output = run(&[path.to_str().unwrap()]);
+    assert!(output.status.success());
+    let output = run(&[path.to_str().unwrap(), "--assert-balanced"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("1 clients with a negative balance:\nclient 1: available -10, held 10"));
+}
+
+#[test]
+fn dispute_ledger_carries_over_between_runs() {
+    let ledger = temp_path("dispute-ledger.csv");
+    let _ = fs::remove_file(&ledger);
+    let first = temp_file(
+        "ledger-run-1.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,2\ndispute,1,1,\n",
+    );
+    let output = run(&[
+        first.to_str().unwrap(),
+        "--dispute-ledger",
+        ledger.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n1,2.0000,10.0000,12.0000,false\n"
+    );
+    let second = temp_file("ledger-run-2.csv", "type,client,tx,amount\nresolve,1,1,\n");
+    let output = run(&[
+        second.to_str().unwrap(),
+        "--dispute-ledger",
+        ledger.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    // Only what was held comes back, the rest of the balance stayed with the first run
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n1,10.0000,0.0000,10.0000,false\n"
+    );
+    // And with it settled, there's nothing left in the ledger for a third run
+    assert_eq!(fs::read_to_string(&ledger).unwrap(), "");
+}
+
+#[test]
+fn count_only_tallies_types() {
+    let path = temp_file(
+        "count-only.csv",
+        "type,client,tx,amount\n\
+         deposit,1,1,10\n\
+         deposit,2,2,1\n\
+         withdrawal,1,3,100\n\
+         dispute,1,1,\n\
+         chargeback,1,1,\n\
+         deposit,1,4,\n",
+    );
+    let output = run(&[path.to_str().unwrap(), "--count-only", "--quiet"]);
+    assert!(output.status.success());
+    let counts: Vec<&str> = stdout(&output).lines().collect();
+    assert_eq!(counts[0], "type,count");
+    for expected in [
+        "deposit,2",
+        "withdrawal,1",
+        "dispute,1",
+        "resolve,0",
+        "chargeback,1",
+        "invalid,1",
+    ] {
+        assert!(counts.contains(&expected), "No {expected} in {counts:?}");
+    }
+}
+
+#[test]
+fn ledger_out_has_running_balances() {
+    let input = temp_file(
+        "ledger-input.csv",
+        "type,client,tx,amount\n\
+         deposit,1,1,10\n\
+         deposit,2,2,1\n\
+         withdrawal,1,3,2.5\n\
+         dispute,1,1,\n\
+         withdrawal,2,4,5\n\
+         deposit,1,5,1\n",
+    );
+    let ledger = temp_path("ledger-out.csv");
+    let output = run(&[
+        input.to_str().unwrap(),
+        "--ledger-out",
+        ledger.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    // Only what went through, and the dispute moving available doesn't get a row of its own
+    assert_eq!(
+        fs::read_to_string(&ledger).unwrap(),
+        "type,client,tx,amount,available\n\
+         deposit,1,1,10,10\n\
+         deposit,2,2,1,1\n\
+         withdrawal,1,3,2.5,7.5\n\
+         deposit,1,5,1,-1.5\n"
+    );
+}
+
+#[test]
+fn preview_shows_what_rows_parse_as() {
+    let path = temp_file(
+        "preview.csv",
+        "type,client,tx,amount\ndeposit,1,1,2.5\ndispute,1,1,\nwithdrawal,1,2,1\n",
+    );
+    let output = run(&[path.to_str().unwrap(), "--preview", "2"]);
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "Deposit {\n    client: 1,\n    tx: 1,\n    amount: 2.5,\n}\n\
+         Dispute {\n    client: 1,\n    tx: 1,\n}\n"
+    );
+}
+
+#[test]
+fn locked_and_unlocked_go_to_their_own_files() {
+    let input = temp_file(
+        "split-input.csv",
+        "type,client,tx,amount\n\
+         deposit,1,1,10\n\
+         deposit,2,2,5\n\
+         dispute,2,2,\n\
+         chargeback,2,2,\n",
+    );
+    let locked = temp_path("locked-out.csv");
+    let unlocked = temp_path("unlocked-out.csv");
+    let output = run(&[
+        input.to_str().unwrap(),
+        "--locked-out",
+        locked.to_str().unwrap(),
+        "--unlocked-out",
+        unlocked.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "");
+    assert_eq!(
+        fs::read_to_string(&locked).unwrap(),
+        "client,available,held,total,locked\n2,0.0000,0.0000,0.0000,true\n"
+    );
+    assert_eq!(
+        fs::read_to_string(&unlocked).unwrap(),
+        "client,available,held,total,locked\n1,10.0000,0.0000,10.0000,false\n"
+    );
+    // With only one of them, the rest go to stdout
+    let output = run(&[
+        input.to_str().unwrap(),
+        "--locked-out",
+        locked.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n1,10.0000,0.0000,10.0000,false\n"
+    );
+}
+
+#[test]
+fn validate_totals_against_a_reference() {
+    let input = temp_file("totals-input.csv", INPUT);
+    // Other columns and formatting don't matter, only the totals do
+    let matching = temp_file("totals-matching.csv", "client,total,note\n1,1.50001,abc\n");
+    let output = run(&[
+        input.to_str().unwrap(),
+        "--validate-totals-against",
+        matching.to_str().unwrap(),
+        "--totals-epsilon",
+        "0.0001",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), OUTPUT);
+    let output = run(&[
+        input.to_str().unwrap(),
+        "--validate-totals-against",
+        matching.to_str().unwrap(),
+        "--totals-epsilon",
+        "0",
+    ]);
+    assert!(!output.status.success());
+    let differing = temp_file("totals-differing.csv", "client,total\n1,2\n2,0\n");
+    let output = run(&[
+        input.to_str().unwrap(),
+        "--validate-totals-against",
+        differing.to_str().unwrap(),
+    ]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Client 1: expected total 2, got 1.5000"));
+    assert!(stderr.contains("Client 2 is missing"));
+}
+
+#[test]
+fn round_trip_check_passes_on_real_output() {
+    let input = temp_file(
+        "round-trip.csv",
+        "type,client,tx,amount\ndeposit,1,1,1.2345\nwithdrawal,1,2,0.5\n",
+    );
+    let output = run(&[input.to_str().unwrap(), "--round-trip-check"]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n1,0.7345,0.0000,0.7345,false\n"
+    );
+}
+
+#[test]
+fn normalize_newlines_splits_lone_carriage_returns() {
+    let input = temp_file(
+        "lone-cr.csv",
+        "type,client,tx,amount\rdeposit,1,1,10\rdeposit,1,2,5\rwithdrawal,1,3,1\r",
+    );
+    // To --max-row-bytes, that's all one line
+    let output = run(&[input.to_str().unwrap(), "--max-row-bytes", "30"]);
+    assert!(!output.status.success());
+    let output = run(&[
+        input.to_str().unwrap(),
+        "--max-row-bytes",
+        "30",
+        "--normalize-newlines",
+    ]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n1,14.0000,0.0000,14.0000,false\n"
+    );
+}
+
+#[test]
+fn stats_prints_a_histogram() {
+    let input = temp_file(
+        "histogram.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,10\nwithdrawal,1,3,5\ndispute,1,1,\nresolve,1,1,\ndispute,1,2,\nchargeback,1,2,\nwithdrawal,1,4,100\n",
+    );
+    let output = run(&[input.to_str().unwrap(), "--stats"]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let lines: Vec<&str> = stderr.lines().collect();
+    assert!(lines.contains(&"deposit             2  25.0%"), "{stderr}");
+    assert!(lines.contains(&"withdrawal          2  25.0%"), "{stderr}");
+    assert!(lines.contains(&"dispute             2  25.0%"), "{stderr}");
+    assert!(lines.contains(&"resolve             1  12.5%"), "{stderr}");
+    assert!(lines.contains(&"chargeback          1  12.5%"), "{stderr}");
+}
+
+#[test]
+fn flagged_out_gets_the_high_held_clients() {
+    let input = temp_file(
+        "high-held.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,90\ndispute,1,2,\ndeposit,2,3,100\n",
+    );
+    let flagged = temp_path("high-held-flagged.txt");
+    let output = run(&[
+        input.to_str().unwrap(),
+        "--flag-high-held-ratio",
+        "0.5",
+        "--flagged-out",
+        flagged.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(&flagged).unwrap(),
+        "client 1: 90 of a total of 100 held\n"
+    );
+    fs::remove_file(flagged).unwrap();
+}
+
+#[test]
+fn commit_rows_write_checkpoints() {
+    let input = temp_file(
+        "commit.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\ncommit,,,\ndeposit,2,2,5\ndeposit,1,3,1\n",
+    );
+    // The checkpoint, then the full output, under the one header
+    let output = run(&[input.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n\
+         1,10.0000,0.0000,10.0000,false\n\
+         1,11.0000,0.0000,11.0000,false\n\
+         2,5.0000,0.0000,5.0000,false\n"
+    );
+    // Same with finalized clients in the mix
+    let output = run(&[input.to_str().unwrap(), "--finalize-after", "1"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output).matches("client,").count(), 1);
+    // Where there's nowhere for them to go, they don't just quietly disappear
+    let output = run(&[input.to_str().unwrap(), "--namespace-by-file"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Got a commit row"));
+    let output = run(&[
+        input.to_str().unwrap(),
+        "--follow",
+        "--follow-interval-ms",
+        "10",
+    ]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Got a commit row"));
+}
+
+#[test]
+fn detect_lock_conflicts_against_the_seed() {
+    let seed = temp_file(
+        "lock-conflict-seed.csv",
+        "client,available,held,total,locked\n1,5,0,5,true\n",
+    );
+    let input = temp_file(
+        "lock-conflict.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\ndispute,1,1,\nresolve,1,1,\n",
+    );
+    let output = run(&[
+        input.to_str().unwrap(),
+        "--seed",
+        seed.to_str().unwrap(),
+        "--detect-lock-conflicts",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "Lock conflict, client 1 is locked in the seed, but got a resolve of tx 1\n"
+    );
+    let output = run(&[input.to_str().unwrap(), "--detect-lock-conflicts"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn held_breakdown_goes_to_its_own_file() {
+    let input = temp_file(
+        "held-breakdown.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,5\ndispute,1,1,\ndispute,1,2,\n",
+    );
+    let breakdown = temp_path("held-breakdown.json");
+    let output = run(&[
+        input.to_str().unwrap(),
+        "--held-breakdown",
+        breakdown.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    // The main output is the same as ever
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n1,0.0000,15.0000,15.0000,false\n"
+    );
+    let json = fs::read_to_string(&breakdown).unwrap();
+    let compact: String = json.split_whitespace().collect();
+    assert_eq!(
+        compact,
+        r#"[{"client":1,"held":"15","disputes":[{"tx":1,"held":"10"},{"tx":2,"held":"5"}]}]"#
+    );
+    fs::remove_file(breakdown).unwrap();
+}
+
+#[test]
+fn unknown_types_error_by_default() {
+    let input = temp_file(
+        "unknown-type.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\ntransfer,1,2,5\n",
+    );
+    let output = run(&[input.to_str().unwrap()]);
+    assert!(!output.status.success());
+    let output = run(&[input.to_str().unwrap(), "--skip-unknown-types"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "Skipping, transfer 2 is of an unknown type\n"
+    );
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n1,10.0000,0.0000,10.0000,false\n"
+    );
+    let blank = temp_file("blank-type.csv", "type,client,tx,amount\n  ,1,1,10\n");
+    let output = run(&[blank.to_str().unwrap(), "--skip-unknown-types"]);
+    assert!(!output.status.success());
+    let output = run(&[blank.to_str().unwrap(), "--skip-blank-type"]);
+    assert!(output.status.success());
+}
+
+#[test]
+fn dispute_log_of_a_dispute_and_resolve() {
+    let input = temp_file(
+        "dispute-log.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,3\ndispute,1,1,\nresolve,1,1,\n",
+    );
+    let log = temp_path("dispute-log-out.csv");
+    let output = run(&[
+        input.to_str().unwrap(),
+        "--dispute-log",
+        log.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string(&log).unwrap(),
+        "event,client,tx,amount,available,held,locked\n\
+         open,1,1,10,3,10,false\n\
+         resolve,1,1,10,13,0,false\n"
+    );
+    fs::remove_file(log).unwrap();
+}
+
+#[test]
+fn client_format_padded() {
+    let input = temp_file(
+        "client-format.csv",
+        "type,client,tx,amount\ndeposit,1,1,1\n",
+    );
+    let output = run(&[input.to_str().unwrap(), "--client-format", "padded:5"]);
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n00001,1.0000,0.0000,1.0000,false\n"
+    );
+    let output = run(&[input.to_str().unwrap(), "--client-format", "octal"]);
+    assert!(!output.status.success());
+    // Too wide to format, which is an error rather than a panic
+    let output = run(&[
+        input.to_str().unwrap(),
+        "--client-format",
+        "padded:1000000000",
+    ]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("can be at most 65535"));
+    let output = run(&[input.to_str().unwrap(), "--client-format", "padded:65535"]);
+    assert!(output.status.success());
+}
+
+#[test]
+fn report_memory_leaves_the_output_alone() {
+    let input = temp_file(
+        "report-memory.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\ndeposit,2,2,5\n",
+    );
+    let plain = run(&[input.to_str().unwrap()]);
+    let reported = run(&[input.to_str().unwrap(), "--report-memory"]);
+    assert!(reported.status.success());
+    assert_eq!(stdout(&plain), stdout(&reported));
+    let stderr = String::from_utf8_lossy(&reported.stderr);
+    let report = stderr.trim_end().strip_prefix("memory: ").unwrap();
+    assert!(report.starts_with("client states "), "{stderr}");
+    assert!(
+        !report.contains(" 0,") && !report.ends_with(" 0"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn input_dir_goes_in_name_order() {
+    let dir = temp_path("input-dir");
+    fs::create_dir_all(&dir).unwrap();
+    // Written out of order, and a chargeback before its dispute would be skipped
+    fs::write(
+        dir.join("2024-01-03.csv"),
+        "type,client,tx,amount\nchargeback,1,1,\n",
+    )
+    .unwrap();
+    fs::write(
+        dir.join("2024-01-01.csv"),
+        "type,client,tx,amount\ndeposit,1,1,10\ndeposit,2,2,5\n",
+    )
+    .unwrap();
+    fs::write(
+        dir.join("2024-01-02.csv"),
+        "type,client,tx,amount\ndispute,1,1,\n",
+    )
+    .unwrap();
+    fs::write(dir.join("notes.txt"), "not a csv").unwrap();
+    let output = run(&["--input-dir", dir.to_str().unwrap()]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n\
+         1,0.0000,0.0000,0.0000,true\n\
+         2,5.0000,0.0000,5.0000,false\n"
+    );
+    fs::remove_dir_all(&dir).unwrap();
+    let empty = temp_path("input-dir-empty");
+    fs::create_dir_all(&empty).unwrap();
+    let output = run(&["--input-dir", empty.to_str().unwrap()]);
+    assert!(!output.status.success());
+    fs::remove_dir_all(empty).unwrap();
+}
+
+#[test]
+fn chargeback_source_flag() {
+    let input = temp_file(
+        "chargeback-source.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,5\ndispute,1,1,\nchargeback,1,1,\n",
+    );
+    let expected = [
+        ("held", "1,5.0000,0.0000,5.0000,true\n"),
+        ("available", "1,-5.0000,10.0000,5.0000,true\n"),
+        ("total", "1,5.0000,0.0000,5.0000,true\n"),
+    ];
+    for (chargeback_source, row) in expected {
+        let output = run(&[
+            input.to_str().unwrap(),
+            "--chargeback-source",
+            chargeback_source,
+        ]);
+        assert!(output.status.success());
+        assert_eq!(
+            stdout(&output),
+            format!("client,available,held,total,locked\n{row}")
+        );
+    }
+    let output = run(&[input.to_str().unwrap(), "--chargeback-source", "nowhere"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn output_buffer_with_a_slow_reader() {
+    // Enough clients that the output is a good few times what a pipe holds
+    let input = temp_path("output-buffer.csv");
+    let output = run(&[
+        "generate",
+        "--clients",
+        "60000",
+        "--deposits",
+        "60000",
+        "--output",
+        input.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    let expected = run(&[input.to_str().unwrap()]);
+    assert!(expected.status.success());
+    // Much smaller than a row, so there's a write for nearly every one of them
+    let mut child = command()
+        .args([input.to_str().unwrap(), "--output-buffer", "16"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut pipe = child.stdout.take().unwrap();
+    let mut written = Vec::new();
+    let mut chunk = [0; 512];
+    loop {
+        let read = pipe.read(&mut chunk).unwrap();
+        if read == 0 {
+            break;
+        }
+        written.extend_from_slice(&chunk[..read]);
+        // Slow enough at the start for the pipe to fill up and the writes to block
+        if written.len() < 128 * 1024 {
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+    assert!(child.wait().unwrap().success());
+    assert!(written.len() > 1 << 20);
+    assert_eq!(String::from_utf8(written).unwrap(), stdout(&expected));
+    fs::remove_file(input).unwrap();
+}
+
+#[test]
+fn detect_dialect_reads_a_semicolon_file() {
+    let input = temp_file(
+        "semicolon.csv",
+        "type;client;tx;amount\ndeposit;1;1;10\nwithdrawal;1;2;2.5\n",
+    );
+    let output = run(&[input.to_str().unwrap(), "--detect-dialect"]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n1,7.5000,0.0000,7.5000,false\n"
+    );
+    // Read as commas, the header's one big column
+    let output = run(&[input.to_str().unwrap()]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn with_avg_tx_column() {
+    let input = temp_file(
+        "avg-tx.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,20\n",
+    );
+    let output = run(&[input.to_str().unwrap(), "--with-avg-tx"]);
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked,avg_tx\n1,30.0000,0.0000,30.0000,false,15.0000\n"
+    );
+}
+
+#[test]
+fn skip_zero_disputes_leaves_them_undisputed() {
+    let tx_db = temp_file(
+        "zero-dispute-db.csv",
+        "type,tx,amount,status\ndeposit,1,0,undisputed\n",
+    );
+    let input = temp_file("zero-dispute.csv", "type,client,tx,amount\ndispute,1,1,\n");
+    let args = [
+        input.to_str().unwrap(),
+        "--load-tx-db",
+        tx_db.to_str().unwrap(),
+        "--report-open-disputes",
+    ];
+    let output = run(&args);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "client 1: 1 open disputes, 0 held\n"
+    );
+    let output = run(&[&args[..], &["--skip-zero-disputes"]].concat());
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "Skipping, dispute of tx 1 for client 1, which is for zero\n"
+    );
+    // Balances are the same either way
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n1,0.0000,0.0000,0.0000,false\n"
+    );
+}
+
+#[test]
+fn buffer_size_of_zero_is_rejected() {
+    let input = temp_file("buffer-size.csv", "type,client,tx,amount\ndeposit,1,1,10\n");
+    let output = run(&[input.to_str().unwrap(), "--buffer-size", "0"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--buffer-size has to be above 0"));
+    let output = run(&[input.to_str().unwrap(), "--buffer-size", "1"]);
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n1,10.0000,0.0000,10.0000,false\n"
+    );
+}
+
+#[test]
+fn detect_lock_conflicts_with_a_buffered_resolve() {
+    let seed = temp_file(
+        "lock-conflict-buffered-seed.csv",
+        "client,available,held,total,locked\n1,5,0,5,true\n",
+    );
+    let input = temp_file(
+        "lock-conflict-buffered.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\nresolve,1,1,\ndispute,1,1,\n",
+    );
+    let output = run(&[
+        input.to_str().unwrap(),
+        "--seed",
+        seed.to_str().unwrap(),
+        "--detect-lock-conflicts",
+        "--strict",
+        "--buffer-ooo",
+    ]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("Client 1 is locked in the seed, but got a resolve of tx 1"));
+}